@@ -1,294 +1,6110 @@
 pub mod matching {
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::fmt;
+    use std::net::{IpAddr, SocketAddr};
+    use std::ops::Range;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex, OnceLock, RwLock};
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
     use std::thread::JoinHandle;
-    use log::{debug, error};
-    use regex::Regex;
-    use lockfree::channel::{RecvErr, spmc};
-    use lockfree::channel::mpsc;
+    use std::time::{Duration, Instant};
+    use log::{debug, error, warn};
+    use regex::{Regex, RegexSet, RegexSetBuilder};
+    use regex_syntax::hir::literal::Literals;
+    use aho_corasick::AhoCorasick;
+    use crossbeam_channel::{Receiver, SendError, Sender};
+    use siphasher::sip::SipHasher24;
+    use xxhash_rust::xxh3::xxh3_128;
+    use crate::loading::{logfmt_value, CaptureRanges, PatternRegex, TemplateRegex, Timestamp, MAX_REGEXES};
 
     const UNKNOWN_THREAD_NAME: &str = "UNKNOWN_THREAD_NAME";
+    const SETTLE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+    // Characters of template tokens that are regex syntax rather than literal text
+    const REGEX_SYNTAX: &[char] = &['\\', '[', ']', '(', ')', '*', '+', '?', '|', '{', '}', '^', '$'];
+    const OVERLAP_EXAMPLES: usize = 3;
+    // Messages a worker labels before it reorders the regexes by their matches again
+    const ADAPTIVE_ORDER_INTERVAL: usize = 1000;
+    // Capture groups whose name starts with it are not masked, while `var_*` and all other groups are
+    const CONST_GROUP_PREFIX: &str = "const_";
+    // Name of the capture group holding the correlation key of a message, e.g. a request or block id
+    pub const CASE_ID_GROUP: &str = "case_id";
 
     #[derive(Debug)]
     pub enum Request {
-        Parse(String),
+        Parse(String, Metadata),
+        // Several messages in one channel message, matched in order by the same worker
+        Batch(Vec<(String, Metadata)>),
         EndOfStream,
     }
 
+    // Context of a message that is carried through the pool untouched
+    #[derive(Clone, Debug)]
+    pub struct Metadata {
+        pub source: Arc<str>,
+        pub line_number: usize,
+        // Byte offset after the line in its file, if reading can resume there
+        pub offset: Option<u64>,
+        pub timestamp: Option<Timestamp>,
+        pub level: Option<String>,
+        pub component: Option<String>,
+        pub fields: Vec<(String, String)>,
+    }
+
     #[derive(Debug)]
     pub struct Response {
         pub msg: String,
+        // One 0/1 digit per unit of the message, see `mask_unit`
+        pub msk: String,
+        pub mask_unit: MaskUnit,
+        pub idx: u32,
+        // Stable id of the matching regex, see `TemplateRegex::id`
+        pub id: u64,
+        // The matching regex with its capture groups rendered as `<*>`
+        pub template: Arc<str>,
+        // Captured parameters of the message in the order of their groups
+        pub params: Vec<Param>,
+        // Token similarity to the template if no regex matched and it was the closest one
+        pub similarity: Option<f64>,
+        // Every regex matching the message with its own mask, in file order, if more than one
+        // matched and the `all` double match policy kept them
+        pub alternatives: Vec<Alternative>,
+        // The other regexes matching the message, which lost to `idx` by their priority or the
+        // double match policy
+        pub overruled: Vec<u32>,
+        // 1 for messages only one regex (of the highest priority) matched, 1/n for n matching
+        // regexes resolved by the double match policy and the similarity of fuzzy matches
+        pub confidence: f64,
+        // Occurrences of the message in the input, only known once the input is exhausted
+        pub count: usize,
+        // The `case_id` group, which groups the messages into process mining traces
+        pub case_id: Option<Param>,
+        pub metadata: Metadata,
+    }
+
+    // Value of a capture group, named if the group is, e.g. `(?P<ip>\S+)`
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Param {
+        pub name: Option<String>,
+        pub value: String,
+        // Byte range of the value in the message
+        pub range: Range<usize>,
+    }
+
+    impl Param {
+        // Groups named `const_*` capture context of the template rather than a variable
+        pub fn is_const(&self) -> bool {
+            self.name.as_deref().is_some_and(is_const_group)
+        }
+    }
+
+    // One of several regexes matching a message
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Alternative {
+        pub idx: u32,
         pub msk: String,
-        pub idx: u16,
     }
 
+    // Label of a message matched by exactly one regex (after resolving double matches)
+    #[derive(Clone, Debug)]
+    pub struct Match {
+        pub idx: usize,
+        pub id: u64,
+        pub template: Arc<str>,
+        // One 0/1 digit per unit of the message, or the digits of the parameter classes
+        pub mask: String,
+        pub params: Vec<Param>,
+        // Similarity of a fuzzy match, `None` for regex matches
+        pub similarity: Option<f64>,
+        pub alternatives: Vec<Alternative>,
+        pub overruled: Vec<usize>,
+        pub confidence: f64,
+        // The `case_id` group, also when `NestedGroups::Leaves` leaves it out of the parameters
+        pub case_id: Option<Param>,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum Column {
+        Message,
+        Mask,
+        Index,
+        Source,
+        LineNumber,
+        Timestamp,
+        Epoch,
+        Level,
+        Component,
+        // All further fields in logfmt form
+        Fields,
+        // The value of one further field
+        Field(String),
+        // Occurrences of the message before deduplication
+        Count,
+        // One digit per whitespace separated token of the message, 1 (or the class digit of the
+        // first parameter in it with typed masks) if it is (partly) a parameter
+        TokenMask,
+        // Values of all capture groups as a JSON list, like the ParameterList of LogHub
+        Params,
+        // Values of all capture groups with their `ParamType` as a JSON list of objects
+        TypedParams,
+        // The message with every run of masked characters replaced by `<*>`
+        MaskedMessage,
+        // The message with every run of masked characters replaced by the name of its
+        // `ParamType`, e.g. `<ip>`
+        TypedMaskedMessage,
+        // The matching regex in LogHub template form
+        Template,
+        // Similarity of fuzzy matches, empty for regex matches
+        Similarity,
+        // Values of the named capture groups as a JSON object
+        NamedParams,
+        // The value of one named capture group
+        Param(String),
+        // Index and mask of every matching regex of the `all` double match policy as JSON
+        Alternatives,
+        // Comma separated indices of the matching regexes that lost to the reported one
+        Overruled,
+        // Confidence of the label between 0 and 1, see `Response::confidence`
+        Confidence,
+        // Stable id of the matching regex as 16 hex digits
+        TemplateId,
+        // Value of the `case_id` group, empty if the regex has none
+        CaseId,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum MatchError {
+        NoMatch,
+        // Indices of all regexes matching the message
+        AmbiguousMatch { indices: Vec<usize> },
+    }
+
+    // Resolution of messages matched by more than one regex
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub enum DoubleMatchPolicy {
+        // Report the message as an `AmbiguousMatch`
+        #[default]
+        Fail,
+        // The first matching regex in file order wins
+        First,
+        // The matching regex with the longest pattern wins, being the most specific one
+        Longest,
+        // Regex indices in order of preference; unlisted regexes rank below them, in file order
+        Priority(Vec<usize>),
+        // Label the message with the first matching regex and keep all of them as alternatives
+        All,
+    }
+
+    // What a digit of the mask stands for. Both agree on ASCII messages, while multi-byte UTF-8
+    // characters take several digits of a byte mask but one of a character mask.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum MaskUnit {
+        // One digit per byte of the UTF-8 encoded message, as the offsets of the `regex` crate
+        #[default]
+        Byte,
+        // One digit per Unicode scalar value, as `str::chars`
+        Char,
+    }
+
+    // Masking of capture groups containing other groups
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum NestedGroups {
+        // Every group is masked and a parameter, and the innermost group decides the digit of a
+        // character, e.g. a `const_*` group within another group stays unmasked
+        #[default]
+        Innermost,
+        // Only groups without a non-empty group inside are masked and parameters, so the text
+        // between the inner groups of `((\d+)ms/(\d+)ms)` stays unmasked
+        Leaves,
+    }
+
+    // Format of the dataset file
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum OutputFormat {
+        #[default]
+        Csv,
+        // One JSON object per message, keyed by the column names
+        Jsonl,
+        // Arrow IPC file with a column per selected column
+        #[cfg(feature = "arrow")]
+        Arrow,
+    }
+
+    // Normalization of messages before they are matched, while their masks and parameters are
+    // taken from the original message
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Normalization {
+        // Runs of whitespace become a single space
+        Whitespace,
+        Lowercase,
+    }
+
+    // Candidates of a message for the regexes without a literal first token in the token index
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum TokenFallback {
+        // Always, so the index never changes which regexes match
+        #[default]
+        Always,
+        // Only if none of the regexes indexed by its first token matched it, which prefers them
+        Unmatched,
+    }
+
+    // Encoding of the mask column
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum MaskFormat {
+        // One 0/1 digit (or `ParamClass` digit) per unit of the message
+        #[default]
+        Digits,
+        // Lengths of the alternating runs of 0s and 1s, starting with 0s, e.g. `5,2,3` for
+        // `0000011000`
+        Rle,
+        // The runs of 1s as a JSON list of `[start,length]` pairs, e.g. `[[5,2]]`
+        Spans,
+        // The runs of 1s as a JSON list of objects with an exclusive end, e.g.
+        // `[{"start":5,"end":7}]`, as annotation tools like Label Studio take them
+        SpanObjects,
+    }
+
+    // Kind of value of a capture group, whose digit marks its characters in typed masks
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum ParamClass {
+        Generic = 1,
+        // Integers and decimals, optionally signed
+        Number = 2,
+        // IPv4 or IPv6 address, optionally with a port and a leading `/` as logged by Java
+        Ip = 3,
+        // Absolute or relative file system path, e.g. `/var/log` or `C:\Temp`
+        Path = 4,
+        // `0x` prefixed hex numbers, or at least 8 hex digits like hashes and block ids
+        Hex = 5,
+        Uuid = 6,
+    }
+
+    // Type of a captured value, inferred from its text
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub enum ParamType {
+        Datetime,
+        // IPv4 or IPv6 address, optionally with a port and a leading `/`
+        Ip,
+        Int,
+        Float,
+        // `0x` prefixed hex numbers, or at least 8 hex digits
+        Hex,
+        Path,
+        // UUIDs and prefixed identifiers like `blk_-1608999687919862906`
+        Id,
+        String,
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct MatchOptions {
+        pub double_match: DoubleMatchPolicy,
+        pub mask_unit: MaskUnit,
+        // Only run the regexes whose literals occur in the message, found by an Aho-Corasick
+        // automaton, instead of the regex set
+        pub prefilter: bool,
+        // Find the candidate regexes with a Hyperscan database of all the patterns, compiled in
+        // prefilter mode, before running the regexes for their captures
+        #[cfg(feature = "hyperscan")]
+        pub hyperscan: bool,
+        // Only run the regexes whose literal first token is the first token of the message, and
+        // those starting with a variable as `token_fallback` says
+        pub token_index: bool,
+        pub token_fallback: TokenFallback,
+        // Number of match results the workers keep for repeated messages
+        pub cache_size: Option<usize>,
+        // Minimum Jaccard similarity between the tokens of an unmatched message and the literal
+        // tokens of a template for the closest template to label the message
+        pub fuzzy: Option<f64>,
+        // Match every regex against every message, even under the `first` policy, and count the
+        // pairs of regexes matching the same message in the stats
+        pub overlaps: bool,
+        // Mark the characters of every parameter with the digit of its `ParamClass` instead of 1
+        pub typed_mask: bool,
+        pub nested_groups: NestedGroups,
+        // Match every regex even under the `first` policy, so the confidence of a double match
+        // counts all the regexes it was resolved between
+        pub confidence: bool,
+        // Time every regex on its own instead of matching with the regex set, see `MatchStats::times`
+        pub profile: bool,
+        // Disable a regex once matching it against a single message took longer, which implies
+        // `profile`
+        pub time_budget: Option<Duration>,
+        // Match the regexes against the normalized message, mapping the captures back to the
+        // original one
+        pub normalize: Vec<Normalization>,
+        // Send the failures of the workers to `ThreadPoolInput::errors` instead of logging them,
+        // except for the unmatched messages the pool retains
+        pub report_errors: bool,
+        // Try the regexes in the order of their matches so far in every worker instead of the
+        // regex set; under the `first` policy the regexes before a match in the file are still
+        // tried, so the first matching regex of the file wins regardless of the order
+        pub adaptive_order: bool,
+        // Limits of the regex set, the same as the `RegexOptions` the regexes were compiled with
+        pub size_limit: Option<usize>,
+        pub dfa_size_limit: Option<usize>,
+    }
+
+    // A message the workers could not label, along with the reason
+    #[derive(Debug)]
+    pub struct Unmatched {
+        pub msg: String,
+        pub error: MatchError,
+        pub metadata: Metadata,
+    }
+
+    // A failure of a worker along with the message it failed on
+    #[derive(Debug)]
+    pub struct WorkerError {
+        pub worker: String,
+        pub kind: WorkerErrorKind,
+        pub msg: String,
+        pub metadata: Metadata,
+    }
+
+    #[derive(Debug)]
+    pub enum WorkerErrorKind {
+        Match(MatchError),
+        // The response could not be sent, as the output of the pool was dropped
+        Send,
+    }
+
+    // Outcomes of the matching attempts (a message re-matched after --watch reloaded the regexes
+    // counts again), counted by every worker and merged when they are joined
+    #[derive(Clone, Debug, Default)]
+    pub struct MatchStats {
+        // Messages labeled by each regex, by regex index
+        pub matches: Vec<usize>,
+        // The patterns of the regexes at the end of the run
+        pub patterns: Vec<String>,
+        // Messages labeled by --fuzzy instead of a regex
+        pub fuzzy: usize,
+        pub unmatched: usize,
+        pub ambiguous: usize,
+        // Pairs of regexes (lower index first) that matched the same message, with --overlaps
+        pub overlaps: BTreeMap<(usize, usize), Overlap>,
+        // Time spent in the regexes at the end of the run, by regex index, if profiled
+        pub times: Vec<RegexTime>,
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct RegexTime {
+        pub total: Duration,
+        pub runs: u64,
+        // Exceeded the time budget on a message and was skipped from then on
+        pub disabled: bool,
+    }
+
+    // Messages matched by both regexes of a pair
+    #[derive(Clone, Debug, Default)]
+    pub struct Overlap {
+        pub count: usize,
+        // The first few of these messages, up to `OVERLAP_EXAMPLES`
+        pub examples: Vec<String>,
+    }
+
+    pub const DEFAULT_COLUMNS: [Column; 3] = [Column::Message, Column::Mask, Column::Index];
+
+    // A submitted message is settled once it was dropped by a worker (since no regex matched it)
+    // or its response was acknowledged by the consumer of the output
     pub struct ThreadPoolInput {
-        input: spmc::Sender<Request>,
-        join_handles: Vec<JoinHandle<String>>,
+        input: Sender<Request>,
+        join_handles: Vec<JoinHandle<(String, MatchStats)>>,
+        submitted: usize,
+        settled: Arc<AtomicUsize>,
+        regexes: Arc<SharedRegexes>,
+        unmatched: Receiver<Unmatched>,
+        errors: Receiver<WorkerError>,
+        options: Arc<MatchOptions>,
+    }
+
+    // Regexes of the workers, which pick up a reloaded set before their next message
+    struct SharedRegexes {
+        generation: AtomicUsize,
+        regexes: RwLock<Arc<CompiledRegexes>>,
+    }
+
+    // The set finds all regexes matching a message in a single pass, so only the matching
+    // ones are run again for their captures. Sets too large to compile fall back to trying
+    // every regex in turn.
+    struct CompiledRegexes {
+        regexes: Vec<PatternRegex>,
+        priorities: Vec<i32>,
+        ids: Vec<u64>,
+        templates: Vec<Arc<str>>,
+        // Literal tokens of the templates for fuzzy matching
+        literals: Vec<HashSet<String>>,
+        set: Option<RegexSet>,
+        prefilter: Option<Prefilter>,
+        token_index: Option<TokenIndex>,
+        #[cfg(feature = "hyperscan")]
+        hyperscan: Option<HyperscanFilter>,
+        // Shared by the workers and dropped along with the regexes on a reload
+        cache: Option<Mutex<MatchCache>>,
+        profile: Option<Profile>,
+    }
+
+    // Time spent in every regex, summed up by all workers. A regex exceeding the budget on a
+    // single message is disabled for all of them, so one pathological pattern cannot stall the run.
+    struct Profile {
+        nanos: Vec<AtomicU64>,
+        runs: Vec<AtomicU64>,
+        disabled: Vec<AtomicBool>,
+        budget: Option<Duration>,
+    }
+
+    // Results of recently matched messages by the XXH3 hash of the message. `order` maps the last
+    // use of every entry to its hash, so the least recently used one is evicted first.
+    struct MatchCache {
+        capacity: usize,
+        entries: HashMap<u128, (u64, Result<Match, MatchError>)>,
+        order: BTreeMap<u64, u128>,
+        clock: u64,
+    }
+
+    // Every match of a filtered regex contains one of its literals, so a regex whose literals
+    // are all missing from a message cannot match it
+    struct Prefilter {
+        automaton: AhoCorasick,
+        // Regex of each pattern of the automaton
+        owners: Vec<usize>,
+        // Regexes without usable literals, which are candidates for every message
+        unfiltered: Vec<usize>,
+        regex_count: usize,
+    }
+
+    // Regexes by their literal first token
+    struct TokenIndex {
+        tokens: HashMap<String, Vec<usize>>,
+        // Regexes without a literal first token
+        unindexed: Vec<usize>,
+        fallback: TokenFallback,
+    }
+
+    // A normalized message along with the byte range of the original message every one of its
+    // bytes stems from
+    struct Normalized {
+        text: String,
+        sources: Vec<Range<usize>>,
+        original_len: usize,
+    }
+
+    // Evaluation order of a worker, by descending number of matches since the regexes were
+    // loaded, updated every `ADAPTIVE_ORDER_INTERVAL` labeled messages
+    struct AdaptiveOrder {
+        matches: Vec<u64>,
+        // Regex indices in evaluation order, and the position of every regex in it
+        order: Vec<usize>,
+        rank: Vec<usize>,
+        until_update: usize,
+    }
+
+    // Prefilter mode compiles patterns Hyperscan does not support exactly (e.g. backreferences)
+    // into supersets of their matches, so every regex that matches a message is a candidate.
+    // A scratch space serves one scan at a time, so the workers share a pool of them.
+    #[cfg(feature = "hyperscan")]
+    struct HyperscanFilter {
+        database: hyperscan::BlockDatabase,
+        scratches: Mutex<Vec<hyperscan::Scratch>>,
     }
 
     pub struct ThreadPoolOutput {
-        output: mpsc::Receiver<Response>,
+        output: Receiver<Response>,
+        settled: Arc<AtomicUsize>,
     }
 
     pub struct ThreadPoolOutputIter {
-        output_receiver: mpsc::Receiver<Response>,
+        output_receiver: Receiver<Response>,
+        settled: Arc<AtomicUsize>,
+    }
+
+    // Pseudonyms replacing parameter values, so datasets of production logs can be shared
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum AnonymizeMode {
+        // 16 hex digits of the SipHash-2-4 of the value under a 128-bit key, the same in every run
+        // with the same key
+        Hash,
+        // The type of the value and a number counting the distinct values of the type, e.g. `ip_3`,
+        // in the order they are written
+        Token,
+    }
+
+    // Replaces the parameters of responses by pseudonyms, the same one for equal values. The
+    // masks are rewritten along with the messages, so they mark the pseudonyms.
+    pub struct Anonymizer {
+        mode: AnonymizeMode,
+        // Types of the parameters to replace, all of them if empty
+        types: Vec<ParamType>,
+        hasher: SipHasher24,
+        pseudonyms: HashMap<String, String>,
+        counts: HashMap<ParamType, usize>,
+    }
+
+    // Matches messages on the calling thread, as every worker of the thread pool does. A matcher
+    // can be shared between threads, which then share its match cache.
+    pub struct Matcher {
+        compiled: CompiledRegexes,
+        options: MatchOptions,
     }
 
     impl Response {
-        pub fn into_csv_record(self) -> [String; 3] {
-            [self.msg, self.msk, self.idx.to_string()]
+        pub fn into_csv_record(self, columns: &[Column], mask_format: MaskFormat) -> Vec<String> {
+            columns.iter().map(|column| {
+                match column {
+                    Column::Message => self.msg.clone(),
+                    Column::Mask => encode_mask(&self.msk, mask_format),
+                    Column::Index => self.idx.to_string(),
+                    Column::Source => self.metadata.source.to_string(),
+                    Column::LineNumber => self.metadata.line_number.to_string(),
+                    Column::Timestamp => self.metadata.timestamp.as_ref().and_then(Timestamp::to_iso8601).unwrap_or_default(),
+                    Column::Epoch => {
+                        self.metadata.timestamp.as_ref()
+                            .and_then(Timestamp::to_epoch_millis)
+                            .map(|epoch| epoch.to_string())
+                            .unwrap_or_default()
+                    }
+                    Column::Level => self.metadata.level.clone().unwrap_or_default(),
+                    Column::Component => self.metadata.component.clone().unwrap_or_default(),
+                    Column::Fields => {
+                        self.metadata.fields.iter()
+                            .map(|(key, value)| format!("{}={}", key, logfmt_value(value)))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    }
+                    Column::Field(name) => {
+                        self.metadata.fields.iter()
+                            .find(|(key, _)| key == name)
+                            .map(|(_, value)| value.clone())
+                            .unwrap_or_default()
+                    }
+                    Column::Count => self.count.to_string(),
+                    Column::TokenMask => token_mask(&self.msg, &self.msk, self.mask_unit),
+                    Column::Template => self.template.to_string(),
+                    Column::Similarity => self.similarity.map(|similarity| format!("{:.3}", similarity)).unwrap_or_default(),
+                    Column::Params => {
+                        serde_json::Value::from(self.params.iter().filter(|param| !param.is_const()).map(|param| param.value.clone()).collect::<Vec<_>>()).to_string()
+                    }
+                    Column::NamedParams => {
+                        let mut named = serde_json::Map::new();
+                        for param in &self.params {
+                            if let Some(name) = &param.name {
+                                named.entry(name.clone()).or_insert_with(|| param.value.clone().into());
+                            }
+                        }
+                        serde_json::Value::Object(named).to_string()
+                    }
+                    Column::Param(name) => {
+                        self.params.iter()
+                            .find(|param| param.name.as_ref() == Some(name))
+                            .map(|param| param.value.clone())
+                            .unwrap_or_default()
+                    }
+                    Column::TypedParams => {
+                        let params: Vec<serde_json::Value> = self.params.iter()
+                            .filter(|param| !param.is_const())
+                            .map(|param| serde_json::json!({ "value": param.value, "type": ParamType::of(&param.value).name() }))
+                            .collect();
+                        serde_json::Value::from(params).to_string()
+                    }
+                    Column::MaskedMessage => masked_message(&self.msg, &self.msk, self.mask_unit, false),
+                    Column::TypedMaskedMessage => masked_message(&self.msg, &self.msk, self.mask_unit, true),
+                    Column::Alternatives => {
+                        let alternatives: Vec<serde_json::Value> = self.alternatives.iter()
+                            .map(|alternative| serde_json::json!({ "idx": alternative.idx, "mask": encode_mask(&alternative.msk, mask_format) }))
+                            .collect();
+                        serde_json::Value::from(alternatives).to_string()
+                    }
+                    Column::Overruled => self.overruled.iter().map(u32::to_string).collect::<Vec<_>>().join(","),
+                    Column::Confidence => format!("{:.3}", self.confidence),
+                    Column::TemplateId => format!("{:016x}", self.id),
+                    Column::CaseId => self.case_id.as_ref().map(|param| param.value.clone()).unwrap_or_default(),
+                }
+            }).collect()
+        }
+
+        // The record as a JSON object keyed by the column names, with numbers and the columns
+        // holding JSON embedded as such rather than as strings
+        pub fn into_json_record(self, columns: &[Column], mask_format: MaskFormat) -> serde_json::Map<String, serde_json::Value> {
+            let values = self.into_csv_record(columns, mask_format);
+            columns.iter().zip(values).map(|(column, value)| {
+                let json = match column {
+                    _ if column.is_numeric() => {
+                        if value.is_empty() { Some(serde_json::Value::Null) } else { serde_json::from_str(&value).ok() }
+                    }
+                    Column::Params | Column::TypedParams | Column::NamedParams | Column::Alternatives => serde_json::from_str(&value).ok(),
+                    Column::Mask if matches!(mask_format, MaskFormat::Spans | MaskFormat::SpanObjects) => serde_json::from_str(&value).ok(),
+                    _ => None,
+                };
+                (column.name(), json.unwrap_or(serde_json::Value::String(value)))
+            }).collect()
         }
     }
 
-    impl ThreadPoolInput {
-        pub fn submit(&mut self, msg: String) {
-            self.input.send(Request::Parse(msg)).expect("Unable to submit job");
+    impl Column {
+        // Columns holding a number, or nothing when it is unknown
+        pub fn is_numeric(&self) -> bool {
+            matches!(self, Column::Index | Column::LineNumber | Column::Count | Column::Epoch | Column::Similarity | Column::Confidence)
         }
 
-        pub fn end_of_stream(&mut self) {
-            for _ in 0..self.join_handles.len() {
-                self.input.send(Request::EndOfStream).expect("Unable to send termination request");
+        // Name of the column as given to --columns
+        pub fn name(&self) -> String {
+            let name = match self {
+                Column::Message => "msg",
+                Column::Mask => "mask",
+                Column::Index => "idx",
+                Column::Source => "source",
+                Column::LineNumber => "line",
+                Column::Timestamp => "timestamp",
+                Column::Epoch => "epoch",
+                Column::Level => "level",
+                Column::Component => "component",
+                Column::Fields => "fields",
+                Column::Field(key) => return format!("field:{}", key),
+                Column::Count => "count",
+                Column::TokenMask => "token_mask",
+                Column::Params => "params",
+                Column::TypedParams => "typed_params",
+                Column::MaskedMessage => "masked_msg",
+                Column::TypedMaskedMessage => "typed_masked_msg",
+                Column::Template => "template",
+                Column::Similarity => "similarity",
+                Column::NamedParams => "named_params",
+                Column::Param(name) => return format!("param:{}", name),
+                Column::Alternatives => "alternatives",
+                Column::Overruled => "overruled",
+                Column::Confidence => "confidence",
+                Column::TemplateId => "template_id",
+                Column::CaseId => "case_id",
+            };
+            name.to_string()
+        }
+    }
+
+    impl fmt::Display for MatchError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                MatchError::NoMatch => write!(f, "no match"),
+                MatchError::AmbiguousMatch { indices } => {
+                    write!(f, "double match of regexes {}", indices.iter().map(usize::to_string).collect::<Vec<_>>().join(", "))
+                }
             }
         }
+    }
 
-        pub fn join(self) {
-            for worker in self.join_handles {
-                let name = worker.join().expect("Unable to join the thread");
-                debug!("Worker thread with name '{}' joined the main thread", name);
+    impl WorkerErrorKind {
+        pub fn name(&self) -> &'static str {
+            match self {
+                WorkerErrorKind::Match(MatchError::NoMatch) => "no_match",
+                WorkerErrorKind::Match(MatchError::AmbiguousMatch { .. }) => "double_match",
+                WorkerErrorKind::Send => "send",
             }
         }
     }
 
-    impl IntoIterator for ThreadPoolOutput {
-        type Item = Response;
-        type IntoIter = ThreadPoolOutputIter;
+    impl fmt::Display for WorkerErrorKind {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                WorkerErrorKind::Match(error) => error.fmt(f),
+                WorkerErrorKind::Send => write!(f, "the output of the pool was dropped"),
+            }
+        }
+    }
 
-        fn into_iter(self) -> Self::IntoIter {
-            ThreadPoolOutputIter {
-                output_receiver: self.output,
+    impl FromStr for DoubleMatchPolicy {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "fail" => Ok(DoubleMatchPolicy::Fail),
+                "first" => Ok(DoubleMatchPolicy::First),
+                "longest" => Ok(DoubleMatchPolicy::Longest),
+                "all" => Ok(DoubleMatchPolicy::All),
+                _ => match s.strip_prefix("priority:") {
+                    Some(order) => order.split(',')
+                        .map(|idx| idx.trim().parse::<usize>().map_err(|_| format!("Invalid regex index '{}'", idx)))
+                        .collect::<Result<_, _>>()
+                        .map(DoubleMatchPolicy::Priority),
+                    None => Err(format!("Unknown double match policy '{}', expected fail, first, longest, all or priority:IDX,...", s)),
+                },
             }
         }
     }
 
-    impl Iterator for ThreadPoolOutputIter {
-        type Item = Response;
+    impl DoubleMatchPolicy {
+        // Picks the winner among the indices of the matching regexes, which are in file order
+        fn resolve(&self, v: &[PatternRegex], indices: &[usize]) -> Option<usize> {
+            match self {
+                DoubleMatchPolicy::Fail => None,
+                DoubleMatchPolicy::First | DoubleMatchPolicy::All => indices.first().copied(),
+                DoubleMatchPolicy::Longest => indices.iter().copied().rev().max_by_key(|&i| v[i].as_str().len()),
+                DoubleMatchPolicy::Priority(order) => indices.iter().copied()
+                    .min_by_key(|i| order.iter().position(|preferred| preferred == i).unwrap_or(order.len())),
+            }
+        }
+    }
 
-        fn next(&mut self) -> Option<Self::Item> {
-            loop {
-                match self.output_receiver.recv() {
-                    Ok(res) => { return Some(res); }
-                    Err(RecvErr::NoMessage) => { continue; }
-                    Err(RecvErr::NoSender) => { return None; }
-                }
+    impl FromStr for MaskUnit {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "byte" => Ok(MaskUnit::Byte),
+                "char" => Ok(MaskUnit::Char),
+                _ => Err(format!("Unknown mask unit '{}', expected byte or char", s)),
             }
         }
     }
 
-    pub fn start_thread_pool(regex_vec: Vec<Regex>, worker_count: u8) -> (ThreadPoolInput, ThreadPoolOutput) {
-        let (i_tx, i_rx) = spmc::create();
-        let (o_tx, o_rx) = mpsc::create();
-        let mut handles = Vec::new();
+    impl ParamClass {
+        pub fn of(value: &str) -> ParamClass {
+            match ParamType::of(value) {
+                ParamType::Int | ParamType::Float => ParamClass::Number,
+                ParamType::Ip => ParamClass::Ip,
+                ParamType::Path => ParamClass::Path,
+                ParamType::Hex => ParamClass::Hex,
+                ParamType::Id if is_uuid(value) => ParamClass::Uuid,
+                ParamType::Id | ParamType::Datetime | ParamType::String => ParamClass::Generic,
+            }
+        }
 
-        for idx in 0..worker_count {
-            let rx = i_rx.clone();
-            let tx = o_tx.clone();
-            let rv = regex_vec.clone();
-            let handle = std::thread::Builder::new()
-                .name(format!("LockFreeWorker {}", idx))
-                .spawn(move || { worker_loop(rx, tx, rv) })
-                .expect("Unable to spawn a thread");
-            handles.push(handle);
+        fn digit(self) -> u8 {
+            b'0' + self as u8
         }
+    }
 
-        (ThreadPoolInput {
-            input: i_tx,
-            join_handles: handles,
-        }, ThreadPoolOutput {
-            output: o_rx,
+    impl ParamType {
+        pub fn of(value: &str) -> ParamType {
+            static INT: OnceLock<Regex> = OnceLock::new();
+            static FLOAT: OnceLock<Regex> = OnceLock::new();
+            static DATETIME: OnceLock<Regex> = OnceLock::new();
+            static ID: OnceLock<Regex> = OnceLock::new();
+            let int = INT.get_or_init(|| Regex::new(r"^[-+]?\d+$").expect("Invalid int regex"));
+            let float = FLOAT.get_or_init(|| {
+                Regex::new(r"^[-+]?(?:\d+\.\d*|\.\d+|\d+(?:\.\d*)?[eE][-+]?\d+)$").expect("Invalid float regex")
+            });
+            // Dates like `2015-10-18` or `2015/10/18` with an optional time, `18/Oct/2015:18:18:01`
+            // of access logs, or times like `18:18:01,234`, optionally with a time zone
+            let datetime = DATETIME.get_or_init(|| {
+                Regex::new(r"^(?:\d{4}[-/]\d\d[-/]\d\d(?:[T ]\d\d:\d\d(?::\d\d(?:[.,]\d+)?)?)?|\d\d/[A-Z][a-z]{2}/\d{4}(?::\d\d:\d\d:\d\d)?|\d\d:\d\d:\d\d(?:[.,]\d+)?)(?:Z| ?[+-]\d\d:?\d\d)?$")
+                    .expect("Invalid datetime regex")
+            });
+            // Prefixed identifiers like `blk_-1608999687919862906` or `container_1445144423722_0020_01_000002`
+            let id = ID.get_or_init(|| Regex::new(r"^(?:[A-Za-z]+[_-])+-?\d[\w-]*$").expect("Invalid id regex"));
+            let address = value.strip_prefix('/').unwrap_or(value);
+            let hex = value.strip_prefix("0x").or(value.strip_prefix("0X"));
+            if is_uuid(value) {
+                ParamType::Id
+            } else if datetime.is_match(value) {
+                ParamType::Datetime
+            } else if int.is_match(value) {
+                ParamType::Int
+            } else if float.is_match(value) {
+                ParamType::Float
+            } else if address.parse::<IpAddr>().is_ok() || address.parse::<SocketAddr>().is_ok() {
+                ParamType::Ip
+            } else if hex.is_some_and(|hex| !hex.is_empty() && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                || (value.len() >= 8 && value.bytes().all(|b| b.is_ascii_hexdigit())) {
+                ParamType::Hex
+            } else if ["/", "./", "../", "~/"].iter().any(|prefix| value.starts_with(prefix))
+                || value.get(1..3) == Some(":\\") {
+                ParamType::Path
+            } else if id.is_match(value) {
+                ParamType::Id
+            } else {
+                ParamType::String
+            }
+        }
+
+        pub fn name(self) -> &'static str {
+            match self {
+                ParamType::Datetime => "datetime",
+                ParamType::Ip => "ip",
+                ParamType::Int => "int",
+                ParamType::Float => "float",
+                ParamType::Hex => "hex",
+                ParamType::Path => "path",
+                ParamType::Id => "id",
+                ParamType::String => "string",
+            }
+        }
+    }
+
+    fn is_uuid(value: &str) -> bool {
+        value.len() == 36 && value.bytes().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => b == b'-',
+            _ => b.is_ascii_hexdigit(),
         })
     }
 
-    fn worker_loop(rx: spmc::Receiver<Request>, tx: mpsc::Sender<Response>, regex_vec: Vec<Regex>) -> String {
-        let current_thread = std::thread::current();
-        let thread_name = current_thread.name().unwrap_or(UNKNOWN_THREAD_NAME);
-        debug!("Worker thread started with name '{}'", thread_name);
-        loop {
-            match rx.recv() {
-                Ok(Request::Parse(msg)) => {
-                    match match_regex(&regex_vec, msg.as_str()) {
-                        Ok((idx, msk)) => {
-                            tx.send(Response {
-                                msg,
-                                msk,
-                                idx: idx as u16,
-                            })
-                                .expect("Cannot send message");
-                        }
-                        Err(err) => { error!("{}", err) }
-                    }
-                }
-                Ok(Request::EndOfStream) => { break; }
-                Err(RecvErr::NoMessage) => { continue; }
-                Err(RecvErr::NoSender) => { panic!("Sender channel closed before worker is finished") }
+    impl FromStr for NestedGroups {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "innermost" => Ok(NestedGroups::Innermost),
+                "leaves" => Ok(NestedGroups::Leaves),
+                _ => Err(format!("Unknown nested group masking '{}', expected innermost or leaves", s)),
             }
         }
-        thread_name.to_string()
     }
 
-    fn match_regex(v: &[Regex], line: &str) -> Result<(isize, String), String> {
-        let mut m: isize = -1;
-        let mut mask = "0".repeat(line.len());
-        for (i, re) in v.iter().enumerate() {
-            if !re.is_match(line) {
-                continue;
-            }
-            if m != -1 {
-                return Err(format!("double match\n{}\n{}\n{}", line, v[m as usize], v[i]));
-            }
-            m = i as isize;
-            let caps = re.captures(line).unwrap();
-            for i in 1..caps.len() {
-                if let Some(mat) = caps.get(i) {
-                    mask.replace_range(mat.range(), "1".repeat(mat.end() - mat.start()).as_str());
-                }
+    impl FromStr for OutputFormat {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "csv" => Ok(OutputFormat::Csv),
+                "jsonl" => Ok(OutputFormat::Jsonl),
+                #[cfg(feature = "arrow")]
+                "arrow" => Ok(OutputFormat::Arrow),
+                _ => Err(format!("Unknown output format '{}', expected csv, jsonl or arrow (with the arrow feature)", s)),
             }
         }
-        if m != -1 {
-            Ok((m, mask))
-        } else {
-            Err(format!("No match found for '{}'", line))
+    }
+
+    impl OutputFormat {
+        pub fn extension(self) -> &'static str {
+            match self {
+                OutputFormat::Csv => "csv",
+                OutputFormat::Jsonl => "jsonl",
+                #[cfg(feature = "arrow")]
+                OutputFormat::Arrow => "arrow",
+            }
         }
     }
-}
 
-pub mod loading {
-    use std::borrow::Borrow;
-    use std::fs::File;
-    use std::io::{BufRead, BufReader, Lines};
-    use regex::Regex;
-    use walkdir::{DirEntry, WalkDir};
+    impl FromStr for Normalization {
+        type Err = String;
 
-    pub fn load_regex(file: &str) -> Vec<Regex> {
-        let mut v = Vec::new();
-        let file = match File::open(file) {
-            Ok(val) => val,
-            Err(err) => panic!("Invalid file {}", err),
-        };
-        let buf_reader = BufReader::new(file);
-        for (i, l) in buf_reader.lines().enumerate() {
-            let re = Regex::new(format!("^{}$", l.expect("Unable to read line").as_str()).as_str()).unwrap_or_else(|_| panic!("Unable to compile regex at {}", i));
-            v.push(re);
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "whitespace" => Ok(Normalization::Whitespace),
+                "lowercase" => Ok(Normalization::Lowercase),
+                _ => Err(format!("Unknown normalization '{}', expected whitespace or lowercase", s)),
+            }
         }
-        v
     }
 
-    pub fn load_loglines(dir: String) -> impl Iterator<Item=String> {
-        WalkDir::new(dir).into_iter()
-            .filter_map(|result| { result.ok() })
-            .filter(is_log)
-            .flat_map(buf_reader)
-            .filter_map(|result| { result.ok() })
+    impl FromStr for AnonymizeMode {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "hash" => Ok(AnonymizeMode::Hash),
+                "token" => Ok(AnonymizeMode::Token),
+                _ => Err(format!("Unknown anonymization '{}', expected hash or token", s)),
+            }
+        }
     }
 
-    pub fn message_extractor(name: &String) -> impl Fn(String) -> Option<String> {
-        match name.borrow() {
-            "hadoop" => |line: String| {
-                if line.len() > 29 {
-                    let begin = line.find(']')? + 1;
-                    let idx = line[begin..].find(':')? + 1;
-                    Some(line[(begin + idx)..].trim().to_string())
-                } else {
-                    None
-                }
-            },
-            "proxifier" => |line: String| {
-                Some(line[17..].trim().to_string())
-            },
-            "ssh" => |line: String| {
-                if line.len() > 29 {
-                    let begin = line.find(']')?;
-                    Some(line[(begin + 3)..].trim().to_string())
-                } else {
-                    None
-                }
-            },
-            "linux" => |line: String| {
-                if line.len() > 23 {
-                    let begin = line[23..].find(':')? + 2;
-                    let msg = line[23 + begin..].trim();
-                    if !msg.is_empty() {
-                        Some(msg.to_string())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            },
-            "openstack" => |line: String| {
-                if line.len() > 29 {
-                    let begin = line.find(']')?;
-                    Some(line[(begin + 2)..].trim().to_string())
-                } else {
-                    None
-                }
-            },
-            "hdfs" => |line: String| {
-                Some(line.trim()
-                    .splitn(6, ' ')
-                    .last()?.to_string())
-            },
-            "android" => |line: String| {
-                let msg = line[33..]
-                    .splitn(2, ':')
-                    .last()?;
-                if msg.is_empty() {
-                    None
-                } else {
-                    Some(msg.trim().to_string())
-                }
-            },
-            "apache" => |line: String| {
-                // let msg = line[28..]
-                //     .splitn(2, ']')
-                //     .last()?;
-                let v: Vec<&str> = line[28..].splitn(2, ']').collect();
-                if v.len() == 2 {
-                    let msg = v[1].trim();
-                    if msg.is_empty() {
-                        None
-                    } else {
-                        Some(msg.trim().to_string())
-                    }
-                } else {
-                    None
-                }
-            },
-            "zookeeper" => |line: String| {
-                Some(line.splitn(3, " - ").last()?.trim().to_string())
-            },
-            "hpc" => |line: String| {
-                let t= line.trim().splitn(7, ' ').last()?;
-                if t.len() > 2 {
-                    let first_char = t.chars().next().unwrap();
-                    if first_char == '0' || first_char == '1' {
-                        Some(t[2..].to_string())
-                    } else {
-                        Some(t.to_string())
-                    }
-                } else {
-                    None
-                }
-            },
-            _ => { panic!("Unsupported dataset!") }
+    impl FromStr for ParamType {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            [ParamType::Datetime, ParamType::Ip, ParamType::Int, ParamType::Float, ParamType::Hex, ParamType::Path, ParamType::Id, ParamType::String]
+                .into_iter()
+                .find(|param_type| param_type.name() == s)
+                .ok_or(format!("Unknown parameter type '{}', expected datetime, ip, int, float, hex, path, id or string", s))
         }
     }
 
-    fn buf_reader(entry: DirEntry) -> Lines<BufReader<File>> {
-        let f = File::open(entry.path()).expect("Unable to open file");
-        BufReader::new(f).lines()
+    impl FromStr for TokenFallback {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "always" => Ok(TokenFallback::Always),
+                "unmatched" => Ok(TokenFallback::Unmatched),
+                _ => Err(format!("Unknown token fallback '{}', expected always or unmatched", s)),
+            }
+        }
     }
 
-    fn is_log(entry: &DirEntry) -> bool {
-        entry.path().extension().unwrap_or_default() == "log"
+    impl FromStr for MaskFormat {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "digits" => Ok(MaskFormat::Digits),
+                "rle" => Ok(MaskFormat::Rle),
+                "spans" => Ok(MaskFormat::Spans),
+                "span-objects" => Ok(MaskFormat::SpanObjects),
+                _ => Err(format!("Unknown mask format '{}', expected digits, rle, spans or span-objects", s)),
+            }
+        }
     }
-}
\ No newline at end of file
+
+    impl FromStr for Column {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "msg" => Ok(Column::Message),
+                "msk" | "mask" => Ok(Column::Mask),
+                "idx" => Ok(Column::Index),
+                "source" => Ok(Column::Source),
+                "line" => Ok(Column::LineNumber),
+                "timestamp" => Ok(Column::Timestamp),
+                "epoch" => Ok(Column::Epoch),
+                "level" => Ok(Column::Level),
+                "component" => Ok(Column::Component),
+                "fields" => Ok(Column::Fields),
+                "count" => Ok(Column::Count),
+                "token_mask" => Ok(Column::TokenMask),
+                "params" => Ok(Column::Params),
+                "typed_params" => Ok(Column::TypedParams),
+                "masked_msg" => Ok(Column::MaskedMessage),
+                "typed_masked_msg" => Ok(Column::TypedMaskedMessage),
+                "template" => Ok(Column::Template),
+                "similarity" => Ok(Column::Similarity),
+                "named_params" => Ok(Column::NamedParams),
+                "alternatives" => Ok(Column::Alternatives),
+                "overruled" => Ok(Column::Overruled),
+                "confidence" => Ok(Column::Confidence),
+                "template_id" => Ok(Column::TemplateId),
+                "case_id" => Ok(Column::CaseId),
+                _ => {
+                    if let Some(name) = s.strip_prefix("field:") {
+                        Ok(Column::Field(name.to_string()))
+                    } else if let Some(name) = s.strip_prefix("param:") {
+                        Ok(Column::Param(name.to_string()))
+                    } else {
+                        Err(format!("Unknown column '{}'", s))
+                    }
+                }
+            }
+        }
+    }
+
+    impl ThreadPoolInput {
+        pub fn submit(&mut self, msg: String, metadata: Metadata) {
+            self.input.send(Request::Parse(msg, metadata)).expect("Unable to submit job");
+            self.submitted += 1;
+        }
+
+        // Sends the messages to a single worker at once, which saves a channel operation per
+        // message when they are matched quickly
+        pub fn submit_batch(&mut self, batch: Vec<(String, Metadata)>) {
+            if batch.is_empty() {
+                return;
+            }
+            let count = batch.len();
+            self.input.send(Request::Batch(batch)).expect("Unable to submit job");
+            self.submitted += count;
+        }
+
+        // Blocks until every submitted message is settled
+        pub fn wait_settled(&self) {
+            while self.settled.load(Ordering::Acquire) < self.submitted {
+                std::thread::sleep(SETTLE_POLL_INTERVAL);
+            }
+        }
+
+        // Messages submitted later are matched against the new regexes
+        pub fn reload(&self, regex_vec: Vec<TemplateRegex>) {
+            *self.regexes.regexes.write().expect("Poisoned regexes") = Arc::new(CompiledRegexes::new(regex_vec, &self.options));
+            self.regexes.generation.fetch_add(1, Ordering::Release);
+        }
+
+        // Appends regexes to the current ones, whose indices stay the same, and matches the
+        // retained unmatched messages against all of them again. Returns the number of messages
+        // submitted again, none if the regexes would exceed the template indices.
+        pub fn add_regexes(&mut self, added: Vec<TemplateRegex>) -> usize {
+            self.wait_settled();
+            let current = self.regexes.regexes.read().expect("Poisoned regexes").clone();
+            let mut regex_vec: Vec<TemplateRegex> = current.regexes.iter()
+                .zip(&current.priorities)
+                .zip(&current.ids)
+                .map(|((regex, &priority), &id)| TemplateRegex { regex: regex.clone(), priority, id })
+                .collect();
+            regex_vec.extend(added);
+            if regex_vec.len() > MAX_REGEXES {
+                error!("Unable to add {} regexes to the {} of the pool, more than the {} template indices", regex_vec.len() - current.regexes.len(), current.regexes.len(), MAX_REGEXES);
+                return 0;
+            }
+            self.reload(regex_vec);
+            self.resubmit_unmatched()
+        }
+
+        // Submits the retained unmatched messages again, e.g. after a reload, and returns their
+        // number. Messages still being matched are only retained once they settle.
+        pub fn resubmit_unmatched(&mut self) -> usize {
+            let unmatched = self.take_unmatched();
+            let count = unmatched.len();
+            for unmatched in unmatched {
+                self.submit(unmatched.msg, unmatched.metadata);
+            }
+            count
+        }
+
+        // Messages no regex matched so far, if the pool retains them
+        pub fn take_unmatched(&self) -> Vec<Unmatched> {
+            self.unmatched.try_iter().collect()
+        }
+
+        // Retained unmatched messages as they are produced, until the workers are joined
+        pub fn unmatched_messages(&self) -> impl Iterator<Item=Unmatched> + Send {
+            self.unmatched.clone().into_iter()
+        }
+
+        // Failures of the workers as they occur, with `MatchOptions::report_errors`, until the
+        // workers are joined
+        pub fn errors(&self) -> impl Iterator<Item=WorkerError> + Send {
+            self.errors.clone().into_iter()
+        }
+
+        pub fn end_of_stream(&mut self) {
+            for _ in 0..self.join_handles.len() {
+                self.input.send(Request::EndOfStream).expect("Unable to send termination request");
+            }
+        }
+
+        // Returns the statistics of all workers
+        pub fn join(self) -> MatchStats {
+            let mut stats = MatchStats::default();
+            for worker in self.join_handles {
+                let (name, worker_stats) = worker.join().expect("Unable to join the thread");
+                debug!("Worker thread with name '{}' joined the main thread", name);
+                stats.merge(worker_stats);
+            }
+            let compiled = self.regexes.regexes.read().expect("Poisoned regexes").clone();
+            stats.patterns = compiled.regexes.iter().map(|re| re.as_str().to_string()).collect();
+            if let Some(profile) = &compiled.profile {
+                stats.times = profile.times();
+            }
+            stats.matches.resize(stats.patterns.len().max(stats.matches.len()), 0);
+            stats
+        }
+    }
+
+    impl MatchStats {
+        fn record(&mut self, result: &Result<Match, MatchError>) {
+            match result {
+                Ok(Match { similarity: Some(_), .. }) => { self.fuzzy += 1; }
+                Ok(Match { idx, .. }) => {
+                    if self.matches.len() <= *idx {
+                        self.matches.resize(idx + 1, 0);
+                    }
+                    self.matches[*idx] += 1;
+                }
+                Err(MatchError::NoMatch) => { self.unmatched += 1; }
+                Err(MatchError::AmbiguousMatch { .. }) => { self.ambiguous += 1; }
+            }
+        }
+
+        fn merge(&mut self, other: MatchStats) {
+            if self.matches.len() < other.matches.len() {
+                self.matches.resize(other.matches.len(), 0);
+            }
+            for (count, other) in self.matches.iter_mut().zip(other.matches) {
+                *count += other;
+            }
+            self.fuzzy += other.fuzzy;
+            self.unmatched += other.unmatched;
+            self.ambiguous += other.ambiguous;
+            for (pair, other) in other.overlaps {
+                let overlap = self.overlaps.entry(pair).or_default();
+                overlap.count += other.count;
+                let missing = OVERLAP_EXAMPLES.saturating_sub(overlap.examples.len());
+                overlap.examples.extend(other.examples.into_iter().take(missing));
+            }
+        }
+
+        fn record_overlaps(&mut self, result: &Result<Match, MatchError>, msg: &str) {
+            let mut indices = match result {
+                Ok(Match { idx, overruled, similarity: None, .. }) => [*idx].into_iter().chain(overruled.iter().copied()).collect(),
+                Err(MatchError::AmbiguousMatch { indices }) => indices.clone(),
+                _ => Vec::new(),
+            };
+            indices.sort_unstable();
+            for (n, &first) in indices.iter().enumerate() {
+                for &second in &indices[n + 1..] {
+                    let overlap = self.overlaps.entry((first, second)).or_default();
+                    overlap.count += 1;
+                    if overlap.examples.len() < OVERLAP_EXAMPLES {
+                        overlap.examples.push(msg.to_string());
+                    }
+                }
+            }
+        }
+
+        // Indices of the regexes that never labeled a message
+        pub fn dead_regexes(&self) -> Vec<usize> {
+            self.matches.iter().enumerate().filter(|(_, &count)| count == 0).map(|(i, _)| i).collect()
+        }
+    }
+
+    impl Matcher {
+        pub fn new(regex_vec: Vec<TemplateRegex>, options: MatchOptions) -> Matcher {
+            Matcher {
+                compiled: CompiledRegexes::new(regex_vec, &options),
+                options,
+            }
+        }
+
+        pub fn match_line(&self, line: &str) -> Result<Match, MatchError> {
+            cached_match(&self.compiled, line, &self.options, None)
+        }
+
+        // Index of the regex labeling the line, without its mask and parameters
+        pub fn classify(&self, line: &str) -> Result<usize, MatchError> {
+            classify(&self.compiled, line, &self.options)
+        }
+
+        pub fn regex_count(&self) -> usize {
+            self.compiled.regexes.len()
+        }
+    }
+
+    impl Anonymizer {
+        // The 128-bit key makes the hashes a keyed pseudorandom function, so without it short
+        // values cannot be recovered from their hashes even given other value/hash pairs
+        pub fn new(mode: AnonymizeMode, types: Vec<ParamType>, key: [u8; 16]) -> Anonymizer {
+            Anonymizer {
+                mode,
+                types,
+                hasher: SipHasher24::new_with_key(&key),
+                pseudonyms: HashMap::new(),
+                counts: HashMap::new(),
+            }
+        }
+
+        fn pseudonym(&mut self, value: &str) -> String {
+            if let Some(pseudonym) = self.pseudonyms.get(value) {
+                return pseudonym.clone();
+            }
+            let pseudonym = match self.mode {
+                AnonymizeMode::Hash => format!("{:016x}", self.hasher.hash(value.as_bytes())),
+                AnonymizeMode::Token => {
+                    let param_type = ParamType::of(value);
+                    let count = self.counts.entry(param_type).or_default();
+                    *count += 1;
+                    format!("{}_{}", param_type.name(), count)
+                }
+            };
+            self.pseudonyms.insert(value.to_string(), pseudonym.clone());
+            pseudonym
+        }
+
+        // Replaces the parameters of the selected types along with the groups within them. Of
+        // nested groups, the outermost one is replaced in the message.
+        pub fn anonymize(&mut self, res: &mut Response) {
+            let mut selected: Vec<Range<usize>> = res.params.iter()
+                .filter(|param| !param.is_const() && (self.types.is_empty() || self.types.contains(&ParamType::of(&param.value))))
+                .map(|param| param.range.clone())
+                .collect();
+            if selected.is_empty() {
+                return;
+            }
+            selected.sort_by_key(|range| (range.start, std::cmp::Reverse(range.end)));
+            let mut replaced: Vec<Range<usize>> = Vec::new();
+            for range in selected {
+                if replaced.last().is_none_or(|last| last.end <= range.start) && !range.is_empty() {
+                    replaced.push(range);
+                }
+            }
+            // Groups within a replaced one get their own pseudonym, and groups containing replaced
+            // ones get their value with the pseudonyms in place
+            for param in res.params.iter_mut().chain(res.case_id.as_mut()) {
+                if replaced.iter().any(|range| range.start <= param.range.start && param.range.end <= range.end) {
+                    param.value = self.pseudonym(&param.value);
+                } else if replaced.iter().any(|range| param.range.start <= range.start && range.end <= param.range.end) {
+                    let mut value = String::new();
+                    let mut start = param.range.start;
+                    for range in replaced.iter().filter(|range| param.range.start <= range.start && range.end <= param.range.end) {
+                        value.push_str(&res.msg[start..range.start]);
+                        value.push_str(&self.pseudonym(&res.msg[range.clone()]));
+                        start = range.end;
+                    }
+                    value.push_str(&res.msg[start..param.range.end]);
+                    param.value = value;
+                }
+            }
+            let units = |c: char| match res.mask_unit {
+                MaskUnit::Byte => c.len_utf8(),
+                MaskUnit::Char => 1,
+            };
+            let msk = res.msk.as_bytes();
+            let mut msg = String::with_capacity(res.msg.len());
+            let mut mask = Vec::with_capacity(msk.len());
+            // Position of the current character in the original mask
+            let mut position = 0;
+            let mut replaced = replaced.into_iter().peekable();
+            for (i, c) in res.msg.char_indices() {
+                match replaced.peek() {
+                    Some(range) if range.start == i => {
+                        let digit = msk.get(position).copied().unwrap_or(b'1');
+                        let pseudonym = self.pseudonym(&res.msg[range.clone()]);
+                        mask.extend(pseudonym.chars().flat_map(|c| std::iter::repeat_n(digit, units(c))));
+                        msg.push_str(&pseudonym);
+                    }
+                    Some(range) if range.contains(&i) => {}
+                    _ => {
+                        mask.extend((0..units(c)).map(|unit| msk.get(position + unit).copied().unwrap_or(b'0')));
+                        msg.push(c);
+                    }
+                }
+                position += units(c);
+                if replaced.peek().is_some_and(|range| range.end <= i + c.len_utf8()) {
+                    replaced.next();
+                }
+            }
+            res.msg = msg;
+            res.msk = String::from_utf8(mask).expect("Masks are ASCII digits");
+        }
+    }
+
+    impl IntoIterator for ThreadPoolOutput {
+        type Item = Response;
+        type IntoIter = ThreadPoolOutputIter;
+
+        fn into_iter(self) -> Self::IntoIter {
+            ThreadPoolOutputIter {
+                output_receiver: self.output,
+                settled: self.settled,
+            }
+        }
+    }
+
+    impl ThreadPoolOutputIter {
+        // True when every response produced so far has been consumed
+        pub fn is_empty(&self) -> bool {
+            self.output_receiver.is_empty()
+        }
+
+        // Marks `count` responses as persisted, see `ThreadPoolInput::wait_settled`
+        pub fn acknowledge(&self, count: usize) {
+            self.settled.fetch_add(count, Ordering::Release);
+        }
+    }
+
+    impl Iterator for ThreadPoolOutputIter {
+        type Item = Response;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.output_receiver.recv().ok()
+        }
+    }
+
+    // With `retain_unmatched`, messages that no regex matched are kept for `take_unmatched` and
+    // `unmatched_messages`, otherwise they are logged and dropped
+    pub fn start_thread_pool(regex_vec: Vec<TemplateRegex>, worker_count: u8, retain_unmatched: bool, options: MatchOptions) -> (ThreadPoolInput, ThreadPoolOutput) {
+        let options = Arc::new(options);
+        // Blocking channels, so idle workers and the writer sleep instead of polling for messages
+        let (i_tx, i_rx) = crossbeam_channel::unbounded();
+        let (o_tx, o_rx) = crossbeam_channel::unbounded();
+        let (u_tx, u_rx) = crossbeam_channel::unbounded();
+        let (e_tx, e_rx) = crossbeam_channel::unbounded();
+        let settled = Arc::new(AtomicUsize::new(0));
+        let regexes = Arc::new(SharedRegexes {
+            generation: AtomicUsize::new(0),
+            regexes: RwLock::new(Arc::new(CompiledRegexes::new(regex_vec, &options))),
+        });
+        let mut handles = Vec::new();
+
+        for idx in 0..worker_count {
+            let rx = i_rx.clone();
+            let tx = o_tx.clone();
+            let unmatched = if retain_unmatched { Some(u_tx.clone()) } else { None };
+            let errors = if options.report_errors { Some(e_tx.clone()) } else { None };
+            let regexes = regexes.clone();
+            let settled = settled.clone();
+            let options = options.clone();
+            let handle = std::thread::Builder::new()
+                .name(format!("Worker {}", idx))
+                .spawn(move || { worker_loop(rx, tx, unmatched, errors, regexes, settled, options) })
+                .expect("Unable to spawn a thread");
+            handles.push(handle);
+        }
+
+        (ThreadPoolInput {
+            input: i_tx,
+            join_handles: handles,
+            submitted: 0,
+            settled: settled.clone(),
+            regexes,
+            unmatched: u_rx,
+            errors: e_rx,
+            options,
+        }, ThreadPoolOutput {
+            output: o_rx,
+            settled,
+        })
+    }
+
+    fn worker_loop(rx: Receiver<Request>, tx: Sender<Response>, unmatched: Option<Sender<Unmatched>>, errors: Option<Sender<WorkerError>>,
+                   regexes: Arc<SharedRegexes>, settled: Arc<AtomicUsize>, options: Arc<MatchOptions>) -> (String, MatchStats) {
+        let current_thread = std::thread::current();
+        let thread_name = current_thread.name().unwrap_or(UNKNOWN_THREAD_NAME);
+        debug!("Worker thread started with name '{}'", thread_name);
+        let mut generation = regexes.generation.load(Ordering::Acquire);
+        let mut compiled = regexes.regexes.read().expect("Poisoned regexes").clone();
+        let mut stats = MatchStats::default();
+        let mut order = options.adaptive_order.then(|| AdaptiveOrder::new(compiled.regexes.len()));
+        let mut handle = |compiled: &CompiledRegexes, order: &mut Option<AdaptiveOrder>, msg: String, metadata: Metadata| {
+            let result = cached_match(compiled, msg.as_str(), &options, order.as_ref());
+            stats.record(&result);
+            if let (Some(order), Ok(Match { idx, similarity: None, .. })) = (order.as_mut(), &result) {
+                order.record(*idx);
+            }
+            if options.overlaps {
+                stats.record_overlaps(&result, &msg);
+            }
+            let report = |kind: WorkerErrorKind, msg: String, metadata: Metadata| {
+                let errors = errors.as_ref().expect("Worker errors are not reported");
+                errors.send(WorkerError { worker: thread_name.to_string(), kind, msg, metadata }).expect("Cannot report error");
+            };
+            match result {
+                Ok(Match { idx, id, template, mask, params, similarity, alternatives, overruled, confidence, case_id }) => {
+                    let sent = tx.send(Response {
+                        msg,
+                        msk: mask,
+                        mask_unit: options.mask_unit,
+                        idx: idx as u32,
+                        id,
+                        template,
+                        params,
+                        similarity,
+                        alternatives,
+                        overruled: overruled.into_iter().map(|i| i as u32).collect(),
+                        confidence,
+                        count: 1,
+                        case_id,
+                        metadata,
+                    });
+                    match sent {
+                        Ok(()) => {}
+                        Err(SendError(response)) if errors.is_some() => {
+                            report(WorkerErrorKind::Send, response.msg, response.metadata);
+                            settled.fetch_add(1, Ordering::Release);
+                        }
+                        Err(_) => panic!("Cannot send message"),
+                    }
+                }
+                Err(error) => {
+                    match &unmatched {
+                        Some(unmatched) => {
+                            unmatched.send(Unmatched { msg, error, metadata }).expect("Cannot retain message");
+                        }
+                        None if errors.is_some() => { report(WorkerErrorKind::Match(error), msg, metadata); }
+                        None => { log_unmatched(&compiled.regexes, &msg, &error); }
+                    }
+                    settled.fetch_add(1, Ordering::Release);
+                }
+            }
+        };
+        loop {
+            let request = rx.recv();
+            if regexes.generation.load(Ordering::Acquire) != generation {
+                generation = regexes.generation.load(Ordering::Acquire);
+                compiled = regexes.regexes.read().expect("Poisoned regexes").clone();
+                if options.adaptive_order {
+                    order = Some(AdaptiveOrder::new(compiled.regexes.len()));
+                }
+            }
+            match request {
+                Ok(Request::Parse(msg, metadata)) => { handle(&compiled, &mut order, msg, metadata); }
+                Ok(Request::Batch(batch)) => {
+                    for (msg, metadata) in batch {
+                        handle(&compiled, &mut order, msg, metadata);
+                    }
+                }
+                Ok(Request::EndOfStream) => { break; }
+                Err(_) => { panic!("Sender channel closed before worker is finished") }
+            }
+        }
+        (thread_name.to_string(), stats)
+    }
+
+    // Leading inline flags like `(?i)` or `(?-u)` added by the regex options, and the rest of the
+    // pattern
+    fn split_inline_flags(pattern: &str) -> (String, &str) {
+        let mut flags = String::new();
+        let mut pattern = pattern;
+        while let Some((group, rest)) = pattern.strip_prefix("(?").and_then(|rest| rest.split_once(')')) {
+            if group.is_empty() || !group.chars().all(|c| c.is_ascii_alphabetic() || c == '-') {
+                break;
+            }
+            flags.push_str(group);
+            pattern = rest;
+        }
+        (flags, pattern)
+    }
+
+    // The literal first token of a pattern anchored at the start, e.g. `Took` for `^Took (\d+) ms`,
+    // lowercased as messages are looked up case-insensitively. Patterns starting with a variable
+    // or other regex syntax, and those with alternations or multi-line anchors, have none.
+    fn first_literal_token(pattern: &str) -> Option<String> {
+        let (flags, pattern) = split_inline_flags(pattern);
+        if pattern.contains('|') || flags.contains(['m', 'x']) {
+            return None;
+        }
+        let mut chars = pattern.strip_prefix('^')?.chars().peekable();
+        let mut token = String::new();
+        loop {
+            match chars.next() {
+                None | Some('$') => break,
+                // Whitespace that may be missing does not end the token for sure
+                Some(' ') if matches!(chars.peek(), Some('*' | '?' | '{')) => return None,
+                Some(' ') => break,
+                Some('\\') => match chars.next()? {
+                    's' if matches!(chars.peek(), Some('*' | '?' | '{')) => return None,
+                    's' => break,
+                    escaped if escaped.is_ascii_punctuation() => token.push(escaped),
+                    _ => return None,
+                },
+                Some(c) if REGEX_SYNTAX.contains(&c) || c == '.' => return None,
+                Some(c) => token.push(c),
+            }
+            if matches!(chars.peek(), Some('*' | '+' | '?' | '{')) {
+                return None;
+            }
+        }
+        if token.is_empty() { None } else { Some(token.to_lowercase()) }
+    }
+
+    // Replaces the outermost capture groups of a pattern with `<*>` and unescapes its literal
+    // characters, e.g. `^Took (\d+) ms \(cached\)$` becomes `Took <*> ms (cached)`. Other
+    // regex syntax is kept as it is.
+    fn render_template(pattern: &str) -> String {
+        let (_, pattern) = split_inline_flags(pattern);
+        let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+        let pattern = match pattern.strip_suffix('$') {
+            Some(stripped) if !stripped.ends_with('\\') => stripped,
+            _ => pattern,
+        };
+        let mut template = String::new();
+        // Whether each open group is a capture group
+        let mut groups: Vec<bool> = Vec::new();
+        // Depths of the open `const_*` groups
+        let mut const_groups: Vec<usize> = Vec::new();
+        let mut in_class = false;
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            let captured = groups.contains(&true);
+            let mut token = c.to_string();
+            match c {
+                '\\' => {
+                    if let Some(escaped) = chars.next() {
+                        if escaped.is_ascii_punctuation() && !in_class {
+                            token = escaped.to_string();
+                        } else {
+                            token.push(escaped);
+                        }
+                    }
+                }
+                '[' if !in_class => { in_class = true; }
+                ']' if in_class => { in_class = false; }
+                '(' if !in_class => {
+                    // Inline flags like `(?i)` are dropped
+                    let flags: String = chars.clone().take_while(|&c| c != ')').collect();
+                    if flags.len() > 1 && flags.starts_with('?') && flags[1..].chars().all(|c| c.is_ascii_alphabetic() || c == '-') {
+                        chars.nth(flags.len());
+                        continue;
+                    }
+                    let rest: String = chars.clone().take(3).collect();
+                    let named = rest.starts_with("?P<") || (rest.starts_with("?<") && !rest.starts_with("?<=") && !rest.starts_with("?<!"));
+                    let name: String = chars.clone().skip_while(|&c| c != '<').skip(1).take_while(|&c| c != '>').collect();
+                    // Only the content of constant groups stays in the template
+                    if named && is_const_group(&name) {
+                        chars.find(|&c| c == '>');
+                        groups.push(false);
+                        const_groups.push(groups.len());
+                        continue;
+                    }
+                    let capturing = chars.peek() != Some(&'?') || named;
+                    groups.push(capturing);
+                    if capturing && !captured {
+                        template.push_str("<*>");
+                    } else if !captured {
+                        template.push('(');
+                    }
+                    continue;
+                }
+                ')' if !in_class && const_groups.last() == Some(&groups.len()) => {
+                    const_groups.pop();
+                    groups.pop();
+                    continue;
+                }
+                ')' if !in_class => {
+                    let closes_capture = groups.pop() == Some(true);
+                    if closes_capture || captured {
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            if !captured {
+                template.push_str(&token);
+            }
+        }
+        template
+    }
+
+    // All digits of a character are alike in a byte mask, so its first one labels it
+    fn token_mask(msg: &str, msk: &str, unit: MaskUnit) -> String {
+        let msk = msk.as_bytes();
+        let mut tokens = String::new();
+        // The first digit other than 0 of the current token so far
+        let mut token = None;
+        for (n, (i, c)) in msg.char_indices().chain(std::iter::once((msg.len(), ' '))).enumerate() {
+            if c.is_whitespace() {
+                if let Some(digit) = token.take() {
+                    tokens.push(char::from(digit));
+                }
+            } else {
+                let position = match unit {
+                    MaskUnit::Byte => i,
+                    MaskUnit::Char => n,
+                };
+                let digit = msk.get(position).copied().unwrap_or(b'0');
+                token = Some(token.filter(|&token| token != b'0').unwrap_or(digit));
+            }
+        }
+        tokens
+    }
+
+    // Adjacent capture groups form a single run and thus a single placeholder
+    fn masked_message(msg: &str, msk: &str, unit: MaskUnit, typed: bool) -> String {
+        let msk = msk.as_bytes();
+        let placeholder = |value: &str| if typed { format!("<{}>", ParamType::of(value).name()) } else { "<*>".to_string() };
+        let mut masked = String::with_capacity(msg.len());
+        // Start of the current run of masked characters
+        let mut run = None;
+        for (n, (i, c)) in msg.char_indices().enumerate() {
+            let position = match unit {
+                MaskUnit::Byte => i,
+                MaskUnit::Char => n,
+            };
+            if msk.get(position).is_some_and(|&digit| digit != b'0') {
+                run.get_or_insert(i);
+            } else {
+                if let Some(start) = run.take() {
+                    masked.push_str(&placeholder(&msg[start..i]));
+                }
+                masked.push(c);
+            }
+        }
+        if let Some(start) = run {
+            masked.push_str(&placeholder(&msg[start..]));
+        }
+        masked
+    }
+
+    fn encode_mask(msk: &str, format: MaskFormat) -> String {
+        if format == MaskFormat::Digits {
+            return msk.to_string();
+        }
+        // Start and length of every run, 1s at the odd positions
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        let mut start = 0;
+        for (i, digit) in msk.bytes().enumerate() {
+            if (digit != b'0') != (runs.len() % 2 == 1) {
+                runs.push((start, i - start));
+                start = i;
+            }
+        }
+        runs.push((start, msk.len() - start));
+        match format {
+            MaskFormat::Digits => unreachable!(),
+            MaskFormat::Rle => runs.iter().map(|(_, length)| length.to_string()).collect::<Vec<_>>().join(","),
+            MaskFormat::Spans => {
+                let spans: Vec<[usize; 2]> = runs.iter().skip(1).step_by(2).map(|&(start, length)| [start, length]).collect();
+                serde_json::to_string(&spans).expect("Spans are serializable")
+            }
+            MaskFormat::SpanObjects => {
+                // Written by hand, as serde_json would sort the keys
+                let spans: Vec<String> = runs.iter().skip(1).step_by(2)
+                    .map(|&(start, length)| format!(r#"{{"start":{},"end":{}}}"#, start, start + length))
+                    .collect();
+                format!("[{}]", spans.join(","))
+            }
+        }
+    }
+
+    // Turns a mask of one digit per byte into one of the given unit; capture groups always start
+    // and end at character boundaries, so every byte of a character carries the same digit
+    fn mask_string(line: &str, mask: Vec<u8>, unit: MaskUnit) -> String {
+        let mask = match unit {
+            MaskUnit::Byte => mask,
+            MaskUnit::Char => line.char_indices().map(|(i, _)| mask[i]).collect(),
+        };
+        String::from_utf8(mask).expect("Mask is ASCII")
+    }
+
+    fn log_unmatched(v: &[PatternRegex], line: &str, error: &MatchError) {
+        match error {
+            MatchError::NoMatch => error!("No match found for '{}'", line),
+            MatchError::AmbiguousMatch { indices } => {
+                error!("double match\n{}\n{}", line, indices.iter().map(|&i| v[i].as_str()).collect::<Vec<_>>().join("\n"))
+            }
+        }
+    }
+
+    impl CompiledRegexes {
+        fn new(templates: Vec<TemplateRegex>, options: &MatchOptions) -> CompiledRegexes {
+            let priorities = templates.iter().map(|template| template.priority).collect();
+            let ids = templates.iter().map(|template| template.id).collect();
+            let regexes: Vec<PatternRegex> = templates.into_iter().map(|template| template.regex).collect();
+            let profile = (options.profile || options.time_budget.is_some()).then(|| Profile::new(regexes.len(), options.time_budget));
+            let (set, prefilter) = if options.prefilter {
+                (None, Some(Prefilter::new(&regexes)))
+            } else if options.token_index || options.adaptive_order || profile.is_some() || !regexes.iter().all(PatternRegex::is_standard) {
+                (None, None)
+            } else {
+                let mut builder = RegexSetBuilder::new(regexes.iter().map(PatternRegex::as_str));
+                if let Some(limit) = options.size_limit {
+                    builder.size_limit(limit);
+                }
+                if let Some(limit) = options.dfa_size_limit {
+                    builder.dfa_size_limit(limit);
+                }
+                let set = builder.build()
+                    .map_err(|err| warn!("Matching without a regex set: {}", err))
+                    .ok();
+                (set, None)
+            };
+            let templates: Vec<Arc<str>> = regexes.iter().map(|re| render_template(re.as_str()).into()).collect();
+            let literals = templates.iter()
+                .map(|template| {
+                    template.split_whitespace()
+                        .filter(|token| !token.contains("<*>") && !token.contains(REGEX_SYNTAX))
+                        .map(str::to_string)
+                        .collect()
+                })
+                .collect();
+            let cache = options.cache_size.map(|capacity| Mutex::new(MatchCache::new(capacity)));
+            let token_index = options.token_index.then(|| TokenIndex::new(&regexes, options.token_fallback));
+            #[cfg(feature = "hyperscan")]
+            let hyperscan = if options.hyperscan {
+                HyperscanFilter::new(&regexes)
+                    .map_err(|err| warn!("Matching without Hyperscan: {}", err))
+                    .ok()
+            } else {
+                None
+            };
+            #[cfg(feature = "hyperscan")]
+            let set = if hyperscan.is_some() { None } else { set };
+            CompiledRegexes {
+                regexes,
+                priorities,
+                ids,
+                templates,
+                literals,
+                set,
+                prefilter,
+                token_index,
+                #[cfg(feature = "hyperscan")]
+                hyperscan,
+                cache,
+                profile,
+            }
+        }
+
+        // Runs regex `i` on the line, timed if profiled. Disabled regexes do not match.
+        fn run<T>(&self, i: usize, line: &str, run: impl FnOnce(&PatternRegex) -> Option<T>) -> Option<T> {
+            let Some(profile) = &self.profile else {
+                return run(&self.regexes[i]);
+            };
+            if profile.disabled[i].load(Ordering::Relaxed) {
+                return None;
+            }
+            let start = Instant::now();
+            let result = run(&self.regexes[i]);
+            profile.record(i, start.elapsed(), line, &self.regexes[i]);
+            result
+        }
+
+        // Indices of the regexes that may match the line, in file order
+        fn candidates(&self, line: &str) -> Vec<usize> {
+            match &self.token_index {
+                Some(index) => self.narrow(index.candidates(line), line),
+                None => self.filtered_candidates(line),
+            }
+        }
+
+        // Candidates of a line none of its `candidates` matched
+        fn fallback_candidates(&self, line: &str) -> Vec<usize> {
+            match &self.token_index {
+                Some(index) if index.fallback == TokenFallback::Unmatched => self.narrow(index.unindexed.clone(), line),
+                _ => Vec::new(),
+            }
+        }
+
+        // Keeps the candidates of the token index that the literal filters let through as well
+        fn narrow(&self, candidates: Vec<usize>, line: &str) -> Vec<usize> {
+            #[cfg(feature = "hyperscan")]
+            let filtered = self.prefilter.is_some() || self.hyperscan.is_some();
+            #[cfg(not(feature = "hyperscan"))]
+            let filtered = self.prefilter.is_some();
+            if !filtered {
+                return candidates;
+            }
+            let filtered = self.filtered_candidates(line);
+            candidates.into_iter().filter(|i| filtered.binary_search(i).is_ok()).collect()
+        }
+
+        fn filtered_candidates(&self, line: &str) -> Vec<usize> {
+            #[cfg(feature = "hyperscan")]
+            if let Some(hyperscan) = &self.hyperscan {
+                match hyperscan.candidates(line) {
+                    Ok(candidates) => return candidates,
+                    Err(err) => warn!("Hyperscan scan failed, trying every regex: {}", err),
+                }
+            }
+            match &self.prefilter {
+                Some(prefilter) => prefilter.candidates(line),
+                None => (0..self.regexes.len()).collect(),
+            }
+        }
+    }
+
+    impl Profile {
+        fn new(regex_count: usize, budget: Option<Duration>) -> Profile {
+            Profile {
+                nanos: (0..regex_count).map(|_| AtomicU64::new(0)).collect(),
+                runs: (0..regex_count).map(|_| AtomicU64::new(0)).collect(),
+                disabled: (0..regex_count).map(|_| AtomicBool::new(false)).collect(),
+                budget,
+            }
+        }
+
+        fn record(&self, i: usize, elapsed: Duration, line: &str, re: &PatternRegex) {
+            self.nanos[i].fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+            self.runs[i].fetch_add(1, Ordering::Relaxed);
+            if self.budget.is_some_and(|budget| elapsed > budget) && !self.disabled[i].swap(true, Ordering::Relaxed) {
+                warn!("Disabling regex {} after matching it against '{}' took {:?}: {}", i, line, elapsed, re.as_str());
+            }
+        }
+
+        fn times(&self) -> Vec<RegexTime> {
+            (0..self.nanos.len())
+                .map(|i| RegexTime {
+                    total: Duration::from_nanos(self.nanos[i].load(Ordering::Relaxed)),
+                    runs: self.runs[i].load(Ordering::Relaxed),
+                    disabled: self.disabled[i].load(Ordering::Relaxed),
+                })
+                .collect()
+        }
+    }
+
+    impl Normalized {
+        fn new(line: &str, steps: &[Normalization]) -> Normalized {
+            let whitespace = steps.contains(&Normalization::Whitespace);
+            let lowercase = steps.contains(&Normalization::Lowercase);
+            let mut normalized = Normalized {
+                text: String::with_capacity(line.len()),
+                sources: Vec::with_capacity(line.len()),
+                original_len: line.len(),
+            };
+            let mut chars = line.char_indices().peekable();
+            while let Some((i, c)) = chars.next() {
+                let mut end = i + c.len_utf8();
+                if whitespace && c.is_whitespace() {
+                    while let Some((j, next)) = chars.next_if(|(_, next)| next.is_whitespace()) {
+                        end = j + next.len_utf8();
+                    }
+                    normalized.push(' ', i..end);
+                } else if lowercase {
+                    for lower in c.to_lowercase() {
+                        normalized.push(lower, i..end);
+                    }
+                } else {
+                    normalized.push(c, i..end);
+                }
+            }
+            normalized
+        }
+
+        fn push(&mut self, c: char, source: Range<usize>) {
+            self.text.push(c);
+            self.sources.extend(std::iter::repeat_n(source, c.len_utf8()));
+        }
+
+        // The range of the original message a range of the normalized one stems from. Captures
+        // ending within a run of whitespace extend to its end.
+        fn original(&self, range: Range<usize>) -> Range<usize> {
+            if range.is_empty() {
+                let start = self.sources.get(range.start).map_or(self.original_len, |source| source.start);
+                return start..start;
+            }
+            self.sources[range.start].start..self.sources[range.end - 1].end
+        }
+    }
+
+    impl AdaptiveOrder {
+        fn new(regex_count: usize) -> AdaptiveOrder {
+            AdaptiveOrder {
+                matches: vec![0; regex_count],
+                order: (0..regex_count).collect(),
+                rank: (0..regex_count).collect(),
+                until_update: ADAPTIVE_ORDER_INTERVAL,
+            }
+        }
+
+        // The stable sort keeps regexes with as many matches in file order
+        fn record(&mut self, idx: usize) {
+            self.matches[idx] += 1;
+            self.until_update -= 1;
+            if self.until_update == 0 {
+                self.order.sort_by_key(|&i| std::cmp::Reverse(self.matches[i]));
+                for (position, &i) in self.order.iter().enumerate() {
+                    self.rank[i] = position;
+                }
+                self.until_update = ADAPTIVE_ORDER_INTERVAL;
+            }
+        }
+
+        fn arrange(&self, mut candidates: Vec<usize>) -> Vec<usize> {
+            if candidates.len() == self.order.len() {
+                return self.order.clone();
+            }
+            candidates.sort_by_key(|&i| self.rank[i]);
+            candidates
+        }
+    }
+
+    impl MatchCache {
+        fn new(capacity: usize) -> MatchCache {
+            MatchCache {
+                capacity,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                clock: 0,
+            }
+        }
+
+        fn get(&mut self, hash: u128) -> Option<Result<Match, MatchError>> {
+            let (used, result) = self.entries.get_mut(&hash)?;
+            self.order.remove(used);
+            self.clock += 1;
+            *used = self.clock;
+            self.order.insert(self.clock, hash);
+            Some(result.clone())
+        }
+
+        fn insert(&mut self, hash: u128, result: Result<Match, MatchError>) {
+            if self.capacity == 0 {
+                return;
+            }
+            if !self.entries.contains_key(&hash) && self.entries.len() >= self.capacity {
+                if let Some((_, oldest)) = self.order.pop_first() {
+                    self.entries.remove(&oldest);
+                }
+            }
+            self.clock += 1;
+            self.order.insert(self.clock, hash);
+            // Another worker may have matched the same message meanwhile
+            if let Some((used, _)) = self.entries.insert(hash, (self.clock, result)) {
+                self.order.remove(&used);
+            }
+        }
+    }
+
+    impl Prefilter {
+        fn new(regexes: &[PatternRegex]) -> Prefilter {
+            let mut patterns = Vec::new();
+            let mut owners = Vec::new();
+            let mut unfiltered = Vec::new();
+            for (i, re) in regexes.iter().enumerate() {
+                match regex_literals(re) {
+                    Some(literals) => {
+                        for literal in literals.literals() {
+                            patterns.push(literal.to_vec());
+                            owners.push(i);
+                        }
+                    }
+                    None => unfiltered.push(i),
+                }
+            }
+            debug!("Prefiltering {} of {} regexes by {} literals", regexes.len() - unfiltered.len(), regexes.len(), patterns.len());
+            Prefilter {
+                automaton: AhoCorasick::new(patterns),
+                owners,
+                unfiltered,
+                regex_count: regexes.len(),
+            }
+        }
+
+        fn candidates(&self, line: &str) -> Vec<usize> {
+            let mut candidate = vec![false; self.regex_count];
+            for &i in &self.unfiltered {
+                candidate[i] = true;
+            }
+            for mat in self.automaton.find_overlapping_iter(line) {
+                candidate[self.owners[mat.pattern()]] = true;
+            }
+            candidate.iter().enumerate().filter(|(_, &candidate)| candidate).map(|(i, _)| i).collect()
+        }
+    }
+
+    #[cfg(feature = "hyperscan")]
+    impl HyperscanFilter {
+        fn new(regexes: &[PatternRegex]) -> Result<HyperscanFilter, hyperscan::Error> {
+            use hyperscan::{Builder, CompileFlags, Pattern, Patterns};
+            let flags = CompileFlags::PREFILTER | CompileFlags::SINGLEMATCH | CompileFlags::UTF8
+                | CompileFlags::UCP | CompileFlags::ALLOWEMPTY;
+            let patterns = regexes.iter().enumerate()
+                .map(|(i, re)| {
+                    let mut pattern = Pattern::with_flags(re.as_str(), flags)?;
+                    pattern.id = Some(i);
+                    Ok(pattern)
+                })
+                .collect::<Result<Patterns, hyperscan::Error>>()?;
+            let database: hyperscan::BlockDatabase = patterns.build()?;
+            debug!("Compiled {} regexes into a Hyperscan database", regexes.len());
+            Ok(HyperscanFilter { database, scratches: Mutex::new(Vec::new()) })
+        }
+
+        fn candidates(&self, line: &str) -> Result<Vec<usize>, hyperscan::Error> {
+            let pooled = self.scratches.lock().unwrap().pop();
+            let scratch = match pooled {
+                Some(scratch) => scratch,
+                None => self.database.alloc_scratch()?,
+            };
+            let mut candidates = Vec::new();
+            let scanned = self.database.scan(line, &scratch, |id: u32, _from: u64, _to: u64, _flags: u32| {
+                candidates.push(id as usize);
+                hyperscan::Matching::Continue
+            });
+            self.scratches.lock().unwrap().push(scratch);
+            scanned?;
+            candidates.sort_unstable();
+            Ok(candidates)
+        }
+    }
+
+    impl TokenIndex {
+        fn new(regexes: &[PatternRegex], fallback: TokenFallback) -> TokenIndex {
+            let mut tokens: HashMap<String, Vec<usize>> = HashMap::new();
+            let mut unindexed = Vec::new();
+            for (i, re) in regexes.iter().enumerate() {
+                match first_literal_token(re.as_str()) {
+                    Some(token) => tokens.entry(token).or_default().push(i),
+                    None => unindexed.push(i),
+                }
+            }
+            debug!("Indexed {} of {} regexes by {} first tokens", regexes.len() - unindexed.len(), regexes.len(), tokens.len());
+            TokenIndex { tokens, unindexed, fallback }
+        }
+
+        fn candidates(&self, line: &str) -> Vec<usize> {
+            let first = line.split_whitespace().next().unwrap_or_default().to_lowercase();
+            let mut candidates = self.tokens.get(&first).cloned().unwrap_or_default();
+            if self.fallback == TokenFallback::Always {
+                candidates.extend(&self.unindexed);
+                candidates.sort_unstable();
+            }
+            candidates
+        }
+    }
+
+    // The prefixes or suffixes of the regex, whichever have the longer shortest literal. Neither
+    // is usable if extraction gave up on them or one of them is empty, e.g. for `(\d+) (\S+)`.
+    fn regex_literals(re: &PatternRegex) -> Option<Literals> {
+        let hir = regex_syntax::Parser::new().parse(re.as_str()).ok()?;
+        [Literals::prefixes(&hir), Literals::suffixes(&hir)].into_iter()
+            .filter(|literals| !literals.is_empty() && !literals.contains_empty())
+            .max_by_key(|literals| literals.min_len())
+    }
+
+    // The cache is not locked while matching, so workers never wait for each other's regexes
+    fn cached_match(compiled: &CompiledRegexes, line: &str, options: &MatchOptions, order: Option<&AdaptiveOrder>) -> Result<Match, MatchError> {
+        let Some(cache) = &compiled.cache else {
+            return match_regex(compiled, line, options, order);
+        };
+        let hash = xxh3_128(line.as_bytes());
+        if let Some(result) = cache.lock().expect("Poisoned match cache").get(hash) {
+            return result;
+        }
+        let result = match_regex(compiled, line, options, order);
+        cache.lock().expect("Poisoned match cache").insert(hash, result.clone());
+        result
+    }
+
+    // Every regex runs at most once on the line: the set only tells which ones match, and
+    // without it the captures of each match are kept, trying only the candidates of the
+    // prefilter or token index if there is one, in the adaptive order if there is one. The `first`
+    // policy stops at the first match, unless regexes have priorities; in the adaptive order, the
+    // remaining candidates before it in the file are tried first, so the label does not depend on
+    // the matches a worker has seen.
+    fn match_regex(compiled: &CompiledRegexes, original: &str, options: &MatchOptions, order: Option<&AdaptiveOrder>) -> Result<Match, MatchError> {
+        let v = &compiled.regexes;
+        let normalized = (!options.normalize.is_empty()).then(|| Normalized::new(original, &options.normalize));
+        let line = normalized.as_ref().map_or(original, |normalized| normalized.text.as_str());
+        let first_only = options.double_match == DoubleMatchPolicy::First && !options.overlaps && !options.confidence && compiled.priorities.iter().all(|&priority| priority == 0);
+        let matches: Vec<(usize, Option<CaptureRanges>)> = match &compiled.set {
+            Some(set) => set.matches(line).into_iter().take(if first_only { 1 } else { usize::MAX }).map(|i| (i, None)).collect(),
+            None => {
+                let run = |candidates: Vec<usize>| {
+                    let candidates = match order {
+                        Some(order) => order.arrange(candidates),
+                        None => candidates,
+                    };
+                    let mut matches = Vec::new();
+                    let mut candidates = candidates.into_iter();
+                    while let Some(i) = candidates.next() {
+                        if let Some(caps) = compiled.run(i, line, |re| re.captures(line)) {
+                            matches.push((i, Some(caps)));
+                            if first_only {
+                                let mut earlier: Vec<usize> = candidates.by_ref().filter(|&j| j < i).collect();
+                                earlier.sort_unstable();
+                                if let Some(first) = earlier.into_iter().find_map(|j| compiled.run(j, line, |re| re.captures(line)).map(|caps| (j, Some(caps)))) {
+                                    matches = vec![first];
+                                }
+                                break;
+                            }
+                        }
+                    }
+                    matches
+                };
+                let matches = run(compiled.candidates(line));
+                if matches.is_empty() { run(compiled.fallback_candidates(line)) } else { matches }
+            }
+        };
+        let indices: Vec<usize> = matches.iter().map(|(i, _)| *i).collect();
+        let m = match resolve_match(compiled, &indices, options) {
+            Err(MatchError::NoMatch) if options.fuzzy.is_some() => { return fuzzy_match(compiled, original, options); }
+            m => m?,
+        };
+        let overruled = indices.iter().copied().filter(|&i| i != m).collect();
+        let tied = indices.iter().filter(|&&i| compiled.priorities[i] == compiled.priorities[m]).count();
+        let all = options.double_match == DoubleMatchPolicy::All && indices.len() > 1;
+        let mut label = None;
+        let mut alternatives = Vec::new();
+        for (i, caps) in matches {
+            if i != m && !all {
+                continue;
+            }
+            let mut caps = caps.unwrap_or_else(|| v[i].captures(line).expect("Regex set and regex disagree"));
+            if let Some(normalized) = &normalized {
+                caps = caps.into_iter().map(|range| range.map(|range| normalized.original(range))).collect();
+            }
+            let case_id = case_id_param(original, &v[i], &caps);
+            let (mask, params) = capture_mask(original, &v[i], caps, options);
+            if all {
+                alternatives.push(Alternative { idx: i as u32, msk: mask.clone() });
+            }
+            if i == m {
+                label = Some((mask, params, case_id));
+            }
+        }
+        let (mask, params, case_id) = label.expect("Resolved to an unmatched regex");
+        Ok(Match {
+            idx: m,
+            id: compiled.ids[m],
+            template: compiled.templates[m].clone(),
+            mask,
+            params,
+            similarity: None,
+            alternatives,
+            overruled,
+            confidence: 1.0 / tied as f64,
+            case_id,
+        })
+    }
+
+    // `match_regex` without the captures, for counting messages by their regex
+    fn classify(compiled: &CompiledRegexes, original: &str, options: &MatchOptions) -> Result<usize, MatchError> {
+        let normalized = (!options.normalize.is_empty()).then(|| Normalized::new(original, &options.normalize));
+        let line = normalized.as_ref().map_or(original, |normalized| normalized.text.as_str());
+        let indices: Vec<usize> = match &compiled.set {
+            Some(set) => set.matches(line).into_iter().collect(),
+            None => {
+                let run = |candidates: Vec<usize>| -> Vec<usize> {
+                    candidates.into_iter().filter(|&i| compiled.run(i, line, |re| re.is_match(line).then_some(())).is_some()).collect()
+                };
+                let indices = run(compiled.candidates(line));
+                if indices.is_empty() { run(compiled.fallback_candidates(line)) } else { indices }
+            }
+        };
+        match resolve_match(compiled, &indices, options) {
+            Err(MatchError::NoMatch) if options.fuzzy.is_some() => fuzzy_match(compiled, original, options).map(|m| m.idx),
+            m => m,
+        }
+    }
+
+    fn capture_mask(line: &str, re: &PatternRegex, caps: CaptureRanges, options: &MatchOptions) -> (String, Vec<Param>) {
+        let mut mask = vec![b'0'; line.len()];
+        let mut params = Vec::new();
+        let groups: Vec<(Range<usize>, Option<&str>)> = caps.into_iter().zip(re.capture_names()).skip(1)
+            .filter_map(|(range, name)| Some((range?, name)))
+            .collect();
+        // Groups are numbered by their opening parenthesis, so nested groups follow the group
+        // containing them and overwrite its digits
+        for (n, (range, name)) in groups.iter().enumerate() {
+            let leaf = !groups[n + 1..].iter()
+                .any(|(inner, _)| !inner.is_empty() && range.start <= inner.start && inner.end <= range.end);
+            if options.nested_groups == NestedGroups::Leaves && !leaf {
+                continue;
+            }
+            let digit = if name.is_some_and(is_const_group) { b'0' } else { param_digit(&line[range.clone()], options) };
+            mask[range.clone()].fill(digit);
+            params.push(Param {
+                name: name.map(str::to_string),
+                value: line[range.clone()].to_string(),
+                range: range.clone(),
+            });
+        }
+        (mask_string(line, mask, options.mask_unit), params)
+    }
+
+    // Taken from the captures rather than the parameters, which may leave out groups containing others
+    fn case_id_param(line: &str, re: &PatternRegex, caps: &CaptureRanges) -> Option<Param> {
+        let group = re.capture_names().iter().position(|name| *name == Some(CASE_ID_GROUP))?;
+        let range = caps.get(group)?.clone()?;
+        Some(Param {
+            name: Some(CASE_ID_GROUP.to_string()),
+            value: line[range.clone()].to_string(),
+            range,
+        })
+    }
+
+    fn is_const_group(name: &str) -> bool {
+        name.starts_with(CONST_GROUP_PREFIX)
+    }
+
+    fn param_digit(value: &str, options: &MatchOptions) -> u8 {
+        if options.typed_mask { ParamClass::of(value).digit() } else { b'1' }
+    }
+
+    // Labels the message with the template sharing the most tokens with it, where the tokens
+    // that are not literals of the template are its parameters
+    fn fuzzy_match(compiled: &CompiledRegexes, line: &str, options: &MatchOptions) -> Result<Match, MatchError> {
+        let tokens: HashSet<&str> = line.split_whitespace().collect();
+        let (m, similarity) = compiled.literals.iter()
+            .map(|literals| {
+                let common = literals.iter().filter(|literal| tokens.contains(literal.as_str())).count();
+                let union = literals.len() + tokens.len() - common;
+                if union == 0 { 0.0 } else { common as f64 / union as f64 }
+            })
+            .enumerate()
+            .fold(None, |best: Option<(usize, f64)>, (i, similarity)| match best {
+                Some((_, best_similarity)) if best_similarity >= similarity => best,
+                _ => Some((i, similarity)),
+            })
+            .ok_or(MatchError::NoMatch)?;
+        if options.fuzzy.is_some_and(|threshold| similarity < threshold) {
+            return Err(MatchError::NoMatch);
+        }
+        let mut mask = vec![b'0'; line.len()];
+        let mut params = Vec::new();
+        for (start, token) in line.split_whitespace().map(|token| (token.as_ptr() as usize - line.as_ptr() as usize, token)) {
+            if !compiled.literals[m].contains(token) {
+                mask[start..start + token.len()].fill(param_digit(token, options));
+                params.push(Param {
+                    name: None,
+                    value: token.to_string(),
+                    range: start..start + token.len(),
+                });
+            }
+        }
+        Ok(Match {
+            idx: m,
+            id: compiled.ids[m],
+            template: compiled.templates[m].clone(),
+            mask: mask_string(line, mask, options.mask_unit),
+            params,
+            similarity: Some(similarity),
+            alternatives: Vec::new(),
+            overruled: Vec::new(),
+            confidence: similarity,
+            case_id: None,
+        })
+    }
+
+    // Only the matching regexes of the highest priority are left to the double match policy
+    fn resolve_match(compiled: &CompiledRegexes, indices: &[usize], options: &MatchOptions) -> Result<usize, MatchError> {
+        let highest = indices.iter().map(|&i| compiled.priorities[i]).max();
+        let indices: Vec<usize> = indices.iter().copied().filter(|&i| Some(compiled.priorities[i]) == highest).collect();
+        match indices[..] {
+            [] => Err(MatchError::NoMatch),
+            [m] => Ok(m),
+            _ => options.double_match.resolve(&compiled.regexes, &indices).ok_or(MatchError::AmbiguousMatch { indices }),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // Mask and parameter values of `line` under the given nested group semantics
+        fn masked(pattern: &str, line: &str, nested_groups: NestedGroups) -> (String, Vec<String>) {
+            let re = PatternRegex::Standard(Regex::new(pattern).unwrap());
+            let caps = re.captures(line).expect("Pattern does not match");
+            let options = MatchOptions { nested_groups, ..MatchOptions::default() };
+            let (mask, params) = capture_mask(line, &re, caps, &options);
+            (mask, params.into_iter().map(|param| param.value).collect())
+        }
+
+        #[test]
+        fn const_group_inside_variable_group() {
+            let pattern = r"^took (\d+ (?P<const_unit>ms))$";
+            assert_eq!(masked(pattern, "took 12 ms", NestedGroups::Innermost), ("0000011100".to_string(), vec!["12 ms".to_string(), "ms".to_string()]));
+            assert_eq!(masked(pattern, "took 12 ms", NestedGroups::Leaves), ("0000000000".to_string(), vec!["ms".to_string()]));
+        }
+
+        #[test]
+        fn variable_group_inside_const_group() {
+            let pattern = r"^took (?P<const_duration>(\d+) ms)$";
+            assert_eq!(masked(pattern, "took 12 ms", NestedGroups::Innermost), ("0000011000".to_string(), vec!["12 ms".to_string(), "12".to_string()]));
+            assert_eq!(masked(pattern, "took 12 ms", NestedGroups::Leaves), ("0000011000".to_string(), vec!["12".to_string()]));
+        }
+
+        #[test]
+        fn text_between_inner_groups() {
+            let pattern = r"^((\d+)ms/(\d+)ms)$";
+            assert_eq!(masked(pattern, "5ms/6ms", NestedGroups::Innermost).0, "1111111");
+            assert_eq!(masked(pattern, "5ms/6ms", NestedGroups::Leaves), ("1000100".to_string(), vec!["5".to_string(), "6".to_string()]));
+        }
+
+        #[test]
+        fn alternation_with_branch_not_taking_part() {
+            let pattern = r"^(?:a=(\d+)|b=(\w+))$";
+            for nested_groups in [NestedGroups::Innermost, NestedGroups::Leaves] {
+                assert_eq!(masked(pattern, "b=xy", nested_groups), ("0011".to_string(), vec!["xy".to_string()]));
+            }
+        }
+
+        #[test]
+        fn empty_inner_group() {
+            let pattern = r"^id (\w+?(\d*))$";
+            for nested_groups in [NestedGroups::Innermost, NestedGroups::Leaves] {
+                assert_eq!(masked(pattern, "id ab", nested_groups), ("00011".to_string(), vec!["ab".to_string(), String::new()]));
+            }
+        }
+
+        #[test]
+        fn adaptive_order_keeps_file_order_first() {
+            let templates = [r"^Took (\d+) ms$", r"^Took (.+)$"].into_iter()
+                .map(|pattern| TemplateRegex { regex: PatternRegex::Standard(Regex::new(pattern).unwrap()), priority: 0, id: 0 })
+                .collect();
+            let options = MatchOptions { adaptive_order: true, double_match: DoubleMatchPolicy::First, ..MatchOptions::default() };
+            let compiled = CompiledRegexes::new(templates, &options);
+            let mut order = AdaptiveOrder::new(2);
+            for _ in 0..ADAPTIVE_ORDER_INTERVAL {
+                order.record(1);
+            }
+            assert_eq!(order.arrange(vec![0, 1]), vec![1, 0]);
+            assert_eq!(match_regex(&compiled, "Took 12 ms", &options, Some(&order)).unwrap().idx, 0);
+            assert_eq!(match_regex(&compiled, "Took long", &options, Some(&order)).unwrap().idx, 1);
+        }
+
+        #[test]
+        fn hash_pseudonyms_depend_on_key_only() {
+            let key = *b"0123456789abcdef";
+            let pseudonym = |key: [u8; 16]| Anonymizer::new(AnonymizeMode::Hash, Vec::new(), key).pseudonym("10.0.0.1");
+            assert_eq!(pseudonym(key), pseudonym(key));
+            assert_eq!(pseudonym(key), "6a8ce86c63e4eda0");
+            assert_ne!(pseudonym(key), pseudonym(*b"fedcba9876543210"));
+            let mut anonymizer = Anonymizer::new(AnonymizeMode::Hash, Vec::new(), key);
+            assert_ne!(anonymizer.pseudonym("10.0.0.1"), anonymizer.pseudonym("10.0.0.2"));
+        }
+
+        #[test]
+        fn case_id_of_group_containing_others() {
+            let re = PatternRegex::Standard(Regex::new(r"^Deleting (?P<case_id>(?P<ip>\S+)) now$").unwrap());
+            let line = "Deleting 10.0.0.1 now";
+            let caps = re.captures(line).unwrap();
+            assert_eq!(masked(re.as_str(), line, NestedGroups::Leaves).1, vec!["10.0.0.1".to_string()]);
+            assert_eq!(case_id_param(line, &re, &caps).map(|param| (param.value, param.range)), Some(("10.0.0.1".to_string(), 9..17)));
+        }
+    }
+}
+
+pub mod loading {
+    use std::borrow::Borrow;
+    use std::ffi::OsStr;
+    use std::fmt;
+    use std::fs::File;
+    use std::io;
+    use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+    use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+    use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+    use std::iter::Peekable;
+    use std::path::{Path, PathBuf};
+    use std::process::{Child, Command, Stdio};
+    use std::str::FromStr;
+    use std::sync::{mpsc, Arc, Mutex, OnceLock};
+    use std::sync::mpsc::{Receiver, SyncSender};
+    use std::thread::JoinHandle;
+    use std::time::{Duration, Instant};
+    use bzip2::bufread::MultiBzDecoder;
+    use chrono::{DateTime, NaiveDateTime, Utc};
+    use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+    use encoding_rs_io::DecodeReaderBytesBuilder;
+    use flate2::bufread::{MultiGzDecoder, ZlibDecoder};
+    use glob::Pattern;
+    use itertools::Itertools;
+    use log::{error, warn};
+    use regex::{Regex, RegexBuilder};
+    use walkdir::{DirEntry, WalkDir};
+    use xz2::bufread::XzDecoder;
+
+    const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+    const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+    const XZ_MAGIC: [u8; 6] = [0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+    const BZIP2_MAGIC: [u8; 3] = [0x42, 0x5a, 0x68];
+    const UTF16LE_BOM: [u8; 2] = [0xff, 0xfe];
+    const UTF16BE_BOM: [u8; 2] = [0xfe, 0xff];
+    // `journalctl -o export` starts every entry with its cursor
+    const JOURNAL_EXPORT_MAGIC: &[u8] = b"__CURSOR=";
+    const UTF16_SNIFF_LENGTH: usize = 64;
+    // The index of a template is written as a `u32`
+    pub const MAX_REGEXES: usize = u32::MAX as usize;
+    const COMPRESSED_EXTENSIONS: [&str; 4] = ["gz", "zst", "xz", "bz2"];
+    const TAR_SUFFIXES: [&str; 9] = [".tar", ".tar.gz", ".tgz", ".tar.zst", ".tzst", ".tar.xz", ".txz", ".tar.bz2", ".tbz2"];
+    const READER_CHANNEL_BOUND: usize = 1024;
+    const SYSLOG_DATAGRAM_SIZE: usize = 65536;
+    // Chunked GELF messages are dropped when not complete within this time, as in Graylog
+    const GELF_CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+    const GELF_MAX_CHUNKS: u8 = 128;
+    const LOGHUB_WILDCARD: &str = "<*>";
+    const FOLLOW_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+    #[cfg(feature = "s3")]
+    const S3_PRESIGN_EXPIRY: u32 = 3600;
+    const LOG4J_TIMESTAMP: &str = "%Y-%m-%d %H:%M:%S,%3f";
+    const BSD_SYSLOG_TIMESTAMP: &str = "%b %d %H:%M:%S";
+    const ACCESS_LOG_TIMESTAMP: &str = "%d/%b/%Y:%H:%M:%S %z";
+    const EVTX_LEVELS: [&str; 6] = ["", "critical", "error", "warning", "information", "verbose"];
+    const SYSLOG_SEVERITIES: [&str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+    const ISO8601: &str = "%Y-%m-%dT%H:%M:%S%.f";
+    const UTC_ISO8601: &str = "%Y-%m-%dT%H:%M:%S%.fZ";
+    const YEARLESS_ISO8601: &str = "--%m-%dT%H:%M:%S%.f";
+
+    // Names accepted by `configured_extractor`, in the order `detect_format` prefers them on a tie
+    pub const FORMATS: [&str; 24] = ["healthapp", "zookeeper", "hadoop", "openstack", "hdfs", "android", "postgres", "mysql", "apache", "access", "ssh", "linux", "proxifier", "hpc", "logfmt", "syslog", "journal", "evtx", "cef", "leef", "docker", "cri", "gelf", "squid"];
+
+    // Golden samples of `check_extractors`. The lines of a format are extracted in order by the
+    // same extractor, so a CSV header comes before its records.
+    pub const EXTRACTOR_SAMPLES: &[ExtractorSample] = &[
+        ExtractorSample { format: "healthapp", line: "20171223-22:15:29:606|Step_LSC|30002312|onStandStepChanged 3579", message: Some("onStandStepChanged 3579"), timestamp: Some("2017-12-23T22:15:29.606"), level: None, component: Some("Step_LSC") },
+        ExtractorSample { format: "zookeeper", line: "2015-07-29 17:41:44,747 - INFO  [QuorumPeer[myid=1]/0:0:0:0:0:0:0:0:2181:FastLeaderElection@774] - Notification time out: 3200", message: Some("Notification time out: 3200"), timestamp: Some("2015-07-29T17:41:44.747"), level: Some("INFO"), component: Some("FastLeaderElection") },
+        ExtractorSample { format: "hadoop", line: "2015-10-18 18:01:47,978 INFO [main] org.apache.hadoop.mapreduce.v2.app.MRAppMaster: Created MRAppMaster for application appattempt_1445144423722_0020_000001", message: Some("Created MRAppMaster for application appattempt_1445144423722_0020_000001"), timestamp: Some("2015-10-18T18:01:47.978"), level: Some("INFO"), component: Some("org.apache.hadoop.mapreduce.v2.app.MRAppMaster") },
+        ExtractorSample { format: "openstack", line: "nova-api.log.1.2017-05-16_13:53:08 2017-05-16 00:00:00.008 25746 INFO nova.osapi_compute.wsgi.server [req-38101a0b-2096-447d-96ea-a692162415ae 113d3a99c3da401fbd62cc2caa5b96d2 54fadb412c4e40cdbaed9335e4c35a9e - - -] 10.11.10.1 \"GET /v2/54fadb412c4e40cdbaed9335e4c35a9e/servers/detail HTTP/1.1\" status: 200 len: 1893 time: 0.2477829", message: Some("10.11.10.1 \"GET /v2/54fadb412c4e40cdbaed9335e4c35a9e/servers/detail HTTP/1.1\" status: 200 len: 1893 time: 0.2477829"), timestamp: Some("2017-05-16T00:00:00.008"), level: Some("INFO"), component: Some("nova.osapi_compute.wsgi.server") },
+        ExtractorSample { format: "hdfs", line: "081109 203615 148 INFO dfs.DataNode$PacketResponder: PacketResponder 1 for block blk_38865049064139660 terminating", message: Some("PacketResponder 1 for block blk_38865049064139660 terminating"), timestamp: Some("2008-11-09T20:36:15"), level: Some("INFO"), component: Some("dfs.DataNode$PacketResponder") },
+        ExtractorSample { format: "android", line: "03-17 16:13:38.811  1702  2395 D WindowManager: printFreezingDisplayLogsopening app wtoken = AppWindowToken{9f4ef63 token=Token{a64f992}}", message: Some("printFreezingDisplayLogsopening app wtoken = AppWindowToken{9f4ef63 token=Token{a64f992}}"), timestamp: Some("--03-17T16:13:38.811"), level: Some("D"), component: Some("WindowManager") },
+        ExtractorSample { format: "android", line: "03-17 16:13:38.811 D/WindowManager( 1702): Changing focus", message: Some("Changing focus"), timestamp: Some("--03-17T16:13:38.811"), level: Some("D"), component: Some("WindowManager") },
+        ExtractorSample { format: "android", line: "--------- beginning of main", message: None, timestamp: None, level: None, component: None },
+        ExtractorSample { format: "apache", line: "[Sun Dec 04 04:47:44 2005] [notice] workerEnv.init() ok /etc/httpd/conf/workers2.properties", message: Some("workerEnv.init() ok /etc/httpd/conf/workers2.properties"), timestamp: Some("2005-12-04T04:47:44"), level: Some("notice"), component: None },
+        ExtractorSample { format: "access", line: r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326"#, message: Some("GET /apache_pb.gif HTTP/1.0 200"), timestamp: Some("2000-10-10T20:55:36Z"), level: None, component: None },
+        ExtractorSample { format: "ssh", line: "Dec 10 06:55:46 LabSZ sshd[24200]: reverse mapping checking getaddrinfo for ns.marryaldkfaczcz.com [173.234.31.186] failed - POSSIBLE BREAK-IN ATTEMPT!", message: Some("reverse mapping checking getaddrinfo for ns.marryaldkfaczcz.com [173.234.31.186] failed - POSSIBLE BREAK-IN ATTEMPT!"), timestamp: Some("--12-10T06:55:46"), level: None, component: Some("sshd") },
+        ExtractorSample { format: "linux", line: "Jun  9 06:06:20 combo syslogd 1.4.1: restart.", message: Some("restart."), timestamp: Some("--06-09T06:06:20"), level: None, component: None },
+        ExtractorSample { format: "proxifier", line: "[10.30 16:49:06] chrome.exe - proxy.cse.cuhk.edu.hk:5070 open through proxy proxy.cse.cuhk.edu.hk:5070 HTTPS", message: Some("chrome.exe - proxy.cse.cuhk.edu.hk:5070 open through proxy proxy.cse.cuhk.edu.hk:5070 HTTPS"), timestamp: Some("--10-30T16:49:06"), level: None, component: Some("chrome.exe") },
+        ExtractorSample { format: "hpc", line: "134681 node-246 unix.hw state_change.unavailable 1077804742 1 Component State Change: Component \\042alt0\\042 is in the unavailable state (HWID=1463)", message: Some("Component State Change: Component \\042alt0\\042 is in the unavailable state (HWID=1463)"), timestamp: Some("2004-02-26T14:12:22Z"), level: None, component: Some("unix.hw") },
+        ExtractorSample { format: "logfmt", line: r#"ts=2023-01-01T00:00:00Z level=info logger=api msg="request served" status=200"#, message: Some("request served"), timestamp: Some("2023-01-01T00:00:00Z"), level: Some("info"), component: Some("api") },
+        ExtractorSample { format: "syslog", line: "<34>1 2003-10-11T22:14:15.003Z mymachine.example.com su - ID47 - 'su root' failed for lonvick on /dev/pts/8", message: Some("'su root' failed for lonvick on /dev/pts/8"), timestamp: Some("2003-10-11T22:14:15.003Z"), level: Some("crit"), component: Some("su") },
+        ExtractorSample { format: "syslog", line: "<13>Feb  5 17:32:18 mymachine myproc[10]: Use the BFG!", message: Some("Use the BFG!"), timestamp: Some("--02-05T17:32:18"), level: Some("notice"), component: Some("myproc") },
+        ExtractorSample { format: "journal", line: r#"{"__REALTIME_TIMESTAMP":"1705053601123456","PRIORITY":"6","SYSLOG_IDENTIFIER":"sshd","MESSAGE":"Accepted publickey for root"}"#, message: Some("Accepted publickey for root"), timestamp: Some("2024-01-12T10:00:01.123456Z"), level: Some("info"), component: Some("sshd") },
+        ExtractorSample { format: "evtx", line: r##"{"Event":{"System":{"Provider":{"#attributes":{"Name":"Service Control Manager"}},"EventID":7036,"Level":4,"TimeCreated":{"#attributes":{"SystemTime":"2016-07-08T18:12:51.681640Z"}},"Channel":"System","Computer":"WIN"},"EventData":{"param1":"Windows Update","param2":"running"}}}"##, message: Some("param1=\"Windows Update\" param2=running"), timestamp: Some("2016-07-08T18:12:51.681640Z"), level: Some("information"), component: Some("Service Control Manager") },
+        ExtractorSample { format: "cef", line: "CEF:0|Security|threatmanager|1.0|100|worm successfully stopped|10|src=10.0.0.1 dst=2.1.2.2 spt=1232", message: Some("worm successfully stopped"), timestamp: None, level: Some("10"), component: Some("threatmanager") },
+        ExtractorSample { format: "leef", line: "LEEF:2.0|Lancope|StealthWatch|1.0|41|^|src=10.0.1.8^sev=5^msg=connection refused", message: Some("connection refused"), timestamp: None, level: Some("5"), component: Some("StealthWatch") },
+        ExtractorSample { format: "docker", line: r#"{"log":"Listening on port 8080\n","stream":"stdout","time":"2019-01-01T11:11:11.111111111Z"}"#, message: Some("Listening on port 8080"), timestamp: Some("2019-01-01T11:11:11.111111111Z"), level: None, component: None },
+        ExtractorSample { format: "cri", line: "2016-10-06T00:17:09.669794202Z stderr F Listening on port 8080", message: Some("Listening on port 8080"), timestamp: Some("2016-10-06T00:17:09.669794202Z"), level: None, component: None },
+        ExtractorSample { format: "gelf", line: r#"{"version":"1.1","host":"web1","short_message":"user alice logged in","timestamp":1385053862.3072,"level":6,"facility":"auth"}"#, message: Some("user alice logged in"), timestamp: Some("2013-11-21T17:11:02.307200Z"), level: Some("info"), component: Some("auth") },
+        ExtractorSample { format: "squid", line: "1286536309.450    156 192.168.0.10 TCP_MISS/200 1234 GET http://www.example.com/ - DIRECT/93.184.216.34 text/html", message: Some("GET http://www.example.com/ TCP_MISS/200"), timestamp: Some("2010-10-08T11:11:49.450Z"), level: None, component: None },
+        ExtractorSample { format: "postgres", line: "2024-01-12 10:00:01.123 UTC [1234] app@shop LOG:  statement: SELECT *\n\tFROM orders", message: Some("statement: SELECT *\n\tFROM orders"), timestamp: Some("2024-01-12T10:00:01.123"), level: Some("LOG"), component: None },
+        ExtractorSample { format: "postgres", line: "2012-08-06 14:00:00 UTC [1234]: [1-1] FATAL:  password authentication failed for user \"bob\"", message: Some("password authentication failed for user \"bob\""), timestamp: Some("2012-08-06T14:00:00"), level: Some("FATAL"), component: None },
+        ExtractorSample { format: "mysql", line: "2024-01-12T10:00:01.123456Z 0 [System] [MY-010116] [Server] /usr/sbin/mysqld (mysqld 8.0.35) starting as process 1", message: Some("/usr/sbin/mysqld (mysqld 8.0.35) starting as process 1"), timestamp: Some("2024-01-12T10:00:01.123456Z"), level: Some("System"), component: Some("Server") },
+        ExtractorSample { format: "mysql", line: "120806  9:00:00 [Note] Plugin 'FEDERATED' is disabled.", message: Some("Plugin 'FEDERATED' is disabled."), timestamp: Some("2012-08-06T09:00:00"), level: Some("Note"), component: None },
+        ExtractorSample { format: "jsonl", line: r#"{"time":"2023-01-01T00:00:00Z","message":"cache warmed","level":"info"}"#, message: Some("cache warmed"), timestamp: None, level: None, component: None },
+        ExtractorSample { format: "csv", line: "timestamp,message", message: None, timestamp: None, level: None, component: None },
+        ExtractorSample { format: "csv", line: "2023-01-01 00:00:00,cache warmed", message: Some("cache warmed"), timestamp: None, level: None, component: None },
+        ExtractorSample { format: "tsv", line: "message\tlevel", message: None, timestamp: None, level: None, component: None },
+        ExtractorSample { format: "tsv", line: "cache warmed\tinfo", message: Some("cache warmed"), timestamp: None, level: None, component: None },
+    ];
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum Utf8Policy {
+        // Drop the line and log a warning
+        #[default]
+        Skip,
+        // Replace invalid sequences with U+FFFD
+        Lossy,
+        // Abort the run
+        Fail,
+    }
+
+    // Rules for joining continuation lines (e.g. stack traces) to the line that precedes them
+    #[derive(Clone, Debug)]
+    pub enum Multiline {
+        // Lines starting with whitespace continue the previous record
+        Indentation,
+        // Lines not matching the regex continue the previous record
+        Header(Regex),
+    }
+
+    #[derive(Debug)]
+    pub enum RegexLoadError {
+        Io { file: String, error: io::Error },
+        IncludeCycle { file: String },
+        Csv { file: String, error: csv::Error },
+        MissingColumn { file: String, column: String },
+        Compile(Vec<RegexCompileError>),
+        // More regexes than template indices
+        TooMany { file: String, count: usize },
+    }
+
+    #[derive(Debug)]
+    pub struct RegexCompileError {
+        pub file: String,
+        // 1-based line number of the pattern
+        pub line: usize,
+        pub pattern: String,
+        pub error: Box<dyn std::error::Error + Send + Sync>,
+    }
+
+    // Network endpoint receiving syslog messages, given as `udp://host:port` or `tcp://host:port`,
+    // or GELF messages on `gelf://host:port` (UDP)
+    #[derive(Clone, Debug)]
+    pub enum Listener {
+        Udp(String),
+        Tcp(String),
+        Gelf(String),
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct CrawlOptions {
+        pub extensions: Vec<String>,
+        pub include: Vec<Pattern>,
+        pub exclude: Vec<Pattern>,
+        pub utf8_policy: Utf8Policy,
+        pub multiline: Option<Multiline>,
+        // Join the records a container runtime split a long line into, per source and stream
+        pub partial_records: bool,
+        // Visit the entries of each directory sorted by file name, making the output reproducible
+        pub sorted: bool,
+        // Maximum depth of the crawled files below the dataset directory, 1 for its direct children
+        pub max_depth: Option<usize>,
+        pub follow_symlinks: bool,
+        // Positions of plain files to seek to instead of reading them from the start
+        pub resume: Arc<HashMap<String, FilePosition>>,
+    }
+
+    // How the patterns of regex and template files are compiled
+    #[derive(Clone, Debug)]
+    pub struct RegexOptions {
+        // Wrap every pattern in `^...$`, so it has to match the whole message. Without it,
+        // patterns match anywhere unless they contain anchors themselves.
+        pub anchored: bool,
+        // Ignore case in every pattern. Single patterns can use the inline `(?i)` or `(?-i)`
+        // flags instead.
+        pub case_insensitive: bool,
+        pub engine: RegexEngine,
+        // Approximate limits in bytes of a compiled pattern and of its lazy DFA cache, `None`
+        // keeping the defaults of the `regex` crate. Large generated patterns may exceed the
+        // size limit, while a lower DFA limit bounds the memory of every worker.
+        pub size_limit: Option<usize>,
+        pub dfa_size_limit: Option<usize>,
+        // Unicode-aware classes like `\w` and `\d`; without it they only match ASCII, which
+        // makes patterns with many such classes much smaller. Ignored by `fancy-regex`.
+        pub unicode: bool,
+        // `^` and `$` match at line breaks as well, for messages of multiline entries
+        pub multi_line: bool,
+    }
+
+    // A compiled pattern of a regex or template file
+    #[derive(Clone, Debug)]
+    pub struct TemplateRegex {
+        pub regex: PatternRegex,
+        // Given by a `10: ` prefix in the regex file, 0 otherwise. Of several templates matching a
+        // message, the one with the highest priority wins.
+        pub priority: i32,
+        // Hash of the pattern as written, which stays the same when the templates are reordered
+        pub id: u64,
+    }
+
+    // A pattern compiled by the `regex` crate, or by `fancy-regex` for lookaround and
+    // backreferences. Both report the same byte offsets, so masks do not depend on the engine.
+    #[derive(Clone, Debug)]
+    pub enum PatternRegex {
+        Standard(Regex),
+        #[cfg(feature = "fancy")]
+        Fancy(fancy_regex::Regex),
+    }
+
+    // Byte ranges of the groups of a match, the whole match first
+    pub type CaptureRanges = Vec<Option<std::ops::Range<usize>>>;
+
+    // Engine compiling the patterns of regex and template files
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum RegexEngine {
+        // The `regex` crate, which matches in linear time
+        #[default]
+        Standard,
+        // `fancy-regex` for every pattern, which backtracks for lookaround and backreferences
+        #[cfg(feature = "fancy")]
+        Fancy,
+        // `fancy-regex` only for the patterns the `regex` crate does not support
+        #[cfg(feature = "fancy")]
+        Auto,
+    }
+
+    // Message of a log line along with the header fields the extractor was able to find
+    #[derive(Clone, Debug)]
+    pub struct Extracted {
+        pub message: String,
+        pub timestamp: Option<Timestamp>,
+        pub level: Option<String>,
+        pub component: Option<String>,
+        // Further key-value pairs of structured formats, like the keys of a logfmt line
+        pub fields: Vec<(String, String)>,
+    }
+
+    // A sample line with its expected extraction; lines to be rejected have no message
+    #[derive(Debug)]
+    pub struct ExtractorSample {
+        pub format: &'static str,
+        pub line: &'static str,
+        pub message: Option<&'static str>,
+        // In the form of `Timestamp::to_iso8601`
+        pub timestamp: Option<&'static str>,
+        pub level: Option<&'static str>,
+        pub component: Option<&'static str>,
+    }
+
+    #[derive(Debug)]
+    pub struct ExtractorFailure {
+        pub format: String,
+        pub line: Option<String>,
+        pub problem: String,
+    }
+
+    // Settings of the extractors that are configured by the user rather than fixed by the format
+    #[derive(Clone, Debug, Default)]
+    pub struct ExtractorOptions {
+        pub json: JsonFields,
+        pub csv: CsvColumns,
+        // Output format of `logcat -v` for the `android` extractor, detected per line when not given
+        pub logcat: Option<LogcatFormat>,
+    }
+
+    // Cleanup of the raw lines before the message is extracted, applied in the given order
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Preprocess {
+        // Color and cursor escape sequences, e.g. of CI logs
+        StripAnsi,
+        // Runs of spaces and tabs become a single space
+        CollapseWhitespace,
+        // Keep at most this many characters
+        Truncate(usize),
+        TrimBom,
+    }
+
+    // The `long` format spreads an entry over its header, the message lines and a blank line;
+    // see `LOGCAT_LONG_HEADER` for joining them
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum LogcatFormat {
+        Threadtime,
+        Time,
+        Brief,
+        Tag,
+        Process,
+        Long,
+    }
+
+    // Field paths such as `.message` or `.log.text` of the `jsonl` extractor; array elements are
+    // selected by their index, e.g. `.lines.0`
+    #[derive(Clone, Debug)]
+    pub struct JsonFields {
+        pub message: String,
+        // RFC 3339 timestamps
+        pub timestamp: Option<String>,
+        pub level: Option<String>,
+        pub component: Option<String>,
+    }
+
+    // Columns of the `csv` and `tsv` extractors. Every record has to be on a single line. When a
+    // column is given by name, the first line is taken as the header and later lines repeating it
+    // (the headers of further files) are skipped.
+    #[derive(Clone, Debug)]
+    pub struct CsvColumns {
+        pub message: CsvColumn,
+        // RFC 3339 or `YYYY-MM-DD hh:mm:ss` timestamps
+        pub timestamp: Option<CsvColumn>,
+        pub level: Option<CsvColumn>,
+        pub component: Option<CsvColumn>,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum CsvColumn {
+        // 1-based, like the fields of `cut`
+        Position(usize),
+        Name(String),
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    pub enum TimestampFormat {
+        // chrono format of a timestamp with a full date, assumed to be in UTC
+        Naive(&'static str),
+        // chrono format of a timestamp without a year, like the BSD syslog one
+        Yearless(&'static str),
+        // chrono format of a timestamp with a UTC offset
+        Offset(&'static str),
+        Rfc3339,
+        EpochSeconds,
+        EpochMicros,
+    }
+
+    // Timestamp as it appears in the log; it is only normalized when it is written out
+    #[derive(Clone, Debug)]
+    pub struct Timestamp {
+        pub text: String,
+        pub format: TimestampFormat,
+    }
+
+    // A raw line (or stitched multi-line record) together with where it was read from
+    #[derive(Clone, Debug)]
+    pub struct LogLine {
+        pub source: Arc<str>,
+        pub line_number: usize,
+        pub text: String,
+        // Byte offset after the line in an uncompressed file, where reading can be resumed by
+        // seeking; `None` for compressed files, archives and streams
+        pub offset: Option<u64>,
+    }
+
+    // Where reading a plain file resumes: the number of lines already handled and the byte
+    // offset after them
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct FilePosition {
+        pub line_number: usize,
+        pub offset: u64,
+    }
+
+    struct DecodedLines<R> {
+        reader: R,
+        source: Arc<str>,
+        utf8_policy: Utf8Policy,
+        line_number: usize,
+        // Only tracked when the reader yields the bytes of the file itself
+        offset: Option<u64>,
+        buf: Vec<u8>,
+    }
+
+    struct StitchedLines<I: Iterator<Item=LogLine>> {
+        lines: Peekable<I>,
+        multiline: Multiline,
+    }
+
+    struct JoinedRecords<I: Iterator<Item=LogLine>> {
+        lines: I,
+        // First part of the partial record of every source and stream, with the text of its parts
+        pending: BTreeMap<(Arc<str>, String), (LogLine, String)>,
+    }
+
+    // Entries of the binary-safe `journalctl -o export` format, turned into the JSON lines of
+    // `journalctl -o json`. Entries are numbered instead of lines.
+    struct JournalExportEntries<R> {
+        reader: R,
+        source: Arc<str>,
+        entry_number: usize,
+    }
+
+    // Records of a Kafka topic, polled as they arrive; the offsets are committed once a poll is buffered
+    #[cfg(feature = "kafka")]
+    struct KafkaLines {
+        consumer: kafka::consumer::Consumer,
+        buffered: VecDeque<LogLine>,
+        utf8_policy: Utf8Policy,
+    }
+
+    // Plain log files of a dataset that are read as they grow, see `follow_loglines`
+    struct FollowedLines {
+        dir: String,
+        options: CrawlOptions,
+        files: Vec<FollowedFile>,
+        // Files that were followed at some point, so renamed (rotated) files are not read twice
+        seen_ids: HashSet<u64>,
+        buffered: VecDeque<LogLine>,
+    }
+
+    struct FollowedFile {
+        path: PathBuf,
+        source: Arc<str>,
+        reader: BufReader<File>,
+        id: Option<u64>,
+        position: u64,
+        line_number: usize,
+        // Bytes of a line whose end has not been written yet
+        partial: Vec<u8>,
+    }
+
+    // Chunks of a GELF message received so far, in the order of their sequence numbers
+    struct GelfChunks {
+        received: Instant,
+        chunks: Vec<Option<Vec<u8>>>,
+    }
+
+    // Lines printed by a child process, which is waited for once its output ends
+    struct ChildLines {
+        lines: Box<dyn Iterator<Item=LogLine>>,
+        child: Option<Child>,
+    }
+
+    // Lines produced by background reader threads
+    struct ThreadedLines {
+        lines: Receiver<LogLine>,
+        handles: Vec<JoinHandle<()>>,
+    }
+
+    impl FromStr for Utf8Policy {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "skip" => Ok(Utf8Policy::Skip),
+                "lossy" => Ok(Utf8Policy::Lossy),
+                "fail" => Ok(Utf8Policy::Fail),
+                _ => Err(format!("Unknown UTF-8 policy '{}', expected skip, lossy or fail", s)),
+            }
+        }
+    }
+
+    impl fmt::Display for ExtractorFailure {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match &self.line {
+                Some(line) => write!(f, "{}: {}\n    {}", self.format, self.problem, line),
+                None => write!(f, "{}: {}", self.format, self.problem),
+            }
+        }
+    }
+
+    impl fmt::Display for RegexLoadError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                RegexLoadError::Io { file, error } => write!(f, "Unable to read {}: {}", file, error),
+                RegexLoadError::IncludeCycle { file } => write!(f, "{} includes itself", file),
+                RegexLoadError::Csv { file, error } => write!(f, "Unable to read {}: {}", file, error),
+                RegexLoadError::MissingColumn { file, column } => write!(f, "{} has no {} column", file, column),
+                RegexLoadError::Compile(errors) => {
+                    write!(f, "{} regexes could not be compiled", errors.len())?;
+                    for error in errors {
+                        write!(f, "\n{}", error)?;
+                    }
+                    Ok(())
+                }
+                RegexLoadError::TooMany { file, count } => {
+                    write!(f, "{} has {} regexes, more than the {} template indices", file, count, MAX_REGEXES)
+                }
+            }
+        }
+    }
+
+    impl fmt::Display for RegexCompileError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}:{}: {}\n{}", self.file, self.line, self.pattern, self.error)
+        }
+    }
+
+    impl std::error::Error for RegexLoadError {}
+
+    impl std::error::Error for RegexCompileError {}
+
+    impl FromStr for RegexEngine {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "standard" => Ok(RegexEngine::Standard),
+                #[cfg(feature = "fancy")]
+                "fancy" => Ok(RegexEngine::Fancy),
+                #[cfg(feature = "fancy")]
+                "auto" => Ok(RegexEngine::Auto),
+                #[cfg(not(feature = "fancy"))]
+                "fancy" | "auto" => Err(format!("The {} regex engine needs the fancy feature", s)),
+                _ => Err(format!("Unknown regex engine '{}', expected standard, fancy or auto", s)),
+            }
+        }
+    }
+
+    impl PatternRegex {
+        pub fn as_str(&self) -> &str {
+            match self {
+                PatternRegex::Standard(re) => re.as_str(),
+                #[cfg(feature = "fancy")]
+                PatternRegex::Fancy(re) => re.as_str(),
+            }
+        }
+
+        pub fn is_standard(&self) -> bool {
+            matches!(self, PatternRegex::Standard(_))
+        }
+
+        // Byte ranges of all groups, the whole match first, or None if the pattern does not
+        // match. A backtracking pattern exceeding its limit counts as not matching.
+        pub fn captures(&self, text: &str) -> Option<CaptureRanges> {
+            match self {
+                PatternRegex::Standard(re) => re.captures(text).map(|caps| caps.iter().map(|mat| mat.map(|mat| mat.range())).collect()),
+                #[cfg(feature = "fancy")]
+                PatternRegex::Fancy(re) => match re.captures(text) {
+                    Ok(caps) => caps.map(|caps| caps.iter().map(|mat| mat.map(|mat| mat.start()..mat.end())).collect()),
+                    Err(err) => {
+                        warn!("Giving up matching '{}' against {}: {}", text, re.as_str(), err);
+                        None
+                    }
+                },
+            }
+        }
+
+        // Whether the pattern matches, which is cheaper than finding its captures
+        pub fn is_match(&self, text: &str) -> bool {
+            match self {
+                PatternRegex::Standard(re) => re.is_match(text),
+                #[cfg(feature = "fancy")]
+                PatternRegex::Fancy(re) => re.is_match(text).unwrap_or_else(|err| {
+                    warn!("Giving up matching '{}' against {}: {}", text, re.as_str(), err);
+                    false
+                }),
+            }
+        }
+
+        // Names of all groups, the whole match first
+        pub fn capture_names(&self) -> Vec<Option<&str>> {
+            match self {
+                PatternRegex::Standard(re) => re.capture_names().collect(),
+                #[cfg(feature = "fancy")]
+                PatternRegex::Fancy(re) => re.capture_names().collect(),
+            }
+        }
+    }
+
+    impl Default for JsonFields {
+        fn default() -> Self {
+            JsonFields {
+                message: ".message".to_string(),
+                timestamp: None,
+                level: None,
+                component: None,
+            }
+        }
+    }
+
+    impl Default for CsvColumns {
+        fn default() -> Self {
+            CsvColumns {
+                message: CsvColumn::Name("message".to_string()),
+                timestamp: None,
+                level: None,
+                component: None,
+            }
+        }
+    }
+
+    impl CsvColumns {
+        fn has_names(&self) -> bool {
+            [Some(&self.message), self.timestamp.as_ref(), self.level.as_ref(), self.component.as_ref()]
+                .iter()
+                .any(|column| matches!(column, Some(CsvColumn::Name(_))))
+        }
+    }
+
+    impl FromStr for CsvColumn {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s.parse::<usize>() {
+                Ok(0) => Err("CSV column positions start at 1".to_string()),
+                Ok(position) => Ok(CsvColumn::Position(position)),
+                Err(_) => Ok(CsvColumn::Name(s.to_string())),
+            }
+        }
+    }
+
+    impl FromStr for Preprocess {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "strip-ansi" => Ok(Preprocess::StripAnsi),
+                "collapse-whitespace" => Ok(Preprocess::CollapseWhitespace),
+                "trim-bom" => Ok(Preprocess::TrimBom),
+                _ => match s.strip_prefix("truncate:") {
+                    Some(length) => length.parse()
+                        .map(Preprocess::Truncate)
+                        .map_err(|_| format!("Invalid truncation length '{}'", length)),
+                    None => Err(format!("Unknown preprocessing step '{}', expected strip-ansi, collapse-whitespace, truncate:N or trim-bom", s)),
+                },
+            }
+        }
+    }
+
+    impl Preprocess {
+        pub fn apply(self, mut line: String) -> String {
+            match self {
+                Preprocess::StripAnsi => {
+                    static ANSI_ESCAPE: OnceLock<Regex> = OnceLock::new();
+                    if line.contains('\x1b') {
+                        // CSI sequences (colors, cursor movement), OSC sequences (titles, links) and two-byte escapes
+                        let regex = ANSI_ESCAPE.get_or_init(|| {
+                            Regex::new(r"\x1b(?:\[[0-?]*[ -/]*[@-~]|\][^\x07\x1b]*(?:\x07|\x1b\\)|[@-Z\\-_])").expect("Invalid ANSI escape regex")
+                        });
+                        line = regex.replace_all(&line, "").into_owned();
+                    }
+                    line
+                }
+                Preprocess::CollapseWhitespace => {
+                    let mut collapsed = String::with_capacity(line.len());
+                    for c in line.chars() {
+                        if c == ' ' || c == '\t' {
+                            if !collapsed.ends_with(' ') {
+                                collapsed.push(' ');
+                            }
+                        } else {
+                            collapsed.push(c);
+                        }
+                    }
+                    collapsed
+                }
+                Preprocess::Truncate(length) => {
+                    if let Some((end, _)) = line.char_indices().nth(length) {
+                        line.truncate(end);
+                    }
+                    line
+                }
+                Preprocess::TrimBom => {
+                    match line.strip_prefix('\u{feff}') {
+                        Some(rest) => rest.to_string(),
+                        None => line,
+                    }
+                }
+            }
+        }
+    }
+
+    impl FromStr for LogcatFormat {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "threadtime" => Ok(LogcatFormat::Threadtime),
+                "time" => Ok(LogcatFormat::Time),
+                "brief" => Ok(LogcatFormat::Brief),
+                "tag" => Ok(LogcatFormat::Tag),
+                "process" => Ok(LogcatFormat::Process),
+                "long" => Ok(LogcatFormat::Long),
+                _ => Err(format!("Unknown logcat format '{}', expected threadtime, time, brief, tag, process or long", s)),
+            }
+        }
+    }
+
+    impl LogcatFormat {
+        // Most detailed formats first, so a line is not taken for a shorter format it contains
+        const ALL: [LogcatFormat; 6] = [
+            LogcatFormat::Long,
+            LogcatFormat::Threadtime,
+            LogcatFormat::Time,
+            LogcatFormat::Process,
+            LogcatFormat::Brief,
+            LogcatFormat::Tag,
+        ];
+
+        fn regex(self) -> &'static Regex {
+            static REGEXES: OnceLock<Vec<Regex>> = OnceLock::new();
+            let regexes = REGEXES.get_or_init(|| {
+                [
+                    r"^(?P<ts>\d\d-\d\d \d\d:\d\d:\d\d\.\d{3})\s+(?P<pid>\d+)\s+(?P<tid>\d+)\s+(?P<level>[VDIWEFAS])\s+(?P<tag>[^:]*):(?P<msg>.*)$",
+                    r"^(?P<ts>\d\d-\d\d \d\d:\d\d:\d\d\.\d{3})\s+(?P<level>[VDIWEFAS])/(?P<tag>.*?)\(\s*(?P<pid>\d+)\):(?P<msg>.*)$",
+                    r"^(?P<level>[VDIWEFAS])/(?P<tag>.*?)\(\s*(?P<pid>\d+)\):(?P<msg>.*)$",
+                    r"^(?P<level>[VDIWEFAS])/(?P<tag>[^:]*):(?P<msg>.*)$",
+                    r"^(?P<level>[VDIWEFAS])\(\s*(?P<pid>\d+)\) (?P<msg>.*?)\s+\((?P<tag>[^()]*)\)$",
+                    r"(?s)^\[ (?P<ts>\d\d-\d\d \d\d:\d\d:\d\d\.\d{3})\s+(?P<pid>\d+):\s*(?P<tid>\S+) (?P<level>[VDIWEFAS])/(?P<tag>.*?)\s+\]\n(?P<msg>.*)$",
+                ]
+                .iter()
+                .map(|pattern| Regex::new(pattern).expect("Invalid logcat regex"))
+                .collect()
+            });
+            &regexes[self as usize]
+        }
+    }
+
+    // Metadata of journal entries that is kept as fields
+    const JOURNAL_FIELDS: [&str; 4] = ["_HOSTNAME", "_SYSTEMD_UNIT", "_PID", "_TRANSPORT"];
+
+    // Header line of the `long` logcat format
+    pub const LOGCAT_LONG_HEADER: &str = r"^\[ \d\d-\d\d \d\d:\d\d:\d\d\.\d{3} ";
+
+    impl FromStr for Listener {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            if let Some(addr) = s.strip_prefix("udp://") {
+                Ok(Listener::Udp(addr.to_string()))
+            } else if let Some(addr) = s.strip_prefix("tcp://") {
+                Ok(Listener::Tcp(addr.to_string()))
+            } else if let Some(addr) = s.strip_prefix("gelf://") {
+                Ok(Listener::Gelf(addr.to_string()))
+            } else {
+                Err(format!("Unknown listener '{}', expected udp://host:port, tcp://host:port or gelf://host:port", s))
+            }
+        }
+    }
+
+    impl Utf8Policy {
+        fn decode(self, bytes: &[u8], line_number: usize, source: &str) -> Option<String> {
+            match std::str::from_utf8(bytes) {
+                Ok(text) => Some(text.to_string()),
+                Err(_) => self.recover(bytes, line_number, source),
+            }
+        }
+
+        fn recover(self, bytes: &[u8], line_number: usize, source: &str) -> Option<String> {
+            match self {
+                Utf8Policy::Skip => {
+                    warn!("Skipping line {} of {} since it is not valid UTF-8", line_number, source);
+                    None
+                }
+                Utf8Policy::Lossy => Some(String::from_utf8_lossy(bytes).into_owned()),
+                Utf8Policy::Fail => { panic!("Line {} of {} is not valid UTF-8", line_number, source) }
+            }
+        }
+    }
+
+    impl Default for CrawlOptions {
+        fn default() -> Self {
+            CrawlOptions {
+                extensions: vec!["log".to_string()],
+                include: Vec::new(),
+                exclude: Vec::new(),
+                utf8_policy: Utf8Policy::default(),
+                multiline: None,
+                partial_records: false,
+                sorted: false,
+                max_depth: None,
+                follow_symlinks: false,
+                resume: Arc::new(HashMap::new()),
+            }
+        }
+    }
+
+    impl Default for RegexOptions {
+        fn default() -> Self {
+            RegexOptions {
+                anchored: true,
+                case_insensitive: false,
+                engine: RegexEngine::default(),
+                size_limit: None,
+                dfa_size_limit: None,
+                unicode: true,
+                multi_line: false,
+            }
+        }
+    }
+
+    impl Timestamp {
+        fn new(text: &str, format: TimestampFormat) -> Option<Timestamp> {
+            let text = text.trim();
+            if text.is_empty() {
+                None
+            } else {
+                Some(Timestamp {
+                    text: text.to_string(),
+                    format,
+                })
+            }
+        }
+
+        // Timestamps without a year are rendered in the truncated `--MM-DDThh:mm:ss` form
+        pub fn to_iso8601(&self) -> Option<String> {
+            match self.format {
+                TimestampFormat::Naive(format) => {
+                    Some(NaiveDateTime::parse_from_str(&self.text, format).ok()?.format(ISO8601).to_string())
+                }
+                TimestampFormat::Yearless(format) => {
+                    // The leap year makes February 29 parseable
+                    let text = format!("2000 {}", self.text.split_whitespace().join(" "));
+                    let format = format!("%Y {}", format);
+                    Some(NaiveDateTime::parse_from_str(&text, &format).ok()?.format(YEARLESS_ISO8601).to_string())
+                }
+                TimestampFormat::Offset(format) => {
+                    Some(DateTime::parse_from_str(&self.text, format).ok()?.with_timezone(&Utc).format(UTC_ISO8601).to_string())
+                }
+                TimestampFormat::Rfc3339 => {
+                    Some(DateTime::parse_from_rfc3339(&self.text).ok()?.with_timezone(&Utc).format(UTC_ISO8601).to_string())
+                }
+                TimestampFormat::EpochSeconds => {
+                    Some(DateTime::from_timestamp(self.text.parse().ok()?, 0)?.format(UTC_ISO8601).to_string())
+                }
+                TimestampFormat::EpochMicros => {
+                    Some(DateTime::from_timestamp_micros(self.text.parse().ok()?)?.format(UTC_ISO8601).to_string())
+                }
+            }
+        }
+
+        // Milliseconds since the Unix epoch, None for timestamps without a year
+        pub fn to_epoch_millis(&self) -> Option<i64> {
+            match self.format {
+                TimestampFormat::Naive(format) => {
+                    Some(NaiveDateTime::parse_from_str(&self.text, format).ok()?.and_utc().timestamp_millis())
+                }
+                TimestampFormat::Yearless(_) => None,
+                TimestampFormat::Offset(format) => Some(DateTime::parse_from_str(&self.text, format).ok()?.timestamp_millis()),
+                TimestampFormat::Rfc3339 => Some(DateTime::parse_from_rfc3339(&self.text).ok()?.timestamp_millis()),
+                TimestampFormat::EpochSeconds => self.text.parse::<i64>().ok()?.checked_mul(1000),
+                TimestampFormat::EpochMicros => Some(self.text.parse::<i64>().ok()? / 1000),
+            }
+        }
+    }
+
+    impl LogLine {
+        fn new(source: &Arc<str>, line_number: usize, text: String) -> Self {
+            LogLine {
+                source: source.clone(),
+                line_number,
+                text,
+                offset: None,
+            }
+        }
+
+        fn at(self, offset: Option<u64>) -> Self {
+            LogLine { offset, ..self }
+        }
+    }
+
+    #[cfg(feature = "kafka")]
+    impl Iterator for KafkaLines {
+        type Item = LogLine;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            while self.buffered.is_empty() {
+                let message_sets = self.consumer.poll().expect("Unable to poll the Kafka topic");
+                for message_set in message_sets.iter() {
+                    let source: Arc<str> = format!("kafka://{}/{}", message_set.topic(), message_set.partition()).into();
+                    for message in message_set.messages() {
+                        let line_number = message.offset as usize;
+                        if let Some(text) = self.utf8_policy.decode(message.value, line_number, &source) {
+                            self.buffered.push_back(LogLine::new(&source, line_number, text));
+                        }
+                    }
+                    self.consumer.consume_messageset(message_set).expect("Unable to consume the Kafka messages");
+                }
+                self.consumer.commit_consumed().expect("Unable to commit the Kafka offsets");
+            }
+            self.buffered.pop_front()
+        }
+    }
+
+    impl FollowedLines {
+        // Picks up files created since the last poll, unless they are a followed file that was renamed
+        fn discover(&mut self) {
+            let followed_paths: HashSet<PathBuf> = self.files.iter().map(|file| file.path.clone()).collect();
+            self.seen_ids.extend(self.files.iter().filter_map(|file| file.id));
+            for entry in crawl(self.dir.clone(), self.options.clone()) {
+                let path = entry.into_path();
+                if followed_paths.contains(&path) || archive_kind(&path).is_some() {
+                    continue;
+                }
+                match FollowedFile::open(path) {
+                    Ok(Some(file)) if file.id.is_none_or(|id| !self.seen_ids.contains(&id)) => { self.files.push(file); }
+                    Ok(_) => {}
+                    Err(err) => { warn!("Unable to follow a file: {}", err); }
+                }
+            }
+        }
+    }
+
+    impl FollowedFile {
+        // Compressed files are not followed, since they are not appended to
+        fn open(path: PathBuf) -> io::Result<Option<FollowedFile>> {
+            let file = File::open(&path)?;
+            let id = file_id(&file.metadata()?);
+            let mut reader = BufReader::new(file);
+            if compression(reader.fill_buf()?).is_some() {
+                return Ok(None);
+            }
+            Ok(Some(FollowedFile {
+                source: path.display().to_string().into(),
+                path,
+                reader,
+                id,
+                position: 0,
+                line_number: 0,
+                partial: Vec::new(),
+            }))
+        }
+
+        fn read_available(&mut self, utf8_policy: Utf8Policy, lines: &mut VecDeque<LogLine>) {
+            loop {
+                match self.reader.read_until(b'\n', &mut self.partial) {
+                    Ok(0) => { return; }
+                    Ok(read) => { self.position += read as u64; }
+                    Err(err) => {
+                        warn!("Unable to read {}: {}", self.source, err);
+                        return;
+                    }
+                }
+                if !self.partial.ends_with(b"\n") {
+                    // Wait for the rest of the line
+                    return;
+                }
+                self.partial.pop();
+                if self.partial.ends_with(b"\r") {
+                    self.partial.pop();
+                }
+                self.line_number += 1;
+                if let Some(text) = utf8_policy.decode(&self.partial, self.line_number, &self.source) {
+                    lines.push_back(LogLine::new(&self.source, self.line_number, text));
+                }
+                self.partial.clear();
+            }
+        }
+
+        // A path replaced by a new file is reopened once the old file is read to its end,
+        // a truncated file is read again from its start
+        fn check_rotation(&mut self, utf8_policy: Utf8Policy, lines: &mut VecDeque<LogLine>) -> io::Result<()> {
+            let metadata = match std::fs::metadata(&self.path) {
+                Ok(metadata) => metadata,
+                Err(_) => { return Ok(()); }
+            };
+            if file_id(&metadata) != self.id {
+                self.read_available(utf8_policy, lines);
+                if let Some(file) = FollowedFile::open(self.path.clone())? {
+                    *self = file;
+                }
+            } else if metadata.len() < self.position {
+                self.reader.seek(SeekFrom::Start(0))?;
+                self.position = 0;
+                self.line_number = 0;
+                self.partial.clear();
+            }
+            Ok(())
+        }
+    }
+
+    impl Iterator for FollowedLines {
+        type Item = LogLine;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            while self.buffered.is_empty() {
+                for file in &mut self.files {
+                    file.read_available(self.options.utf8_policy, &mut self.buffered);
+                }
+                if self.buffered.is_empty() {
+                    std::thread::sleep(FOLLOW_POLL_INTERVAL);
+                    for file in &mut self.files {
+                        if let Err(err) = file.check_rotation(self.options.utf8_policy, &mut self.buffered) {
+                            warn!("Unable to reopen {}: {}", file.source, err);
+                        }
+                    }
+                    self.discover();
+                }
+            }
+            self.buffered.pop_front()
+        }
+    }
+
+    impl Iterator for ChildLines {
+        type Item = LogLine;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let line = self.lines.next();
+            if line.is_none() {
+                if let Some(mut child) = self.child.take() {
+                    let status = child.wait().expect("Unable to wait for the child process");
+                    if !status.success() {
+                        panic!("Child process failed with {}", status);
+                    }
+                }
+            }
+            line
+        }
+    }
+
+    impl<R: BufRead> DecodedLines<R> {
+        fn new(reader: R, source: String, utf8_policy: Utf8Policy) -> Self {
+            DecodedLines {
+                reader,
+                source: source.into(),
+                utf8_policy,
+                line_number: 0,
+                offset: None,
+                buf: Vec::new(),
+            }
+        }
+    }
+
+    impl<R: BufRead> Iterator for DecodedLines<R> {
+        type Item = LogLine;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                self.buf.clear();
+                match self.reader.read_until(b'\n', &mut self.buf) {
+                    Ok(0) => { return None; }
+                    Ok(read) => { self.offset = self.offset.map(|offset| offset + read as u64); }
+                    Err(err) => {
+                        error!("Unable to read {}: {}", self.source, err);
+                        return None;
+                    }
+                }
+                self.line_number += 1;
+                if self.buf.ends_with(b"\n") {
+                    self.buf.pop();
+                    if self.buf.ends_with(b"\r") {
+                        self.buf.pop();
+                    }
+                }
+                match String::from_utf8(std::mem::take(&mut self.buf)) {
+                    Ok(text) => { return Some(LogLine::new(&self.source, self.line_number, text).at(self.offset)); }
+                    Err(err) => {
+                        if let Some(text) = self.utf8_policy.recover(err.as_bytes(), self.line_number, &self.source) {
+                            return Some(LogLine::new(&self.source, self.line_number, text).at(self.offset));
+                        }
+                        self.buf = err.into_bytes();
+                    }
+                }
+            }
+        }
+    }
+
+    impl<R: BufRead> Iterator for JournalExportEntries<R> {
+        type Item = LogLine;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let mut entry = serde_json::Map::new();
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                match self.reader.read_until(b'\n', &mut line) {
+                    Ok(0) => { break; }
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!("Unable to read {}: {}", self.source, err);
+                        return None;
+                    }
+                }
+                if line.ends_with(b"\n") {
+                    line.pop();
+                }
+                if line.is_empty() {
+                    if entry.is_empty() {
+                        continue;
+                    }
+                    break;
+                }
+                // Text fields are `NAME=value`, binary ones `NAME\n` followed by the size and the data
+                let (name, value) = match line.iter().position(|&b| b == b'=') {
+                    Some(position) => (&line[..position], line[position + 1..].to_vec()),
+                    None => match read_export_data(&mut self.reader) {
+                        Ok(value) => (&line[..], value),
+                        Err(err) => {
+                            error!("Unable to read {}: {}", self.source, err);
+                            return None;
+                        }
+                    },
+                };
+                let value = match String::from_utf8(value) {
+                    Ok(text) => serde_json::Value::String(text),
+                    Err(err) => serde_json::Value::Array(err.into_bytes().into_iter().map(serde_json::Value::from).collect()),
+                };
+                entry.entry(String::from_utf8_lossy(name)).or_insert(value);
+            }
+            if entry.is_empty() {
+                return None;
+            }
+            self.entry_number += 1;
+            Some(LogLine::new(&self.source, self.entry_number, serde_json::Value::Object(entry).to_string()))
+        }
+    }
+
+    fn read_export_data<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+        let mut size = [0; 8];
+        reader.read_exact(&mut size)?;
+        let mut data = Vec::new();
+        reader.by_ref().take(u64::from_le_bytes(size)).read_to_end(&mut data)?;
+        let mut newline = [0; 1];
+        reader.read_exact(&mut newline)?;
+        Ok(data)
+    }
+
+    impl Multiline {
+        fn is_continuation(&self, line: &str) -> bool {
+            match self {
+                Multiline::Indentation => line.starts_with([' ', '\t']),
+                Multiline::Header(header) => !header.is_match(line),
+            }
+        }
+    }
+
+    // Stitched records keep the line number of their first line
+    impl<I: Iterator<Item=LogLine>> Iterator for StitchedLines<I> {
+        type Item = LogLine;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let mut record = self.lines.next()?;
+            while let Some(line) = self.lines.next_if(|line| self.multiline.is_continuation(&line.text)) {
+                record.text.push('\n');
+                record.text.push_str(&line.text);
+                record.offset = line.offset;
+            }
+            Some(record)
+        }
+    }
+
+    // Joined records keep the line number of their first part. While a record is partial, the
+    // offsets of the lines after it are dropped, since resuming from them would skip its parts.
+    impl<I: Iterator<Item=LogLine>> Iterator for JoinedRecords<I> {
+        type Item = LogLine;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                let Some(mut line) = self.lines.next() else {
+                    // Records still partial at the end of the input are returned as they are
+                    let (_, (mut first, text)) = self.pending.pop_first()?;
+                    first.text = joined_record(&first.text, text);
+                    return Some(first);
+                };
+                let Some((stream, part, complete)) = record_part(&line.text) else {
+                    if !self.pending.is_empty() {
+                        line.offset = None;
+                    }
+                    return Some(line);
+                };
+                let key = (line.source.clone(), stream);
+                match self.pending.remove(&key) {
+                    None if !complete => { self.pending.insert(key, (line, part)); }
+                    None => {
+                        if !self.pending.is_empty() {
+                            line.offset = None;
+                        }
+                        return Some(line);
+                    }
+                    Some((first, mut text)) if !complete => {
+                        text.push_str(&part);
+                        self.pending.insert(key, (first, text));
+                    }
+                    Some((mut first, mut text)) => {
+                        text.push_str(&part);
+                        first.text = joined_record(&first.text, text);
+                        first.offset = if self.pending.is_empty() { line.offset } else { None };
+                        return Some(first);
+                    }
+                }
+            }
+        }
+    }
+
+    impl Iterator for ThreadedLines {
+        type Item = LogLine;
+
+        // Panics of the reader threads (e.g. due to Utf8Policy::Fail) are propagated to the caller
+        fn next(&mut self) -> Option<Self::Item> {
+            match self.lines.recv() {
+                Ok(line) => Some(line),
+                Err(_) => {
+                    for handle in self.handles.drain(..) {
+                        if let Err(panic) = handle.join() {
+                            std::panic::resume_unwind(panic);
+                        }
+                    }
+                    None
+                }
+            }
+        }
+    }
+
+    impl CrawlOptions {
+        fn is_excluded(&self, path: &Path) -> bool {
+            self.exclude.iter().any(|pattern| glob_matches(pattern, path))
+        }
+
+        fn is_log_path(&self, path: &Path) -> bool {
+            !self.is_excluded(path) && (self.has_log_extension(path) || self.include.iter().any(|pattern| glob_matches(pattern, path)))
+        }
+
+        // Compression suffixes are ignored, so `app.log.gz` has the `log` extension
+        fn has_log_extension(&self, path: &Path) -> bool {
+            let path = match path.extension().and_then(OsStr::to_str) {
+                Some(ext) if COMPRESSED_EXTENSIONS.contains(&ext) => Path::new(path.file_stem().unwrap_or_default()),
+                _ => path,
+            };
+            path.extension()
+                .and_then(OsStr::to_str)
+                .is_some_and(|ext| self.extensions.iter().any(|e| e == ext))
+        }
+
+        fn is_crawled(&self, path: &Path) -> bool {
+            self.is_log_path(path) || (archive_kind(path).is_some() && !self.is_excluded(path))
+        }
+    }
+
+    enum Compression {
+        Gzip,
+        Zstd,
+        Xz,
+        Bzip2,
+    }
+
+    enum ArchiveKind {
+        Tar,
+        Zip,
+    }
+
+    // One pattern per line. Blank lines and lines starting with `#` are skipped (a pattern starting
+    // with a literal `#` is written as `\#`), and `@include other.regex` inserts the patterns of
+    // another file, relative to the including one. All patterns are compiled, so every invalid one
+    // is reported at once.
+    pub fn load_regex(file: &str, options: &RegexOptions) -> Result<Vec<TemplateRegex>, RegexLoadError> {
+        let mut v = Vec::new();
+        let mut errors = Vec::new();
+        read_regex_file(Path::new(file), options, &mut Vec::new(), &mut HashMap::new(), &mut v, &mut errors)?;
+        loaded_regexes(file, v, errors)
+    }
+
+    // Reads a LogHub `*_templates.csv` file (with EventId and EventTemplate columns), turning every
+    // `<*>` placeholder of a template into a capture group and escaping the rest of it
+    pub fn load_loghub_templates(file: &str, options: &RegexOptions) -> Result<Vec<TemplateRegex>, RegexLoadError> {
+        let csv_error = |error| RegexLoadError::Csv { file: file.to_string(), error };
+        let mut reader = csv::Reader::from_path(file).map_err(csv_error)?;
+        let template_column = reader.headers().map_err(csv_error)?
+            .iter()
+            .position(|header| header == "EventTemplate")
+            .ok_or_else(|| RegexLoadError::MissingColumn { file: file.to_string(), column: "EventTemplate".to_string() })?;
+        let mut v = Vec::new();
+        let mut errors = Vec::new();
+        for (i, record) in reader.records().enumerate() {
+            let record = record.map_err(csv_error)?;
+            let template = record.get(template_column).unwrap_or_default();
+            let pattern = template.split(LOGHUB_WILDCARD).map(regex::escape).join("(.*?)");
+            match compile_pattern(&pattern, options) {
+                Ok(regex) => v.push(TemplateRegex { regex, priority: 0, id: template_id(template) }),
+                Err(error) => errors.push(RegexCompileError {
+                    file: file.to_string(),
+                    // The header is the first line
+                    line: i + 2,
+                    pattern: template.to_string(),
+                    error,
+                }),
+            }
+        }
+        loaded_regexes(file, v, errors)
+    }
+
+    // Concatenates the `*.regex` files below `dir` in the order of their paths, so the regex indexes
+    // stay stable as long as no fragment is added or removed
+    pub fn load_regex_dir(dir: &str, options: &RegexOptions) -> Result<Vec<TemplateRegex>, RegexLoadError> {
+        let mut v = Vec::new();
+        let mut errors = Vec::new();
+        // Macros of a fragment are defined for the fragments after it as well
+        let mut macros = HashMap::new();
+        for entry in WalkDir::new(dir).sort_by_file_name() {
+            let entry = entry.map_err(|error| RegexLoadError::Io { file: dir.to_string(), error: error.into() })?;
+            if entry.file_type().is_file() && entry.path().extension().is_some_and(|ext| ext == "regex") {
+                read_regex_file(entry.path(), options, &mut Vec::new(), &mut macros, &mut v, &mut errors)?;
+            }
+        }
+        loaded_regexes(dir, v, errors)
+    }
+
+    fn loaded_regexes(file: &str, v: Vec<TemplateRegex>, errors: Vec<RegexCompileError>) -> Result<Vec<TemplateRegex>, RegexLoadError> {
+        if !errors.is_empty() {
+            Err(RegexLoadError::Compile(errors))
+        } else if v.len() > MAX_REGEXES {
+            Err(RegexLoadError::TooMany { file: file.to_string(), count: v.len() })
+        } else {
+            Ok(v)
+        }
+    }
+
+    // Options become part of the pattern, so the regex set of the workers compiles it alike
+    fn compile_pattern(pattern: &str, options: &RegexOptions) -> Result<PatternRegex, Box<dyn std::error::Error + Send + Sync>> {
+        let mut flags = String::new();
+        if options.case_insensitive {
+            flags.push_str("(?i)");
+        }
+        if options.multi_line {
+            flags.push_str("(?m)");
+        }
+        let pattern = if options.anchored {
+            format!("{}^{}$", flags, pattern)
+        } else {
+            format!("{}{}", flags, pattern)
+        };
+        match options.engine {
+            RegexEngine::Standard => Ok(PatternRegex::Standard(build_regex(&pattern, options)?)),
+            #[cfg(feature = "fancy")]
+            RegexEngine::Fancy => Ok(PatternRegex::Fancy(build_fancy_regex(&pattern, options)?)),
+            #[cfg(feature = "fancy")]
+            RegexEngine::Auto => match build_regex(&pattern, options) {
+                Ok(regex) => Ok(PatternRegex::Standard(regex)),
+                // The error of the standard engine tells more about plain syntax errors
+                Err(error) => build_fancy_regex(&pattern, options).map(PatternRegex::Fancy).map_err(|_| error.into()),
+            },
+        }
+    }
+
+    // ASCII classes are an inline flag rather than a builder setting, so the regex set built
+    // from the patterns agrees with the regexes
+    fn build_regex(pattern: &str, options: &RegexOptions) -> Result<Regex, regex::Error> {
+        let pattern = if options.unicode { pattern.to_string() } else { format!("(?-u){}", pattern) };
+        let mut builder = RegexBuilder::new(&pattern);
+        if let Some(limit) = options.size_limit {
+            builder.size_limit(limit);
+        }
+        if let Some(limit) = options.dfa_size_limit {
+            builder.dfa_size_limit(limit);
+        }
+        builder.build()
+    }
+
+    #[cfg(feature = "fancy")]
+    fn build_fancy_regex(pattern: &str, options: &RegexOptions) -> Result<fancy_regex::Regex, fancy_regex::Error> {
+        let mut builder = fancy_regex::RegexBuilder::new(pattern);
+        if let Some(limit) = options.size_limit {
+            builder.delegate_size_limit(limit);
+        }
+        if let Some(limit) = options.dfa_size_limit {
+            builder.delegate_dfa_size_limit(limit);
+        }
+        builder.build()
+    }
+
+    // XXH3 hash of the pattern of a regex file, or of the template of a template file
+    pub fn template_id(pattern: &str) -> u64 {
+        xxhash_rust::xxh3::xxh3_64(pattern.as_bytes())
+    }
+
+    fn is_macro_name(name: &str) -> bool {
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    // Replaces every `{{NAME}}` of the pattern with the definition of the macro as a non-capturing
+    // group, so quantifiers apply to all of it and `({{IP}})` captures it
+    fn expand_macros(pattern: &str, macros: &HashMap<String, String>) -> Result<String, String> {
+        let mut expanded = String::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find("{{") {
+            let Some(length) = rest[start + 2..].find("}}") else { break };
+            let name = &rest[start + 2..start + 2 + length];
+            if !is_macro_name(name) {
+                expanded.push_str(&rest[..start + 2]);
+                rest = &rest[start + 2..];
+                continue;
+            }
+            let definition = macros.get(name).ok_or_else(|| format!("Undefined macro {{{{{}}}}}", name))?;
+            expanded.push_str(&rest[..start]);
+            expanded.push_str("(?:");
+            expanded.push_str(definition);
+            expanded.push(')');
+            rest = &rest[start + length + 4..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    // A pattern may start with its priority, e.g. `10: Took (\d+) ms`. Patterns starting with such
+    // a number literally can wrap it in a group, e.g. `(?:10): `.
+    fn split_priority(pattern: &str) -> (i32, &str) {
+        pattern.split_once(": ")
+            .and_then(|(priority, body)| Some((priority.parse().ok()?, body)))
+            .unwrap_or((0, pattern))
+    }
+
+    // `including` holds the files whose includes are being resolved, to detect cycles, and `macros`
+    // the macros defined so far, including those of included files
+    fn read_regex_file(path: &Path, options: &RegexOptions, including: &mut Vec<PathBuf>, macros: &mut HashMap<String, String>,
+                       v: &mut Vec<TemplateRegex>, errors: &mut Vec<RegexCompileError>) -> Result<(), RegexLoadError> {
+        let file = path.display().to_string();
+        let io_error = |error| RegexLoadError::Io { file: file.clone(), error };
+        let canonical = path.canonicalize().map_err(io_error)?;
+        if including.contains(&canonical) {
+            return Err(RegexLoadError::IncludeCycle { file });
+        }
+        let buf_reader = BufReader::new(File::open(path).map_err(io_error)?);
+        including.push(canonical);
+        for (i, l) in buf_reader.lines().enumerate() {
+            let pattern = l.map_err(io_error)?;
+            if pattern.trim().is_empty() || pattern.trim_start().starts_with('#') {
+                continue;
+            }
+            if let Some(include) = pattern.strip_prefix("@include ") {
+                let include = path.parent().unwrap_or(Path::new("")).join(include.trim());
+                read_regex_file(&include, options, including, macros, v, errors)?;
+                continue;
+            }
+            let compile_error = |pattern, error| RegexCompileError { file: file.clone(), line: i + 1, pattern, error };
+            if let Some(definition) = pattern.strip_prefix("@define ") {
+                let (name, body) = definition.trim_start().split_once(' ').unwrap_or((definition.trim(), ""));
+                if !is_macro_name(name) {
+                    errors.push(compile_error(pattern.clone(), format!("Invalid macro name '{}'", name).into()));
+                    continue;
+                }
+                match expand_macros(body.trim(), macros) {
+                    Ok(expanded) => { macros.insert(name.to_string(), expanded); }
+                    Err(error) => errors.push(compile_error(pattern.clone(), error.into())),
+                }
+                continue;
+            }
+            let (priority, body) = split_priority(&pattern);
+            let compiled = expand_macros(body, macros)
+                .map_err(Into::into)
+                .and_then(|expanded| compile_pattern(&expanded, options));
+            match compiled {
+                Ok(regex) => v.push(TemplateRegex { regex, priority, id: template_id(body) }),
+                Err(error) => errors.push(compile_error(pattern, error)),
+            }
+        }
+        including.pop();
+        Ok(())
+    }
+
+    pub fn load_loglines(dir: String, options: CrawlOptions) -> impl Iterator<Item=LogLine> {
+        crawl(dir, options.clone())
+            .flat_map(move |entry| { read_lines(entry, &options) })
+    }
+
+    // Files are distributed among `reader_count` threads, so lines of different files are interleaved
+    pub fn load_loglines_parallel(dir: String, options: CrawlOptions, reader_count: usize) -> impl Iterator<Item=LogLine> {
+        let files = Arc::new(Mutex::new(crawl(dir, options.clone()).collect::<Vec<_>>().into_iter()));
+        let (tx, rx) = mpsc::sync_channel(READER_CHANNEL_BOUND);
+        let handles = (0..reader_count).map(|idx| {
+            let files = files.clone();
+            let tx = tx.clone();
+            let options = options.clone();
+            std::thread::Builder::new()
+                .name(format!("FileReader {}", idx))
+                .spawn(move || {
+                    loop {
+                        let entry = files.lock().expect("Poisoned file queue").next();
+                        match entry {
+                            Some(entry) => {
+                                if !send_lines(read_lines(entry, &options), &tx) {
+                                    break;
+                                }
+                            }
+                            None => { break; }
+                        }
+                    }
+                })
+                .expect("Unable to spawn a thread")
+        }).collect();
+        ThreadedLines {
+            lines: rx,
+            handles,
+        }
+    }
+
+    fn crawl(dir: String, options: CrawlOptions) -> impl Iterator<Item=DirEntry> {
+        let root = PathBuf::from(&dir);
+        let mut walker = WalkDir::new(dir).follow_links(options.follow_symlinks);
+        if options.sorted {
+            walker = walker.sort_by_file_name();
+        }
+        if let Some(max_depth) = options.max_depth {
+            walker = walker.max_depth(max_depth);
+        }
+        walker.into_iter()
+            .filter_map(|result| { result.ok() })
+            .filter(move |entry| { !entry.file_type().is_dir() && options.is_crawled(relative_path(&root, entry)) })
+    }
+
+    pub fn load_stdin_lines(options: &CrawlOptions) -> impl Iterator<Item=LogLine> {
+        let reader = decompress(io::stdin().lock())
+            .and_then(transcode)
+            .expect("Unable to read from stdin");
+        decoded_lines(reader, "stdin".to_string(), options)
+    }
+
+    // Remote files are streamed, so compressed files work but archives are not supported
+    #[cfg(feature = "http")]
+    pub fn load_url_lines(urls: Vec<String>, options: CrawlOptions) -> impl Iterator<Item=LogLine> {
+        urls.into_iter().flat_map(move |url| { fetch_lines(&url, url.clone(), &options) })
+    }
+
+    // Objects below `s3://bucket/prefix` are filtered like crawled files and streamed through presigned
+    // URLs. The region and credentials are taken from the AWS_* environment variables (or the AWS
+    // profile), AWS_ENDPOINT selects an S3 compatible store.
+    #[cfg(feature = "s3")]
+    pub fn load_s3_lines(url: String, options: CrawlOptions) -> impl Iterator<Item=LogLine> {
+        let (bucket_name, prefix) = url.strip_prefix("s3://")
+            .map(|path| path.split_once('/').unwrap_or((path, "")))
+            .unwrap_or_else(|| panic!("Invalid S3 url {}", url));
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        };
+        let region = s3::Region::from_default_env().expect("AWS_REGION is not provided in the environment variables");
+        let path_style = matches!(region, s3::Region::Custom { .. });
+        let credentials = s3::creds::Credentials::default().expect("Unable to load the AWS credentials");
+        let mut bucket = s3::Bucket::new(bucket_name, region, credentials).expect("Invalid S3 bucket");
+        if path_style {
+            bucket = bucket.with_path_style();
+        }
+        let keys = bucket.list(prefix.clone(), None)
+            .unwrap_or_else(|err| panic!("Unable to list {}: {}", url, err))
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| object.key)
+            .filter(|key| !key.ends_with('/') && options.is_log_path(Path::new(&key[prefix.len()..])))
+            .collect::<Vec<_>>();
+        let bucket_name = bucket_name.to_string();
+        keys.into_iter().flat_map(move |key| {
+            let presigned = bucket.presign_get(format!("/{}", key), S3_PRESIGN_EXPIRY, None)
+                .unwrap_or_else(|err| panic!("Unable to presign {}: {}", key, err));
+            fetch_lines(&presigned, format!("s3://{}/{}", bucket_name, key), &options)
+        })
+    }
+
+    // Consumes the topic until the process is stopped; the record offset is used as the line number
+    #[cfg(feature = "kafka")]
+    pub fn load_kafka_lines(brokers: Vec<String>, topic: String, group: String, options: &CrawlOptions) -> impl Iterator<Item=LogLine> {
+        let consumer = kafka::consumer::Consumer::from_hosts(brokers)
+            .with_topic(topic)
+            .with_group(group)
+            .with_fallback_offset(kafka::consumer::FetchOffset::Earliest)
+            .with_offset_storage(Some(kafka::consumer::GroupOffsetStorage::Kafka))
+            .create()
+            .expect("Unable to connect to the Kafka brokers");
+        KafkaLines {
+            consumer,
+            buffered: VecDeque::new(),
+            utf8_policy: options.utf8_policy,
+        }
+    }
+
+    // Reads the plain log files of `dir` and keeps polling them for appended lines, reopening rotated
+    // files and picking up new ones. Multi-line records are not stitched in this mode.
+    pub fn follow_loglines(dir: String, options: CrawlOptions) -> Box<dyn Iterator<Item=LogLine>> {
+        let partial_records = options.partial_records;
+        let mut lines = FollowedLines {
+            dir,
+            options,
+            files: Vec::new(),
+            seen_ids: HashSet::new(),
+            buffered: VecDeque::new(),
+        };
+        lines.discover();
+        if partial_records {
+            Box::new(JoinedRecords { lines, pending: BTreeMap::new() })
+        } else {
+            Box::new(lines)
+        }
+    }
+
+    // Streams the entries of the local journal as JSON lines, to be parsed by the `journal` extractor.
+    // `matches` are passed to journalctl, e.g. `_SYSTEMD_UNIT=sshd.service`.
+    pub fn load_journal_lines(matches: &[String], options: &CrawlOptions) -> impl Iterator<Item=LogLine> {
+        let mut child = Command::new("journalctl")
+            .args(["--output", "json", "--no-pager"])
+            .args(matches)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Unable to run journalctl");
+        let stdout = child.stdout.take().expect("Unable to read the output of journalctl");
+        ChildLines {
+            lines: decoded_lines(BufReader::new(stdout), "journald".to_string(), options),
+            child: Some(child),
+        }
+    }
+
+    // Receives syslog messages until the process is stopped. Every peer (or TCP connection) is a
+    // separate source, whose messages are numbered in the order they arrive.
+    pub fn listen_syslog(listener: Listener, options: &CrawlOptions) -> impl Iterator<Item=LogLine> {
+        let (tx, rx) = mpsc::sync_channel(READER_CHANNEL_BOUND);
+        let utf8_policy = options.utf8_policy;
+        match listener {
+            Listener::Udp(addr) => {
+                let socket = UdpSocket::bind(&addr).unwrap_or_else(|err| panic!("Unable to bind to udp://{}: {}", addr, err));
+                std::thread::Builder::new()
+                    .name("SyslogListener".to_string())
+                    .spawn(move || { receive_datagrams(socket, utf8_policy, tx) })
+                    .expect("Unable to spawn a thread");
+            }
+            Listener::Tcp(addr) => {
+                let socket = TcpListener::bind(&addr).unwrap_or_else(|err| panic!("Unable to bind to tcp://{}: {}", addr, err));
+                std::thread::Builder::new()
+                    .name("SyslogListener".to_string())
+                    .spawn(move || {
+                        for stream in socket.incoming() {
+                            match stream {
+                                Ok(stream) => {
+                                    let tx = tx.clone();
+                                    std::thread::spawn(move || { receive_stream(stream, utf8_policy, tx) });
+                                }
+                                Err(err) => { warn!("Unable to accept a syslog connection: {}", err) }
+                            }
+                        }
+                    })
+                    .expect("Unable to spawn a thread");
+            }
+            Listener::Gelf(addr) => {
+                let socket = UdpSocket::bind(&addr).unwrap_or_else(|err| panic!("Unable to bind to gelf://{}: {}", addr, err));
+                std::thread::Builder::new()
+                    .name("GelfListener".to_string())
+                    .spawn(move || { receive_gelf(socket, utf8_policy, tx) })
+                    .expect("Unable to spawn a thread");
+            }
+        }
+        rx.into_iter()
+    }
+
+    // Every datagram is a JSON message, possibly compressed or split into chunks
+    fn receive_gelf(socket: UdpSocket, utf8_policy: Utf8Policy, tx: SyncSender<LogLine>) {
+        let mut peers: HashMap<SocketAddr, (Arc<str>, usize)> = HashMap::new();
+        let mut chunked: HashMap<[u8; 8], GelfChunks> = HashMap::new();
+        let mut buf = vec![0; SYSLOG_DATAGRAM_SIZE];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) => {
+                    warn!("Unable to receive a GELF datagram: {}", err);
+                    continue;
+                }
+            };
+            chunked.retain(|_, message| message.received.elapsed() < GELF_CHUNK_TIMEOUT);
+            let datagram = &buf[..len];
+            let payload = match datagram {
+                [0x1e, 0x0f, rest @ ..] if rest.len() >= 10 => {
+                    let id: [u8; 8] = rest[..8].try_into().expect("Invalid GELF message id");
+                    let (sequence, count) = (rest[8], rest[9]);
+                    if count == 0 || count > GELF_MAX_CHUNKS || sequence >= count {
+                        warn!("Dropping a GELF chunk {} of {} from {}", sequence, count, peer);
+                        continue;
+                    }
+                    let message = chunked.entry(id).or_insert_with(|| GelfChunks {
+                        received: Instant::now(),
+                        chunks: vec![None; usize::from(count)],
+                    });
+                    if let Some(chunk) = message.chunks.get_mut(usize::from(sequence)) {
+                        *chunk = Some(rest[10..].to_vec());
+                    }
+                    if message.chunks.iter().any(Option::is_none) {
+                        continue;
+                    }
+                    let message = chunked.remove(&id).expect("Missing GELF chunks");
+                    message.chunks.into_iter().flatten().flatten().collect()
+                }
+                _ => datagram.to_vec(),
+            };
+            let payload = match decompress_gelf(payload) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    warn!("Unable to decompress a GELF message from {}: {}", peer, err);
+                    continue;
+                }
+            };
+            let (source, line_number) = peers.entry(peer).or_insert_with(|| (format!("gelf://{}", peer).into(), 0));
+            *line_number += 1;
+            if let Some(text) = utf8_policy.decode(payload.trim_ascii(), *line_number, source) {
+                if tx.send(LogLine::new(source, *line_number, text)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    // GELF messages are sent as plain JSON or compressed with gzip or zlib
+    fn decompress_gelf(payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        match payload.as_slice() {
+            [0x1f, 0x8b, ..] => { MultiGzDecoder::new(payload.as_slice()).read_to_end(&mut decompressed)?; }
+            [0x78, ..] => { ZlibDecoder::new(payload.as_slice()).read_to_end(&mut decompressed)?; }
+            _ => { return Ok(payload); }
+        }
+        Ok(decompressed)
+    }
+
+    // A datagram carries a single message, though some senders batch several newline separated ones
+    fn receive_datagrams(socket: UdpSocket, utf8_policy: Utf8Policy, tx: SyncSender<LogLine>) {
+        let mut peers: HashMap<SocketAddr, (Arc<str>, usize)> = HashMap::new();
+        let mut buf = vec![0; SYSLOG_DATAGRAM_SIZE];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) => {
+                    warn!("Unable to receive a syslog datagram: {}", err);
+                    continue;
+                }
+            };
+            let (source, line_number) = peers.entry(peer).or_insert_with(|| (format!("udp://{}", peer).into(), 0));
+            for message in buf[..len].split(|b| *b == b'\n').filter(|message| !message.trim_ascii().is_empty()) {
+                *line_number += 1;
+                if let Some(text) = utf8_policy.decode(message.trim_ascii_end(), *line_number, source) {
+                    if tx.send(LogLine::new(source, *line_number, text)).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // Both RFC 6587 framings are accepted: octet counting (`<length> <message>`) and newline delimited
+    fn receive_stream(stream: TcpStream, utf8_policy: Utf8Policy, tx: SyncSender<LogLine>) {
+        let source: Arc<str> = match stream.peer_addr() {
+            Ok(peer) => format!("tcp://{}", peer).into(),
+            Err(_) => "tcp://unknown".into(),
+        };
+        let mut reader = BufReader::new(stream);
+        let mut line_number = 0;
+        loop {
+            let message = match read_syslog_frame(&mut reader) {
+                Ok(Some(message)) => message,
+                Ok(None) => { return; }
+                Err(err) => {
+                    warn!("Closing syslog connection {}: {}", source, err);
+                    return;
+                }
+            };
+            if message.trim_ascii().is_empty() {
+                continue;
+            }
+            line_number += 1;
+            if let Some(text) = utf8_policy.decode(message.trim_ascii_end(), line_number, &source) {
+                if tx.send(LogLine::new(&source, line_number, text)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    // A frame is octet counted only when a length of at most SYSLOG_DATAGRAM_SIZE is directly followed by
+    // a space; anything else is read up to the next newline, keeping the digits already consumed
+    fn read_syslog_frame<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+        let max_digits = SYSLOG_DATAGRAM_SIZE.ilog10() as usize + 1;
+        let mut frame = Vec::new();
+        loop {
+            match reader.fill_buf()?.first().copied() {
+                None if frame.is_empty() => { return Ok(None); }
+                None => { return Ok(Some(frame)); }
+                Some(b) if b.is_ascii_digit() && frame.len() < max_digits => {
+                    frame.push(b);
+                    reader.consume(1);
+                }
+                Some(b' ') if !frame.is_empty() => {
+                    let length = std::str::from_utf8(&frame)
+                        .ok()
+                        .and_then(|length| length.parse::<usize>().ok())
+                        .filter(|length| (1..=SYSLOG_DATAGRAM_SIZE).contains(length));
+                    if let Some(length) = length {
+                        reader.consume(1);
+                        frame = vec![0; length];
+                        reader.read_exact(&mut frame)?;
+                        return Ok(Some(frame));
+                    }
+                    break;
+                }
+                Some(_) => { break; }
+            }
+        }
+        reader.read_until(b'\n', &mut frame)?;
+        Ok(Some(frame))
+    }
+
+    #[cfg(feature = "http")]
+    fn fetch_lines(url: &str, source: String, options: &CrawlOptions) -> Box<dyn Iterator<Item=LogLine>> {
+        let response = ureq::get(url)
+            .call()
+            .unwrap_or_else(|err| panic!("Unable to fetch {}: {}", source, err));
+        let reader = decompress(BufReader::new(response.into_reader()))
+            .and_then(transcode)
+            .expect("Unable to read the response body");
+        decoded_lines(reader, source, options)
+    }
+
+    // Tries every extractor on the sample and returns the one extracting the most lines, along with
+    // its success rate. Lenient extractors accept almost any line, so a line only counts when its
+    // timestamp is valid too; ties are broken by the number of extracted levels, components and fields.
+    pub fn detect_format(sample: &[LogLine]) -> Option<(&'static str, f64)> {
+        FORMATS.iter()
+            .map(|format| {
+                let extractor = configured_extractor(&format.to_string(), &ExtractorOptions::default());
+                let (mut extracted, mut fields) = (0, 0);
+                for line in sample {
+                    if let Some(e) = extractor(line.text.clone()) {
+                        if e.timestamp.as_ref().is_some_and(|ts| ts.to_iso8601().is_some()) {
+                            extracted += 1;
+                            fields += usize::from(e.level.is_some()) + usize::from(e.component.is_some()) + usize::from(!e.fields.is_empty());
+                        }
+                    }
+                }
+                (*format, extracted, fields)
+            })
+            .filter(|(_, extracted, _)| *extracted > 0)
+            .rev()
+            .max_by_key(|(_, extracted, fields)| (*extracted, *fields))
+            .map(|(format, extracted, _)| (format, extracted as f64 / sample.len() as f64))
+    }
+
+    // Runs the extractors of the given formats (all formats with samples if empty) with their default
+    // options on `EXTRACTOR_SAMPLES`, returning every sample whose extraction differs
+    pub fn check_extractors(formats: &[String]) -> Vec<ExtractorFailure> {
+        let formats: Vec<String> = if formats.is_empty() {
+            EXTRACTOR_SAMPLES.iter().map(|sample| sample.format.to_string()).unique().collect()
+        } else {
+            formats.to_vec()
+        };
+        let mut failures = Vec::new();
+        for format in formats {
+            let samples: Vec<&ExtractorSample> = EXTRACTOR_SAMPLES.iter().filter(|sample| sample.format == format).collect();
+            if samples.is_empty() {
+                failures.push(ExtractorFailure { format, line: None, problem: "No samples for this format".to_string() });
+                continue;
+            }
+            let extractor = configured_extractor(&format, &ExtractorOptions::default());
+            for sample in samples {
+                let extracted = extractor(sample.line.to_string());
+                let expected = sample.message.map(|message| {
+                    (message.to_string(), sample.timestamp.map(str::to_string), sample.level.map(str::to_string), sample.component.map(str::to_string))
+                });
+                let actual = extracted.map(|e| (e.message, e.timestamp.as_ref().and_then(Timestamp::to_iso8601), e.level, e.component));
+                if actual != expected {
+                    failures.push(ExtractorFailure {
+                        format: format.clone(),
+                        line: Some(sample.line.to_string()),
+                        problem: format!("expected {:?}, extracted {:?}", expected, actual),
+                    });
+                }
+            }
+        }
+        failures
+    }
+
+    // Like `message_extractor`, but also knows the formats whose fields are chosen by the user
+    pub fn configured_extractor(name: &String, options: &ExtractorOptions) -> Box<dyn Fn(String) -> Option<Extracted>> {
+        match name.borrow() {
+            "jsonl" => {
+                let fields = options.json.clone();
+                Box::new(move |line: String| { extract_json(&line, &fields) })
+            }
+            "android" => {
+                let format = options.logcat;
+                Box::new(move |line: String| { extract_logcat(&line, format) })
+            }
+            "docker" => Box::new(|line: String| { extract_docker(&line) }),
+            "cri" => Box::new(|line: String| { extract_cri(&line) }),
+            "csv" | "tsv" => {
+                let columns = options.csv.clone();
+                let delimiter = if name == "tsv" { b'\t' } else { b',' };
+                let header = Mutex::new(None);
+                Box::new(move |line: String| { extract_csv(&line, delimiter, &columns, &header) })
+            }
+            _ => Box::new(message_extractor(name)),
+        }
+    }
+
+    fn extract_logcat(line: &str, format: Option<LogcatFormat>) -> Option<Extracted> {
+        let captures = match format {
+            Some(format) => format.regex().captures(line)?,
+            None => LogcatFormat::ALL.iter().find_map(|format| format.regex().captures(line))?,
+        };
+        let msg = captures.name("msg")?.as_str().trim();
+        if msg.is_empty() {
+            None
+        } else {
+            Some(Extracted {
+                message: msg.to_string(),
+                timestamp: captures.name("ts")
+                    .and_then(|ts| Timestamp::new(ts.as_str(), TimestampFormat::Yearless("%m-%d %H:%M:%S%.3f"))),
+                level: captures.name("level").map(|level| level.as_str().to_string()),
+                component: captures.name("tag").map(|tag| tag.as_str().trim().to_string()),
+                fields: ["pid", "tid"].iter()
+                    .filter_map(|name| Some((name.to_string(), captures.name(name)?.as_str().to_string())))
+                    .collect(),
+            })
+        }
+    }
+
+    // Records of the json-file logging driver, whose partial records `JoinedRecords` joined
+    fn extract_docker(line: &str) -> Option<Extracted> {
+        let record: serde_json::Value = serde_json::from_str(line).ok()?;
+        let log = record.get("log")?.as_str()?;
+        let msg = log.trim();
+        if msg.is_empty() {
+            None
+        } else {
+            Some(Extracted {
+                message: msg.to_string(),
+                timestamp: record.get("time").and_then(json_text).and_then(|ts| Timestamp::new(&ts, TimestampFormat::Rfc3339)),
+                level: None,
+                component: None,
+                fields: record.get("stream").and_then(json_text).map(|stream| ("stream".to_string(), stream)).into_iter().collect(),
+            })
+        }
+    }
+
+    // Lines of the CRI format (containerd, CRI-O), `<timestamp> <stream> <tags> <message>`, whose
+    // partial lines `JoinedRecords` joined
+    fn extract_cri(line: &str) -> Option<Extracted> {
+        let mut parts = line.splitn(4, ' ');
+        let (ts, stream, _tags) = (parts.next()?, parts.next()?, parts.next()?);
+        if !is_rfc3339(ts) || !matches!(stream, "stdout" | "stderr") {
+            return None;
+        }
+        let msg = parts.next().unwrap_or_default().trim();
+        if msg.is_empty() {
+            None
+        } else {
+            Some(Extracted {
+                message: msg.to_string(),
+                timestamp: Timestamp::new(ts, TimestampFormat::Rfc3339),
+                level: None,
+                component: None,
+                fields: vec![("stream".to_string(), stream.to_string())],
+            })
+        }
+    }
+
+    // PostgreSQL logs with the default `%m [%p] ` prefix or the older `%t [%p]: [%l-1] ` one,
+    // optionally followed by `user@database`. Statements continue on tab-indented lines.
+    fn extract_postgres(line: &str) -> Option<Extracted> {
+        static POSTGRES: OnceLock<Regex> = OnceLock::new();
+        let regex = POSTGRES.get_or_init(|| {
+            Regex::new(r"(?s)^(?P<ts>\d{4}-\d\d-\d\d \d\d:\d\d:\d\d(?:\.\d+)?)(?: (?P<tz>[A-Za-z]+|[+-]\d\d(?::?\d\d)?))? \[(?P<pid>\d+)\]:?(?: \[\d+-\d+\])? (?:(?P<user>\S*)@(?P<db>\S*) )?(?P<level>DEBUG\d?|LOG|INFO|NOTICE|WARNING|ERROR|FATAL|PANIC|DETAIL|HINT|QUERY|CONTEXT|STATEMENT|LOCATION):\s+(?P<msg>.*)$")
+                .expect("Invalid PostgreSQL regex")
+        });
+        let captures = regex.captures(line)?;
+        let msg = captures["msg"].trim();
+        if msg.is_empty() {
+            return None;
+        }
+        let mut fields = vec![("pid".to_string(), captures["pid"].to_string())];
+        for name in ["user", "db"] {
+            if let Some(value) = captures.name(name).filter(|value| !value.as_str().is_empty()) {
+                fields.push((name.to_string(), value.as_str().to_string()));
+            }
+        }
+        Some(Extracted {
+            message: msg.to_string(),
+            timestamp: Timestamp::new(&captures["ts"], TimestampFormat::Naive("%Y-%m-%d %H:%M:%S%.f")),
+            level: Some(captures["level"].to_string()),
+            component: None,
+            fields,
+        })
+    }
+
+    // MySQL error logs: `<timestamp> <thread> [<level>] [<code>] [<subsystem>] <message>` since
+    // 8.0, without the code and subsystem before, and with `YYMMDD HH:MM:SS` timestamps in 5.5
+    fn extract_mysql(line: &str) -> Option<Extracted> {
+        static MYSQL: OnceLock<Regex> = OnceLock::new();
+        let regex = MYSQL.get_or_init(|| {
+            Regex::new(r"^(?P<ts>\d{4}-\d\d-\d\dT\d\d:\d\d:\d\d(?:\.\d+)?(?:Z|[+-]\d\d:\d\d)|\d{4}-\d\d-\d\d \d\d:\d\d:\d\d|\d{6}\s+\d{1,2}:\d\d:\d\d)\s+(?:(?P<thread>\d+) )?\[(?P<level>[A-Za-z]+)\](?: \[(?P<code>MY-\d+)\])?(?: \[(?P<subsystem>[A-Za-z]+)\])? (?P<msg>.*)$")
+                .expect("Invalid MySQL regex")
+        });
+        let captures = regex.captures(line)?;
+        let msg = captures["msg"].trim();
+        if msg.is_empty() {
+            return None;
+        }
+        let ts = &captures["ts"];
+        let timestamp = if is_rfc3339(ts) {
+            Timestamp::new(ts, TimestampFormat::Rfc3339)
+        } else if ts.contains('-') {
+            Timestamp::new(ts, TimestampFormat::Naive("%Y-%m-%d %H:%M:%S"))
+        } else {
+            Timestamp::new(ts, TimestampFormat::Naive("%y%m%d %k:%M:%S"))
+        };
+        let fields = ["thread", "code"].into_iter()
+            .filter_map(|name| captures.name(name).map(|value| (name.to_string(), value.as_str().to_string())))
+            .collect();
+        Some(Extracted {
+            message: msg.to_string(),
+            timestamp,
+            level: Some(captures["level"].to_string()),
+            component: captures.name("subsystem").map(|subsystem| subsystem.as_str().to_string()),
+            fields,
+        })
+    }
+
+    // Stream, text and whether it ends the line, of a record of the docker json-file logging driver,
+    // whose log keeps its newline unless the line was split into several records, or of a CRI line,
+    // whose `P` tag marks a partial line and `F` its last part
+    fn record_part(line: &str) -> Option<(String, String, bool)> {
+        if line.starts_with('{') {
+            let record: serde_json::Value = serde_json::from_str(line).ok()?;
+            record.get("time")?;
+            let stream = record.get("stream")?.as_str()?;
+            let log = record.get("log")?.as_str()?;
+            return Some((stream.to_string(), log.to_string(), log.ends_with('\n')));
+        }
+        let mut parts = line.splitn(4, ' ');
+        let (ts, stream, tags) = (parts.next()?, parts.next()?, parts.next()?);
+        if !is_rfc3339(ts) || !matches!(stream, "stdout" | "stderr") || !matches!(tags.split(':').next(), Some("P" | "F")) {
+            return None;
+        }
+        Some((stream.to_string(), parts.next().unwrap_or_default().to_string(), tags.split(':').next() == Some("F")))
+    }
+
+    // The first part of a split record, with the text of all its parts
+    fn joined_record(first: &str, text: String) -> String {
+        if first.starts_with('{') {
+            let mut record: serde_json::Value = serde_json::from_str(first).expect("Invalid partial record");
+            record["log"] = serde_json::Value::String(text);
+            return record.to_string();
+        }
+        let mut parts = first.splitn(3, ' ');
+        format!("{} {} F {}", parts.next().unwrap_or_default(), parts.next().unwrap_or_default(), text)
+    }
+
+    fn extract_csv(line: &str, delimiter: u8, columns: &CsvColumns, header: &Mutex<Option<csv::StringRecord>>) -> Option<Extracted> {
+        let record = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .delimiter(delimiter)
+            .from_reader(line.as_bytes())
+            .records()
+            .next()?
+            .ok()?;
+        let mut header = header.lock().expect("Poisoned CSV header");
+        if columns.has_names() {
+            match header.as_ref() {
+                None => {
+                    *header = Some(record);
+                    return None;
+                }
+                Some(names) if *names == record => { return None; }
+                Some(_) => {}
+            }
+        }
+        let field = |column: &CsvColumn| {
+            let index = match column {
+                CsvColumn::Position(position) => position - 1,
+                CsvColumn::Name(name) => header.as_ref()?.iter().position(|header| header == name)?,
+            };
+            record.get(index).map(str::to_string)
+        };
+        let msg = field(&columns.message)?;
+        let msg = msg.trim();
+        if msg.is_empty() {
+            None
+        } else {
+            Some(Extracted {
+                message: msg.to_string(),
+                timestamp: columns.timestamp.as_ref().and_then(field).and_then(|ts| {
+                    if is_rfc3339(&ts) {
+                        Timestamp::new(&ts, TimestampFormat::Rfc3339)
+                    } else {
+                        Timestamp::new(&ts, TimestampFormat::Naive("%Y-%m-%d %H:%M:%S%.f"))
+                    }
+                }),
+                level: columns.level.as_ref().and_then(field),
+                component: columns.component.as_ref().and_then(field),
+                fields: Vec::new(),
+            })
+        }
+    }
+
+    fn extract_json(line: &str, fields: &JsonFields) -> Option<Extracted> {
+        let value: serde_json::Value = serde_json::from_str(line).ok()?;
+        let field = |path: &Option<String>| path.as_ref().and_then(|path| json_path(&value, path)).and_then(json_text);
+        let msg = json_text(json_path(&value, &fields.message)?)?;
+        let msg = msg.trim();
+        if msg.is_empty() {
+            None
+        } else {
+            Some(Extracted {
+                message: msg.to_string(),
+                timestamp: field(&fields.timestamp).and_then(|ts| Timestamp::new(&ts, TimestampFormat::Rfc3339)),
+                level: field(&fields.level),
+                component: field(&fields.component),
+                fields: Vec::new(),
+            })
+        }
+    }
+
+    fn json_path<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+        path.trim_start_matches('.')
+            .split('.')
+            .filter(|key| !key.is_empty())
+            .try_fold(value, |value, key| {
+                match value {
+                    serde_json::Value::Array(elements) => elements.get(key.parse::<usize>().ok()?),
+                    _ => value.get(key),
+                }
+            })
+    }
+
+    fn json_text(value: &serde_json::Value) -> Option<String> {
+        match value {
+            serde_json::Value::String(text) => Some(text.clone()),
+            serde_json::Value::Null | serde_json::Value::Array(_) | serde_json::Value::Object(_) => None,
+            _ => Some(value.to_string()),
+        }
+    }
+
+    pub fn message_extractor(name: &String) -> impl Fn(String) -> Option<Extracted> {
+        match name.borrow() {
+            "hadoop" => |line: String| {
+                if line.len() > 29 {
+                    let begin = line.find(']')? + 1;
+                    let idx = line[begin..].find(':')? + 1;
+                    Some(Extracted {
+                        message: line[(begin + idx)..].trim().to_string(),
+                        timestamp: line.get(..23).and_then(|ts| Timestamp::new(ts, TimestampFormat::Naive(LOG4J_TIMESTAMP))),
+                        level: nth_token(&line, 2),
+                        component: Some(line[begin..(begin + idx - 1)].trim().to_string()),
+                        fields: Vec::new(),
+                    })
+                } else {
+                    None
+                }
+            },
+            "proxifier" => |line: String| {
+                Some(Extracted {
+                    message: line.get(17..)?.trim().to_string(),
+                    timestamp: line.get(1..15).and_then(|ts| Timestamp::new(ts, TimestampFormat::Yearless("%m.%d %H:%M:%S"))),
+                    level: None,
+                    component: line.get(17..)?.split(" - ").next().map(|program| program.trim().to_string()),
+                    fields: Vec::new(),
+                })
+            },
+            "ssh" => |line: String| {
+                if line.len() > 29 {
+                    let begin = line.find(']')?;
+                    Some(Extracted {
+                        message: line[(begin + 3)..].trim().to_string(),
+                        timestamp: line.get(..15).and_then(|ts| Timestamp::new(ts, TimestampFormat::Yearless(BSD_SYSLOG_TIMESTAMP))),
+                        level: None,
+                        component: syslog_app_name(&line),
+                        fields: Vec::new(),
+                    })
+                } else {
+                    None
+                }
+            },
+            "linux" => |line: String| {
+                if line.len() > 23 {
+                    let begin = line[23..].find(':')? + 2;
+                    let msg = line[23 + begin..].trim();
+                    if !msg.is_empty() {
+                        Some(Extracted {
+                            message: msg.to_string(),
+                            timestamp: line.get(..15).and_then(|ts| Timestamp::new(ts, TimestampFormat::Yearless(BSD_SYSLOG_TIMESTAMP))),
+                            level: None,
+                            component: syslog_app_name(&line),
+                            fields: Vec::new(),
+                        })
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            },
+            "openstack" => |line: String| {
+                if line.len() > 29 {
+                    let begin = line.find(']')?;
+                    let mut fields = line.split_whitespace().skip(1);
+                    let timestamp = fields.next().zip(fields.next()).map(|(date, time)| format!("{} {}", date, time));
+                    Some(Extracted {
+                        message: line[(begin + 2)..].trim().to_string(),
+                        timestamp: timestamp.and_then(|ts| Timestamp::new(&ts, TimestampFormat::Naive("%Y-%m-%d %H:%M:%S%.3f"))),
+                        level: nth_token(&line, 4),
+                        component: nth_token(&line, 5),
+                        fields: Vec::new(),
+                    })
+                } else {
+                    None
+                }
+            },
+            "hdfs" => |line: String| {
+                let line = line.trim();
+                Some(Extracted {
+                    message: line.splitn(6, ' ').last()?.to_string(),
+                    timestamp: line.get(..13).and_then(|ts| Timestamp::new(ts, TimestampFormat::Naive("%y%m%d %H%M%S"))),
+                    level: nth_token(line, 3),
+                    component: nth_token(line, 4).map(|component| component.trim_end_matches(':').to_string()),
+                    fields: Vec::new(),
+                })
+            },
+            "android" => |line: String| { extract_logcat(&line, None) },
+            "postgres" => |line: String| { extract_postgres(&line) },
+            "mysql" => |line: String| { extract_mysql(&line) },
+            "apache" => |line: String| {
+                // let msg = line[28..]
+                //     .splitn(2, ']')
+                //     .last()?;
+                let v: Vec<&str> = line.get(28..)?.splitn(2, ']').collect();
+                if v.len() == 2 {
+                    let msg = v[1].trim();
+                    if msg.is_empty() {
+                        None
+                    } else {
+                        Some(Extracted {
+                            message: msg.trim().to_string(),
+                            timestamp: line.get(1..25).and_then(|ts| Timestamp::new(ts, TimestampFormat::Naive("%a %b %d %H:%M:%S %Y"))),
+                            level: Some(v[0].trim().to_string()),
+                            component: None,
+                            fields: Vec::new(),
+                        })
+                    }
+                } else {
+                    None
+                }
+            },
+            "zookeeper" => |line: String| {
+                Some(Extracted {
+                    message: line.splitn(3, " - ").last()?.trim().to_string(),
+                    timestamp: line.get(..23).and_then(|ts| Timestamp::new(ts, TimestampFormat::Naive(LOG4J_TIMESTAMP))),
+                    level: line.split(" - ").nth(1).and_then(|part| nth_token(part, 0)),
+                    component: zookeeper_component(&line),
+                    fields: Vec::new(),
+                })
+            },
+            "hpc" => |line: String| {
+                let t= line.trim().splitn(7, ' ').last()?;
+                let timestamp = line.split_whitespace().nth(4).and_then(|ts| Timestamp::new(ts, TimestampFormat::EpochSeconds));
+                let component = nth_token(&line, 2);
+                if t.len() > 2 {
+                    let first_char = t.chars().next().unwrap();
+                    if first_char == '0' || first_char == '1' {
+                        Some(Extracted {
+                            message: t[2..].to_string(),
+                            timestamp,
+                            level: None,
+                            component,
+                            fields: Vec::new(),
+                        })
+                    } else {
+                        Some(Extracted {
+                            message: t.to_string(),
+                            timestamp,
+                            level: None,
+                            component,
+                            fields: Vec::new(),
+                        })
+                    }
+                } else {
+                    None
+                }
+            },
+            "healthapp" => |line: String| {
+                let mut fields = line.splitn(4, '|');
+                let timestamp = Timestamp::new(fields.next()?, TimestampFormat::Naive("%Y%m%d-%H:%M:%S:%3f"));
+                let component = fields.next().map(str::to_string);
+                let msg = fields.nth(1)?.trim();
+                if msg.is_empty() {
+                    None
+                } else {
+                    Some(Extracted {
+                        message: msg.to_string(),
+                        timestamp,
+                        level: None,
+                        component,
+                        fields: Vec::new(),
+                    })
+                }
+            },
+            "syslog" => |line: String| {
+                let record = parse_syslog(&line)?;
+                let msg = record.message.trim();
+                if msg.is_empty() {
+                    None
+                } else {
+                    Some(Extracted {
+                        message: msg.to_string(),
+                        timestamp: record.timestamp.and_then(|ts| {
+                            if is_rfc3339(ts) {
+                                Timestamp::new(ts, TimestampFormat::Rfc3339)
+                            } else {
+                                Timestamp::new(ts, TimestampFormat::Yearless(BSD_SYSLOG_TIMESTAMP))
+                            }
+                        }),
+                        level: record.pri.map(|pri| SYSLOG_SEVERITIES[usize::from(pri % 8)].to_string()),
+                        component: record.app_name.map(str::to_string),
+                        fields: Vec::new(),
+                    })
+                }
+            },
+            // Common and combined access logs of Apache and Nginx; the message is the request line
+            // followed by the status, the other fields are kept as key-value pairs
+            "access" => |line: String| {
+                let (host, rest) = next_token(&line);
+                let (ident, rest) = next_token(rest);
+                let (user, rest) = next_token(rest);
+                let rest = rest.trim_start().strip_prefix('[')?;
+                let (timestamp, rest) = rest.split_once(']')?;
+                let (request, rest) = quoted_field(rest)?;
+                let (status, rest) = next_token(rest);
+                let (bytes, rest) = next_token(rest);
+                if request.is_empty() || status.is_empty() {
+                    return None;
+                }
+                let mut fields = vec![
+                    ("host".to_string(), host.to_string()),
+                    ("ident".to_string(), ident.to_string()),
+                    ("user".to_string(), user.to_string()),
+                    ("bytes".to_string(), bytes.to_string()),
+                ];
+                if let Some((referer, rest)) = quoted_field(rest) {
+                    fields.push(("referer".to_string(), referer));
+                    if let Some((user_agent, _)) = quoted_field(rest) {
+                        fields.push(("user_agent".to_string(), user_agent));
+                    }
+                }
+                Some(Extracted {
+                    message: format!("{} {}", request, status),
+                    timestamp: Timestamp::new(timestamp, TimestampFormat::Offset(ACCESS_LOG_TIMESTAMP)),
+                    level: None,
+                    component: None,
+                    fields,
+                })
+            },
+            // Native access.log of Squid: `time elapsed client code/status bytes method URL user
+            // hierarchy/peer type`. The message is the request with its result code.
+            "squid" => |line: String| {
+                let (time, rest) = next_token(&line);
+                let (elapsed, rest) = next_token(rest);
+                let (client, rest) = next_token(rest);
+                let (result, rest) = next_token(rest);
+                let (bytes, rest) = next_token(rest);
+                let (method, rest) = next_token(rest);
+                let (url, rest) = next_token(rest);
+                if url.is_empty() || !result.contains('/') || elapsed.parse::<u64>().is_err() {
+                    return None;
+                }
+                let mut fields = vec![
+                    ("client".to_string(), client.to_string()),
+                    ("elapsed".to_string(), elapsed.to_string()),
+                    ("bytes".to_string(), bytes.to_string()),
+                ];
+                let names = ["user", "hierarchy", "type"];
+                fields.extend(rest.split_whitespace().zip(names).map(|(value, name)| (name.to_string(), value.to_string())));
+                Some(Extracted {
+                    message: format!("{} {} {}", method, url, result),
+                    timestamp: epoch_micros(time).and_then(|micros| Timestamp::new(&micros, TimestampFormat::EpochMicros)),
+                    level: None,
+                    component: None,
+                    fields,
+                })
+            },
+            // `key=value` pairs, e.g. `ts=2024-01-12T10:00:02Z level=info msg="user logged in" user=42`
+            "logfmt" => |line: String| {
+                let mut fields = parse_logfmt(&line)?;
+                let position = fields.iter().position(|(key, _)| key == "msg" || key == "message")?;
+                let (_, msg) = fields.remove(position);
+                let msg = msg.trim();
+                if msg.is_empty() {
+                    None
+                } else {
+                    let field = |names: &[&str]| fields.iter().find(|(key, _)| names.contains(&key.as_str())).map(|(_, value)| value.clone());
+                    Some(Extracted {
+                        message: msg.to_string(),
+                        timestamp: field(&["ts", "time", "timestamp"]).and_then(|ts| Timestamp::new(&ts, TimestampFormat::Rfc3339)),
+                        level: field(&["level", "lvl"]),
+                        component: field(&["logger", "component"]),
+                        fields,
+                    })
+                }
+            },
+            // ArcSight CEF events, optionally behind a syslog header. The message is the `msg`
+            // extension if there is one, otherwise the event name; the other extensions become fields.
+            "cef" => |line: String| {
+                let (header, extension) = split_header(&line[line.find("CEF:")? + 4..], 7)?;
+                let mut fields = parse_cef_extension(extension);
+                let msg = match fields.iter().position(|(key, _)| key == "msg") {
+                    Some(position) => fields.remove(position).1,
+                    None => header[5].clone(),
+                };
+                let msg = msg.trim();
+                if msg.is_empty() {
+                    None
+                } else {
+                    let timestamp = fields.iter().find(|(key, _)| key == "rt").and_then(|(_, rt)| security_timestamp(rt));
+                    Some(Extracted {
+                        message: msg.to_string(),
+                        timestamp,
+                        level: Some(header[6].clone()).filter(|severity| !severity.is_empty()),
+                        component: Some(header[2].clone()).filter(|product| !product.is_empty()),
+                        fields,
+                    })
+                }
+            },
+            // QRadar LEEF 1.0 and 2.0 events (the latter may name their attribute delimiter),
+            // labeled by the `msg` attribute or else by the event id
+            "leef" => |line: String| {
+                let event = &line[line.find("LEEF:")? + 5..];
+                let (header, attributes) = if event.starts_with('2') {
+                    let (header, attributes) = split_header(event, 6)?;
+                    let delimiter = leef_delimiter(&header[5])?;
+                    (header, attributes.split(delimiter).collect::<Vec<_>>())
+                } else {
+                    let (header, attributes) = split_header(event, 5)?;
+                    (header, attributes.split('\t').collect())
+                };
+                let mut fields: Vec<(String, String)> = attributes.iter()
+                    .filter_map(|attribute| attribute.split_once('='))
+                    .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+                    .collect();
+                let msg = match fields.iter().position(|(key, _)| key == "msg") {
+                    Some(position) => fields.remove(position).1,
+                    None => header[4].clone(),
+                };
+                let msg = msg.trim();
+                if msg.is_empty() {
+                    None
+                } else {
+                    let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+                    Some(Extracted {
+                        message: msg.to_string(),
+                        timestamp: field("devTime").and_then(|ts| security_timestamp(&ts)),
+                        level: field("sev"),
+                        component: Some(header[2].clone()).filter(|product| !product.is_empty()),
+                        fields,
+                    })
+                }
+            },
+            // GELF messages of Graylog, labeled by their short message. Additional fields lose their
+            // `_` prefix; the full message (e.g. a backtrace) is kept as a field.
+            "gelf" => |line: String| {
+                let record: serde_json::Value = serde_json::from_str(&line).ok()?;
+                let msg = record.get("short_message").or_else(|| record.get("full_message")).and_then(json_text)?;
+                let msg = msg.trim();
+                if msg.is_empty() {
+                    None
+                } else {
+                    let fields = record.as_object()?.iter()
+                        .filter_map(|(key, value)| match key.as_str() {
+                            "host" | "full_message" => Some((key.clone(), json_text(value)?)),
+                            "_id" => None,
+                            _ => Some((key.strip_prefix('_')?.to_string(), json_text(value)?)),
+                        })
+                        .collect();
+                    Some(Extracted {
+                        message: msg.to_string(),
+                        timestamp: record.get("timestamp")
+                            .and_then(serde_json::Value::as_f64)
+                            .and_then(|seconds| Timestamp::new(&((seconds * 1e6).round() as i64).to_string(), TimestampFormat::EpochMicros)),
+                        level: record.get("level")
+                            .and_then(serde_json::Value::as_u64)
+                            .and_then(|level| SYSLOG_SEVERITIES.get(level as usize))
+                            .map(|severity| severity.to_string()),
+                        component: record.get("facility").or_else(|| record.get("_facility")).and_then(json_text),
+                        fields,
+                    })
+                }
+            },
+            // Windows event log records in the JSON form of the evtx crate (or `evtx_dump -o jsonl`).
+            // Only forwarded events contain the rendered message, otherwise the event data is used.
+            "evtx" => |line: String| {
+                let record: serde_json::Value = serde_json::from_str(&line).ok()?;
+                let event = record.get("Event")?;
+                let system = event.get("System")?;
+                let msg = json_path(event, ".RenderingInfo.Message")
+                    .and_then(json_text)
+                    .or_else(|| event.get("EventData").or_else(|| event.get("UserData")).map(evtx_data))?;
+                let msg = msg.trim();
+                if msg.is_empty() {
+                    None
+                } else {
+                    let fields = ["EventID", "Channel", "Computer"].iter()
+                        .filter_map(|name| Some((name.to_string(), evtx_text(system.get(*name)?)?)))
+                        .collect();
+                    Some(Extracted {
+                        message: msg.to_string(),
+                        timestamp: json_path(system, ".TimeCreated.#attributes.SystemTime")
+                            .and_then(json_text)
+                            .and_then(|ts| Timestamp::new(&ts, TimestampFormat::Rfc3339)),
+                        level: system.get("Level")
+                            .and_then(evtx_text)
+                            .and_then(|level| EVTX_LEVELS.get(level.parse::<usize>().ok()?).copied())
+                            .filter(|level| !level.is_empty())
+                            .map(str::to_string),
+                        component: json_path(system, ".Provider.#attributes.Name").and_then(json_text),
+                        fields,
+                    })
+                }
+            },
+            // Entries of `journalctl -o json`, or of `journalctl -o export` once they are read
+            "journal" => |line: String| {
+                let entry: serde_json::Value = serde_json::from_str(&line).ok()?;
+                let msg = journal_field(&entry, "MESSAGE")?;
+                let msg = msg.trim();
+                if msg.is_empty() {
+                    None
+                } else {
+                    Some(Extracted {
+                        message: msg.to_string(),
+                        timestamp: Timestamp::new(&journal_field(&entry, "__REALTIME_TIMESTAMP").unwrap_or_default(), TimestampFormat::EpochMicros),
+                        level: journal_field(&entry, "PRIORITY")
+                            .and_then(|priority| SYSLOG_SEVERITIES.get(priority.parse::<usize>().ok()?))
+                            .map(|severity| severity.to_string()),
+                        component: journal_field(&entry, "SYSLOG_IDENTIFIER").or_else(|| journal_field(&entry, "_COMM")),
+                        fields: JOURNAL_FIELDS.iter()
+                            .filter_map(|name| Some((name.to_string(), journal_field(&entry, name)?)))
+                            .collect(),
+                    })
+                }
+            },
+            _ => { panic!("Unsupported dataset!") }
+        }
+    }
+
+    fn nth_token(line: &str, n: usize) -> Option<String> {
+        line.split_whitespace().nth(n).map(str::to_string)
+    }
+
+    // Splits the `|`-separated header of a CEF or LEEF event into `count` fields and the rest,
+    // undoing the `\|` and `\\` escapes
+    fn split_header(event: &str, count: usize) -> Option<(Vec<String>, &str)> {
+        let mut header = Vec::with_capacity(count);
+        let mut field = String::new();
+        let mut chars = event.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next()? {
+                    (_, c @ ('|' | '\\')) => field.push(c),
+                    (_, c) => {
+                        field.push('\\');
+                        field.push(c);
+                    }
+                },
+                '|' => {
+                    header.push(std::mem::take(&mut field));
+                    if header.len() == count {
+                        return Some((header, &event[i + 1..]));
+                    }
+                }
+                c => field.push(c),
+            }
+        }
+        None
+    }
+
+    // CEF extension values may contain spaces, so a value ends where the next `key=` begins
+    fn parse_cef_extension(extension: &str) -> Vec<(String, String)> {
+        let mut pairs = Vec::new();
+        let mut key: Option<String> = None;
+        let mut text = String::new();
+        let mut chars = extension.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('n') => text.push('\n'),
+                    Some('r') => text.push('\r'),
+                    Some(c) => text.push(c),
+                    None => text.push('\\'),
+                },
+                '=' => {
+                    let start = text.rfind(char::is_whitespace).unwrap_or(0);
+                    let next_key = text[start..].trim().to_string();
+                    text.truncate(start);
+                    if let Some(key) = key.replace(next_key) {
+                        pairs.push((key, text.trim().to_string()));
+                    }
+                    text.clear();
+                }
+                c => text.push(c),
+            }
+        }
+        if let Some(key) = key {
+            pairs.push((key, text.trim().to_string()));
+        }
+        pairs
+    }
+
+    // Either a single character or its code such as `x09` or `0x09`
+    fn leef_delimiter(field: &str) -> Option<char> {
+        let code = field.strip_prefix("0x").or_else(|| field.strip_prefix('x'));
+        match code {
+            Some(code) if !code.is_empty() => char::from_u32(u32::from_str_radix(code, 16).ok()?),
+            _ => field.chars().exactly_one().ok(),
+        }
+    }
+
+    // Epoch milliseconds, RFC 3339 or the `MMM dd yyyy HH:mm:ss` form of CEF and LEEF
+    fn security_timestamp(text: &str) -> Option<Timestamp> {
+        if let Ok(millis) = text.parse::<i64>() {
+            Timestamp::new(&millis.checked_mul(1000)?.to_string(), TimestampFormat::EpochMicros)
+        } else if is_rfc3339(text) {
+            Timestamp::new(text, TimestampFormat::Rfc3339)
+        } else {
+            Timestamp::new(text, TimestampFormat::Naive("%b %d %Y %H:%M:%S%.f"))
+        }
+    }
+
+    // Elements with attributes keep their value in `#text`
+    fn evtx_text(value: &serde_json::Value) -> Option<String> {
+        json_text(value.get("#text").unwrap_or(value))
+    }
+
+    fn evtx_data(data: &serde_json::Value) -> String {
+        match data {
+            serde_json::Value::Object(entries) => {
+                entries.iter()
+                    .filter(|(key, _)| !key.starts_with('#'))
+                    .map(|(key, value)| {
+                        let value = evtx_text(value).unwrap_or_else(|| value.to_string());
+                        format!("{}={}", key, logfmt_value(&value))
+                    })
+                    .join(" ")
+            }
+            _ => evtx_text(data).unwrap_or_default(),
+        }
+    }
+
+    // A `"`-quoted field with `\"` escapes at the start of `s`, along with the rest of `s`
+    fn quoted_field(s: &str) -> Option<(String, &str)> {
+        let s = s.trim_start().strip_prefix('"')?;
+        let mut field = String::new();
+        let mut chars = s.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => { return Some((field, &s[i + 1..])); }
+                '\\' => { field.push(chars.next()?.1); }
+                c => field.push(c),
+            }
+        }
+        None
+    }
+
+    pub(crate) fn logfmt_value(value: &str) -> String {
+        if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+            format!("{:?}", value)
+        } else {
+            value.to_string()
+        }
+    }
+
+    // A key without `=` gets an empty value, quoted values may contain `\"` and `\n` escapes
+    fn parse_logfmt(line: &str) -> Option<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        let mut chars = line.chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.peek().is_none() {
+                return Some(pairs);
+            }
+            let mut key = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=') {
+                key.push(c);
+            }
+            if key.is_empty() {
+                return None;
+            }
+            let mut value = String::new();
+            if chars.next_if_eq(&'=').is_some() {
+                if chars.next_if_eq(&'"').is_some() {
+                    loop {
+                        match chars.next()? {
+                            '"' => { break; }
+                            '\\' => match chars.next()? {
+                                'n' => value.push('\n'),
+                                't' => value.push('\t'),
+                                c => value.push(c),
+                            },
+                            c => value.push(c),
+                        }
+                    }
+                } else {
+                    while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                        value.push(c);
+                    }
+                }
+            }
+            pairs.push((key, value));
+        }
+    }
+
+    // Fields that are not valid UTF-8 are serialized as arrays of bytes
+    fn journal_field(entry: &serde_json::Value, name: &str) -> Option<String> {
+        match entry.get(name)? {
+            serde_json::Value::String(value) => Some(value.clone()),
+            serde_json::Value::Array(bytes) => {
+                let bytes = bytes.iter().map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok())).collect::<Option<Vec<u8>>>()?;
+                Some(String::from_utf8_lossy(&bytes).into_owned())
+            }
+            _ => None,
+        }
+    }
+
+    fn syslog_app_name(line: &str) -> Option<String> {
+        parse_syslog(line)?.app_name.map(str::to_string)
+    }
+
+    // `[QuorumPeer[myid=1]/0:0:0:0:0:0:0:0:2181:FastLeaderElection@774]` holds the FastLeaderElection component
+    fn zookeeper_component(line: &str) -> Option<String> {
+        let location = line.split(" - ").nth(1)?;
+        let location = location.get(location.find('[')? + 1..location.rfind(']')?)?;
+        let class = location.rsplit(':').next()?;
+        Some(class.split('@').next()?.to_string())
+    }
+
+    #[derive(Debug, Default)]
+    pub struct SyslogRecord<'a> {
+        pub pri: Option<u16>,
+        pub timestamp: Option<&'a str>,
+        pub hostname: Option<&'a str>,
+        pub app_name: Option<&'a str>,
+        pub proc_id: Option<&'a str>,
+        pub msg_id: Option<&'a str>,
+        pub structured_data: Option<&'a str>,
+        pub message: &'a str,
+    }
+
+    // Parses both RFC 5424 (`<PRI>1 TIMESTAMP HOST APP PROCID MSGID SD MSG`) and
+    // RFC 3164 (`<PRI>Mmm dd hh:mm:ss HOST TAG[PID]: MSG`) lines. The PRI part is optional
+    // since it is usually dropped when syslog daemons write to files.
+    pub fn parse_syslog(line: &str) -> Option<SyslogRecord<'_>> {
+        let mut record = SyslogRecord::default();
+        let mut rest = line.trim_start();
+        if let Some(stripped) = rest.strip_prefix('<') {
+            let end = stripped.find('>')?;
+            record.pri = Some(stripped[..end].parse().ok()?);
+            rest = &stripped[end + 1..];
+        }
+        if is_rfc5424(rest) {
+            parse_rfc5424(&mut record, rest)?;
+        } else {
+            parse_rfc3164(&mut record, rest)?;
+        }
+        Some(record)
+    }
+
+    fn is_rfc5424(rest: &str) -> bool {
+        let (version, rest) = next_token(rest);
+        if version.is_empty() || version.len() > 3 || !version.bytes().all(|b| b.is_ascii_digit()) {
+            return false;
+        }
+        let (timestamp, _) = next_token(rest);
+        timestamp == "-" || is_rfc3339(timestamp)
+    }
+
+    fn parse_rfc5424<'a>(record: &mut SyslogRecord<'a>, rest: &'a str) -> Option<()> {
+        let mut fields = rest.splitn(7, ' ');
+        fields.next()?;
+        record.timestamp = nil_value(fields.next()?);
+        record.hostname = nil_value(fields.next()?);
+        record.app_name = nil_value(fields.next()?);
+        record.proc_id = nil_value(fields.next()?);
+        record.msg_id = nil_value(fields.next()?);
+        let rest = fields.next().unwrap_or("");
+        let sd_end = structured_data_end(rest)?;
+        record.structured_data = nil_value(&rest[..sd_end]);
+        let msg = &rest[sd_end..];
+        record.message = msg.strip_prefix(' ').unwrap_or(msg).trim_start_matches('\u{feff}');
+        Some(())
+    }
+
+    // Returns the byte offset right after the STRUCTURED-DATA field, honouring `\]` escapes
+    fn structured_data_end(rest: &str) -> Option<usize> {
+        if rest.is_empty() {
+            return Some(0);
+        }
+        if rest.starts_with('-') {
+            return Some(1);
+        }
+        let bytes = rest.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() && bytes[i] == b'[' {
+            i += 1;
+            loop {
+                match bytes.get(i)? {
+                    b'\\' => { i += 2; }
+                    b']' => { i += 1; break; }
+                    _ => { i += 1; }
+                }
+            }
+        }
+        if i == 0 {
+            None
+        } else {
+            Some(i)
+        }
+    }
+
+    fn parse_rfc3164<'a>(record: &mut SyslogRecord<'a>, rest: &'a str) -> Option<()> {
+        let (first, after_first) = next_token(rest);
+        let rest = if is_rfc3339(first) {
+            record.timestamp = Some(first);
+            after_first
+        } else {
+            let (day, after_day) = next_token(after_first);
+            let (time, after_time) = next_token(after_day);
+            if first.len() != 3 || !first.bytes().all(|b| b.is_ascii_alphabetic())
+                || day.is_empty() || !day.bytes().all(|b| b.is_ascii_digit())
+                || time.bytes().filter(|b| *b == b':').count() != 2 {
+                return None;
+            }
+            let rest = rest.trim_start();
+            record.timestamp = Some(&rest[..rest.len() - after_time.len()]);
+            after_time
+        };
+
+        // The hostname is missing in some configurations, in which case the tag comes first
+        let (host, after_host) = next_token(rest);
+        let rest = if is_tag(host) {
+            rest
+        } else {
+            record.hostname = Some(host).filter(|h| !h.is_empty());
+            after_host
+        };
+
+        let (tag, after_tag) = next_token(rest);
+        if is_tag(tag) {
+            let tag = tag.strip_suffix(':').unwrap_or(tag);
+            match tag.find('[') {
+                Some(begin) if tag.ends_with(']') => {
+                    record.app_name = Some(&tag[..begin]);
+                    record.proc_id = Some(&tag[begin + 1..tag.len() - 1]);
+                }
+                _ => { record.app_name = Some(tag); }
+            }
+            record.message = after_tag.trim_start();
+        } else {
+            record.message = rest.trim_start();
+        }
+        Some(())
+    }
+
+    fn is_tag(token: &str) -> bool {
+        token.len() > 1 && (token.ends_with(':') || (token.ends_with(']') && token.contains('[')))
+    }
+
+    fn is_rfc3339(token: &str) -> bool {
+        let bytes = token.as_bytes();
+        bytes.len() >= 19 && bytes[..4].iter().all(|b| b.is_ascii_digit()) && bytes[4] == b'-' && bytes[10] == b'T'
+    }
+
+    fn nil_value(field: &str) -> Option<&str> {
+        if field == "-" || field.is_empty() {
+            None
+        } else {
+            Some(field)
+        }
+    }
+
+    // Seconds with an optional fraction, e.g. `1286536309.450`, as whole microseconds
+    fn epoch_micros(seconds: &str) -> Option<String> {
+        let (whole, fraction) = seconds.split_once('.').unwrap_or((seconds, ""));
+        if whole.is_empty() || !whole.bytes().all(|b| b.is_ascii_digit()) || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let fraction: String = fraction.chars().chain(std::iter::repeat('0')).take(6).collect();
+        Some(format!("{}{}", whole, fraction))
+    }
+
+    fn next_token(s: &str) -> (&str, &str) {
+        let s = s.trim_start_matches(' ');
+        match s.find(' ') {
+            Some(end) => (&s[..end], &s[end..]),
+            None => (s, ""),
+        }
+    }
+
+    fn read_lines(entry: DirEntry, options: &CrawlOptions) -> Box<dyn Iterator<Item=LogLine>> {
+        let path = entry.into_path();
+        #[cfg(feature = "evtx")]
+        if path.extension().is_some_and(|ext| ext == "evtx") {
+            return Box::new(evtx_lines(path));
+        }
+        match archive_kind(&path) {
+            Some(kind) => Box::new(archive_lines(path, kind, options.clone())),
+            None => {
+                let source = path.display().to_string();
+                let resume = options.resume.get(&source).copied();
+                if let Some((reader, position)) = open_plain_log(&path, resume).expect("Unable to open file") {
+                    let mut lines = DecodedLines::new(reader, source, options.utf8_policy);
+                    lines.line_number = position.line_number;
+                    lines.offset = Some(position.offset);
+                    return stitched_lines(lines, options);
+                }
+                let reader = open_log(&path).expect("Unable to open file");
+                decoded_lines(reader, source, options)
+            }
+        }
+    }
+
+    fn decoded_lines<'a, R: BufRead + 'a>(mut reader: R, source: String, options: &CrawlOptions) -> Box<dyn Iterator<Item=LogLine> + 'a> {
+        if reader.fill_buf().is_ok_and(|buf| buf.starts_with(JOURNAL_EXPORT_MAGIC)) {
+            return Box::new(JournalExportEntries {
+                reader,
+                source: source.into(),
+                entry_number: 0,
+            });
+        }
+        stitched_lines(DecodedLines::new(reader, source, options.utf8_policy), options)
+    }
+
+    fn stitched_lines<'a, I: Iterator<Item=LogLine> + 'a>(lines: I, options: &CrawlOptions) -> Box<dyn Iterator<Item=LogLine> + 'a> {
+        let lines: Box<dyn Iterator<Item=LogLine> + 'a> = if options.partial_records {
+            Box::new(JoinedRecords { lines, pending: BTreeMap::new() })
+        } else {
+            Box::new(lines)
+        };
+        match &options.multiline {
+            Some(multiline) => Box::new(StitchedLines {
+                lines: lines.peekable(),
+                multiline: multiline.clone(),
+            }),
+            None => Box::new(lines),
+        }
+    }
+
+    fn open_log(path: &Path) -> io::Result<Box<dyn BufRead>> {
+        transcode(decompress(BufReader::new(File::open(path)?))?)
+    }
+
+    // Files read as they are, positioned at `resume` unless the file is shorter than that, or
+    // `None` if they have to be decoded and can only be read from the start
+    fn open_plain_log(path: &Path, resume: Option<FilePosition>) -> io::Result<Option<(BufReader<File>, FilePosition)>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        if !is_plain_text(reader.fill_buf()?) {
+            return Ok(None);
+        }
+        let length = reader.get_ref().metadata()?.len();
+        let position = resume.filter(|position| position.offset <= length).unwrap_or(FilePosition { line_number: 0, offset: 0 });
+        reader.seek(SeekFrom::Start(position.offset))?;
+        Ok(Some((reader, position)))
+    }
+
+    fn is_plain_text(head: &[u8]) -> bool {
+        compression(head).is_none() && utf16_encoding(head).is_none() && !head.starts_with(JOURNAL_EXPORT_MAGIC)
+    }
+
+    // Compressed streams are detected by their magic bytes and decompressed on the fly
+    fn decompress<'a, R: BufRead + 'a>(mut reader: R) -> io::Result<Box<dyn BufRead + 'a>> {
+        Ok(match compression(reader.fill_buf()?) {
+            Some(Compression::Gzip) => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+            Some(Compression::Zstd) => Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+            Some(Compression::Xz) => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+            Some(Compression::Bzip2) => Box::new(BufReader::new(MultiBzDecoder::new(reader))),
+            None => Box::new(reader),
+        })
+    }
+
+    fn compression(magic: &[u8]) -> Option<Compression> {
+        if magic.starts_with(&GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if magic.starts_with(&ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else if magic.starts_with(&XZ_MAGIC) {
+            Some(Compression::Xz)
+        } else if magic.starts_with(&BZIP2_MAGIC) && magic.get(3).is_some_and(|b| (b'1'..=b'9').contains(b)) {
+            Some(Compression::Bzip2)
+        } else {
+            None
+        }
+    }
+
+    // UTF-16 streams (usually Windows logs) are converted to UTF-8, everything else is passed through as is
+    fn transcode<'a>(mut reader: Box<dyn BufRead + 'a>) -> io::Result<Box<dyn BufRead + 'a>> {
+        match utf16_encoding(reader.fill_buf()?) {
+            Some(encoding) => {
+                let decoder = DecodeReaderBytesBuilder::new()
+                    .encoding(Some(encoding))
+                    .build(reader);
+                Ok(Box::new(BufReader::new(decoder)))
+            }
+            None => Ok(reader),
+        }
+    }
+
+    fn utf16_encoding(sample: &[u8]) -> Option<&'static Encoding> {
+        if sample.starts_with(&UTF16LE_BOM) {
+            return Some(UTF_16LE);
+        }
+        if sample.starts_with(&UTF16BE_BOM) {
+            return Some(UTF_16BE);
+        }
+        // Without a BOM, mostly ASCII text encoded as UTF-16 has every other byte zeroed
+        let sample = &sample[..sample.len().min(UTF16_SNIFF_LENGTH) & !1];
+        if sample.len() < 4 {
+            return None;
+        }
+        if sample.chunks(2).all(|pair| pair[0] != 0 && pair[1] == 0) {
+            Some(UTF_16LE)
+        } else if sample.chunks(2).all(|pair| pair[0] == 0 && pair[1] != 0) {
+            Some(UTF_16BE)
+        } else {
+            None
+        }
+    }
+
+    // Archive members are read on a dedicated thread since the archive readers borrow
+    // the archive for as long as a member is being read
+    fn archive_lines(path: PathBuf, kind: ArchiveKind, options: CrawlOptions) -> ThreadedLines {
+        let (tx, rx) = mpsc::sync_channel(READER_CHANNEL_BOUND);
+        let handle = std::thread::Builder::new()
+            .name(format!("ArchiveReader {}", path.display()))
+            .spawn(move || {
+                let result = match kind {
+                    ArchiveKind::Tar => read_tar(&path, &options, &tx),
+                    ArchiveKind::Zip => read_zip(&path, &options, &tx),
+                };
+                if let Err(err) = result {
+                    error!("Unable to read archive {}: {}", path.display(), err);
+                }
+            })
+            .expect("Unable to spawn a thread");
+        ThreadedLines {
+            lines: rx,
+            handles: vec![handle],
+        }
+    }
+
+    fn read_tar(path: &Path, options: &CrawlOptions, tx: &SyncSender<LogLine>) -> io::Result<()> {
+        let mut archive = tar::Archive::new(decompress(BufReader::new(File::open(path)?))?);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let member = entry.path()?.into_owned();
+            if !entry.header().entry_type().is_file() || !options.is_log_path(&member) {
+                continue;
+            }
+            let source = format!("{}:{}", path.display(), member.display());
+            let reader = transcode(decompress(BufReader::new(entry))?)?;
+            if !send_lines(decoded_lines(reader, source, options), tx) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_zip(path: &Path, options: &CrawlOptions, tx: &SyncSender<LogLine>) -> io::Result<()> {
+        let mut archive = zip::ZipArchive::new(File::open(path)?).map_err(io::Error::from)?;
+        for i in 0..archive.len() {
+            let file = archive.by_index(i).map_err(io::Error::from)?;
+            if !file.is_file() || !file.enclosed_name().is_some_and(|name| options.is_log_path(&name)) {
+                continue;
+            }
+            let source = format!("{}:{}", path.display(), file.name());
+            let reader = transcode(decompress(BufReader::new(file))?)?;
+            if !send_lines(decoded_lines(reader, source, options), tx) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Every record becomes a line of JSON, numbered by its record id
+    #[cfg(feature = "evtx")]
+    fn evtx_lines(path: PathBuf) -> ThreadedLines {
+        let (tx, rx) = mpsc::sync_channel(READER_CHANNEL_BOUND);
+        let handle = std::thread::Builder::new()
+            .name(format!("EvtxReader {}", path.display()))
+            .spawn(move || {
+                let mut parser = match evtx::EvtxParser::from_path(&path) {
+                    Ok(parser) => parser,
+                    Err(err) => {
+                        error!("Unable to read event log {}: {}", path.display(), err);
+                        return;
+                    }
+                };
+                let source: Arc<str> = path.display().to_string().into();
+                for record in parser.records_json_value() {
+                    match record {
+                        Ok(record) => {
+                            if tx.send(LogLine::new(&source, record.event_record_id as usize, record.data.to_string())).is_err() {
+                                return;
+                            }
+                        }
+                        Err(err) => { warn!("Skipping a record of {}: {}", source, err); }
+                    }
+                }
+            })
+            .expect("Unable to spawn a thread");
+        ThreadedLines {
+            lines: rx,
+            handles: vec![handle],
+        }
+    }
+
+    // Returns false once the receiving side has hung up
+    fn send_lines(mut lines: impl Iterator<Item=LogLine>, tx: &SyncSender<LogLine>) -> bool {
+        lines.all(|line| tx.send(line).is_ok())
+    }
+
+    fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+        let name = path.file_name()?.to_str()?;
+        if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if TAR_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+            Some(ArchiveKind::Tar)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(unix)]
+    fn file_id(metadata: &std::fs::Metadata) -> Option<u64> {
+        use std::os::unix::fs::MetadataExt;
+        Some(metadata.ino())
+    }
+
+    #[cfg(not(unix))]
+    fn file_id(_metadata: &std::fs::Metadata) -> Option<u64> {
+        None
+    }
+
+    fn glob_matches(pattern: &Pattern, path: &Path) -> bool {
+        // Patterns without a separator apply to the file name, like in .gitignore files
+        if pattern.as_str().contains('/') {
+            pattern.matches_path(path)
+        } else {
+            path.file_name().is_some_and(|name| pattern.matches(&name.to_string_lossy()))
+        }
+    }
+
+    fn relative_path<'a>(root: &Path, entry: &'a DirEntry) -> &'a Path {
+        entry.path().strip_prefix(root).unwrap_or(entry.path())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn frames(input: &str) -> Vec<String> {
+            let mut reader = BufReader::new(input.as_bytes());
+            std::iter::from_fn(|| read_syslog_frame(&mut reader).unwrap())
+                .map(|frame| String::from_utf8(frame).unwrap())
+                .collect()
+        }
+
+        fn joined(lines: &[(&str, &str)]) -> Vec<(String, usize, String)> {
+            let lines = lines.iter().enumerate()
+                .map(|(i, (source, text))| LogLine::new(&Arc::from(*source), i + 1, text.to_string()))
+                .collect::<Vec<_>>();
+            JoinedRecords { lines: lines.into_iter(), pending: BTreeMap::new() }
+                .map(|line| (line.source.to_string(), line.line_number, line.text))
+                .collect()
+        }
+
+        #[test]
+        fn docker_records_joined_per_stream() {
+            let lines = [
+                ("a", r#"{"log":"out ","stream":"stdout","time":"2019-01-01T11:11:11Z"}"#),
+                ("a", r#"{"log":"err\n","stream":"stderr","time":"2019-01-01T11:11:12Z"}"#),
+                ("a", r#"{"log":"line\n","stream":"stdout","time":"2019-01-01T11:11:13Z"}"#),
+            ];
+            assert_eq!(joined(&lines), vec![
+                ("a".to_string(), 2, r#"{"log":"err\n","stream":"stderr","time":"2019-01-01T11:11:12Z"}"#.to_string()),
+                ("a".to_string(), 1, r#"{"log":"out line\n","stream":"stdout","time":"2019-01-01T11:11:11Z"}"#.to_string()),
+            ]);
+        }
+
+        #[test]
+        fn docker_partial_record_ends_with_its_source() {
+            let lines = [
+                ("a", r#"{"log":"cut","stream":"stdout","time":"2019-01-01T11:11:11Z"}"#),
+                ("b", r#"{"log":"whole\n","stream":"stdout","time":"2019-01-01T11:11:12Z"}"#),
+            ];
+            assert_eq!(joined(&lines), vec![
+                ("b".to_string(), 2, r#"{"log":"whole\n","stream":"stdout","time":"2019-01-01T11:11:12Z"}"#.to_string()),
+                ("a".to_string(), 1, r#"{"log":"cut","stream":"stdout","time":"2019-01-01T11:11:11Z"}"#.to_string()),
+            ]);
+        }
+
+        #[test]
+        fn cri_lines_joined_per_stream() {
+            let lines = [
+                ("a", "2016-10-06T00:17:09.669794202Z stdout P Listening "),
+                ("a", "2016-10-06T00:17:09.669794203Z stderr F Failed"),
+                ("a", "2016-10-06T00:17:09.669794204Z stdout P on port "),
+                ("a", "2016-10-06T00:17:09.669794205Z stdout F 8080"),
+                ("a", "2016-10-06T00:17:09.669794206Z stderr P Cut"),
+            ];
+            assert_eq!(joined(&lines), vec![
+                ("a".to_string(), 2, "2016-10-06T00:17:09.669794203Z stderr F Failed".to_string()),
+                ("a".to_string(), 1, "2016-10-06T00:17:09.669794202Z stdout F Listening on port 8080".to_string()),
+                ("a".to_string(), 5, "2016-10-06T00:17:09.669794206Z stderr F Cut".to_string()),
+            ]);
+        }
+
+        #[test]
+        fn rfc3164_syslog() {
+            let record = parse_syslog("<34>Oct 11 22:14:15 mymachine su[230]: 'su root' failed on /dev/pts/8").unwrap();
+            assert_eq!(record.pri, Some(34));
+            assert_eq!(record.timestamp, Some("Oct 11 22:14:15"));
+            assert_eq!(record.hostname, Some("mymachine"));
+            assert_eq!(record.app_name, Some("su"));
+            assert_eq!(record.proc_id, Some("230"));
+            assert_eq!(record.message, "'su root' failed on /dev/pts/8");
+        }
+
+        #[test]
+        fn rfc3164_syslog_without_pid() {
+            let record = parse_syslog("Oct  1 02:04:05 gateway sshd: Accepted publickey").unwrap();
+            assert_eq!(record.pri, None);
+            assert_eq!(record.timestamp, Some("Oct  1 02:04:05"));
+            assert_eq!(record.hostname, Some("gateway"));
+            assert_eq!(record.app_name, Some("sshd"));
+            assert_eq!(record.proc_id, None);
+            assert_eq!(record.message, "Accepted publickey");
+        }
+
+        #[test]
+        fn rfc5424_syslog() {
+            let record = parse_syslog(r#"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut="3"] An application event"#).unwrap();
+            assert_eq!(record.pri, Some(165));
+            assert_eq!(record.timestamp, Some("2003-10-11T22:14:15.003Z"));
+            assert_eq!(record.hostname, Some("mymachine.example.com"));
+            assert_eq!(record.app_name, Some("evntslog"));
+            assert_eq!(record.proc_id, None);
+            assert_eq!(record.msg_id, Some("ID47"));
+            assert_eq!(record.structured_data, Some(r#"[exampleSDID@32473 iut="3"]"#));
+            assert_eq!(record.message, "An application event");
+        }
+
+        #[test]
+        fn rfc5424_syslog_nil_values() {
+            let record = parse_syslog("<14>1 - - - - - - Started").unwrap();
+            assert_eq!((record.timestamp, record.hostname, record.app_name), (None, None, None));
+            assert_eq!((record.proc_id, record.msg_id, record.structured_data), (None, None, None));
+            assert_eq!(record.message, "Started");
+        }
+
+        #[test]
+        fn rfc5424_structured_data_with_bracket() {
+            let record = parse_syslog(r#"<14>1 2003-10-11T22:14:15Z host app 42 - [origin ip="10.0.0.1" note="a\]b"][meta x="1"] Done [ok]"#).unwrap();
+            assert_eq!(record.proc_id, Some("42"));
+            assert_eq!(record.structured_data, Some(r#"[origin ip="10.0.0.1" note="a\]b"][meta x="1"]"#));
+            assert_eq!(record.message, "Done [ok]");
+        }
+
+        #[test]
+        fn malformed_syslog_pri() {
+            assert!(parse_syslog("<abc>Oct 11 22:14:15 host su: failed").is_none());
+            assert!(parse_syslog("<34 Oct 11 22:14:15 host su: failed").is_none());
+            assert!(parse_syslog("<>1 - - - - - - Started").is_none());
+        }
+
+        #[test]
+        fn cef_header_escapes() {
+            let (header, extension) = split_header(r"0|Se\|curity|threat\\manager|1.0|100|worm\n|10|src=10.0.0.1", 7).unwrap();
+            assert_eq!(header, vec!["0", "Se|curity", r"threat\manager", "1.0", "100", r"worm\n", "10"]);
+            assert_eq!(extension, "src=10.0.0.1");
+        }
+
+        #[test]
+        fn cef_extension_escapes() {
+            assert_eq!(parse_cef_extension(r"msg=a\=b c\\d\nnext src=10.0.0.1"), vec![
+                ("msg".to_string(), "a=b c\\d\nnext".to_string()),
+                ("src".to_string(), "10.0.0.1".to_string()),
+            ]);
+        }
+
+        #[test]
+        fn cef_extension_values_with_spaces() {
+            assert_eq!(parse_cef_extension("act=blocked by policy suser=John Doe dst=10.0.0.2"), vec![
+                ("act".to_string(), "blocked by policy".to_string()),
+                ("suser".to_string(), "John Doe".to_string()),
+                ("dst".to_string(), "10.0.0.2".to_string()),
+            ]);
+        }
+
+        #[test]
+        fn leef_delimiters() {
+            assert_eq!(leef_delimiter("^"), Some('^'));
+            assert_eq!(leef_delimiter("x09"), Some('\t'));
+            assert_eq!(leef_delimiter("0x7c"), Some('|'));
+            assert_eq!(leef_delimiter("^^"), None);
+            let extractor = message_extractor(&"leef".to_string());
+            let leef1 = extractor("LEEF:1.0|Microsoft|MSExchange|4.0|15345|src=10.50.1.1\tdst=10.0.0.5\tsev=5\tmsg=Mail sent".to_string()).unwrap();
+            assert_eq!((leef1.message.as_str(), leef1.level.as_deref()), ("Mail sent", Some("5")));
+            assert_eq!(leef1.fields, vec![("src".to_string(), "10.50.1.1".to_string()), ("dst".to_string(), "10.0.0.5".to_string()), ("sev".to_string(), "5".to_string())]);
+            let leef2 = extractor("LEEF:2.0|Lancope|StealthWatch|1.0|41|^|src=10.0.1.8^dst=10.0.0.5^msg=Flow denied".to_string()).unwrap();
+            assert_eq!(leef2.message, "Flow denied");
+            assert_eq!(leef2.fields, vec![("src".to_string(), "10.0.1.8".to_string()), ("dst".to_string(), "10.0.0.5".to_string())]);
+            let leef2 = extractor("LEEF:2.0|Lancope|StealthWatch|1.0|41|x09|src=10.0.1.8\tmsg=Flow denied".to_string()).unwrap();
+            assert_eq!(leef2.fields, vec![("src".to_string(), "10.0.1.8".to_string())]);
+        }
+
+        #[test]
+        fn octet_counted_frames() {
+            assert_eq!(frames("5 hello11 hello\nworld"), vec!["hello", "hello\nworld"]);
+        }
+
+        #[test]
+        fn digits_without_space_are_newline_framed() {
+            assert_eq!(frames("2024-01-01 started\n12:00 stopped\n"), vec!["2024-01-01 started\n", "12:00 stopped\n"]);
+        }
+
+        #[test]
+        fn oversized_octet_count_is_newline_framed() {
+            assert_eq!(frames("99999999 too long\n0 empty\n70000 big\n"), vec!["99999999 too long\n", "0 empty\n", "70000 big\n"]);
+        }
+    }
+}
+pub mod dedup {
+    use std::collections::HashSet;
+    use std::f64::consts::LN_2;
+    use std::fmt;
+    use std::fs::File;
+    use std::io::{self, BufWriter, Write};
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use itertools::Itertools;
+    use log::{debug, warn};
+    use memmap2::Mmap;
+    use serde_json::{json, Value};
+    use xxhash_rust::xxh3::{xxh3_128, xxh3_64};
+
+    // How the already distributed messages are remembered
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum DedupMode {
+        Exact,
+        // XXH3 hashes of the messages; a collision drops a new message as if it was a repeat,
+        // which becomes likely for 64-bit hashes after a few billion unique messages
+        Hash64,
+        Hash128,
+        // Approximate, with memory bounded by the expected number of unique messages
+        Bloom,
+        // 128-bit hashes in sorted runs on disk, for unique messages that do not fit in memory
+        Disk,
+    }
+
+    // Hashes buffered in memory before they are written as a run
+    const DISK_BUFFER_ENTRIES: usize = 1 << 20;
+    const DISK_ENTRY_SIZE: usize = 16;
+
+    #[derive(Clone, Debug)]
+    pub struct DedupOptions {
+        pub mode: DedupMode,
+        // Expected number of unique messages of the Bloom filter
+        pub capacity: usize,
+        // Probability of the Bloom filter taking a new message for a repeat, while below its capacity
+        pub false_positive_rate: f64,
+        // Directory of the runs of the disk store
+        pub directory: Option<PathBuf>,
+    }
+
+    pub enum SeenMessages {
+        Exact(HashSet<String>),
+        Hash64(HashSet<u64>),
+        Hash128(HashSet<u128>),
+        Bloom(BloomFilter),
+        Disk(DiskSet),
+    }
+
+    // The bit positions of a message are derived from the two halves of its 128-bit hash
+    pub struct BloomFilter {
+        words: Vec<u64>,
+        bits: u64,
+        hashes: u32,
+        capacity: usize,
+        inserted: usize,
+    }
+
+    // Runs are merged like the digits of a binary counter, so at most a logarithmic number of them
+    // is searched for every message. Merged runs are only removed once a later checkpoint no longer
+    // references them.
+    pub struct DiskSet {
+        directory: PathBuf,
+        buffer: HashSet<u128>,
+        runs: Vec<Run>,
+        next_run: usize,
+        merged: Vec<PathBuf>,
+        unreferenced: Vec<PathBuf>,
+    }
+
+    // Big-endian hashes in ascending order
+    struct Run {
+        path: PathBuf,
+        mmap: Mmap,
+    }
+
+    impl FromStr for DedupMode {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "exact" => Ok(DedupMode::Exact),
+                "hash64" => Ok(DedupMode::Hash64),
+                "hash128" => Ok(DedupMode::Hash128),
+                "bloom" => Ok(DedupMode::Bloom),
+                "disk" => Ok(DedupMode::Disk),
+                _ => Err(format!("Unknown dedup mode '{}', expected exact, hash64, hash128, bloom or disk", s)),
+            }
+        }
+    }
+
+    impl fmt::Display for DedupMode {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                DedupMode::Exact => write!(f, "exact"),
+                DedupMode::Hash64 => write!(f, "hash64"),
+                DedupMode::Hash128 => write!(f, "hash128"),
+                DedupMode::Bloom => write!(f, "bloom"),
+                DedupMode::Disk => write!(f, "disk"),
+            }
+        }
+    }
+
+    impl SeenMessages {
+        pub fn new(options: &DedupOptions) -> SeenMessages {
+            match options.mode {
+                DedupMode::Exact => SeenMessages::Exact(HashSet::new()),
+                DedupMode::Hash64 => SeenMessages::Hash64(HashSet::new()),
+                DedupMode::Hash128 => SeenMessages::Hash128(HashSet::new()),
+                DedupMode::Bloom => SeenMessages::Bloom(BloomFilter::new(options.capacity, options.false_positive_rate)),
+                DedupMode::Disk => {
+                    let directory = options.directory.clone().expect("The disk dedup store needs a directory");
+                    SeenMessages::Disk(DiskSet::open(directory, Vec::new()).expect("Unable to open the disk dedup store"))
+                }
+            }
+        }
+
+        pub fn mode(&self) -> DedupMode {
+            match self {
+                SeenMessages::Exact(_) => DedupMode::Exact,
+                SeenMessages::Hash64(_) => DedupMode::Hash64,
+                SeenMessages::Hash128(_) => DedupMode::Hash128,
+                SeenMessages::Bloom(_) => DedupMode::Bloom,
+                SeenMessages::Disk(_) => DedupMode::Disk,
+            }
+        }
+
+        // Returns false when the message was seen before
+        pub fn insert(&mut self, message: &str) -> bool {
+            match self {
+                SeenMessages::Exact(messages) => {
+                    if messages.contains(message) {
+                        false
+                    } else {
+                        messages.insert(message.to_string())
+                    }
+                }
+                SeenMessages::Hash64(hashes) => hashes.insert(xxh3_64(message.as_bytes())),
+                SeenMessages::Hash128(hashes) => hashes.insert(xxh3_128(message.as_bytes())),
+                SeenMessages::Bloom(filter) => filter.insert(message),
+                SeenMessages::Disk(set) => set.insert(xxh3_128(message.as_bytes())).expect("Unable to update the disk dedup store"),
+            }
+        }
+
+        // 128-bit hashes are written as hex strings, since JSON numbers cannot hold them. The disk
+        // store only lists its runs, after writing the buffered hashes as one.
+        pub fn snapshot(&mut self) -> Value {
+            if let SeenMessages::Disk(set) = self {
+                return set.snapshot().expect("Unable to save the disk dedup store");
+            }
+            if let SeenMessages::Bloom(filter) = self {
+                return json!({
+                    "mode": DedupMode::Bloom.to_string(),
+                    "bits": filter.bits,
+                    "hashes": filter.hashes,
+                    "capacity": filter.capacity,
+                    "inserted": filter.inserted,
+                    "words": filter.words.iter().map(|word| format!("{:016x}", word)).collect::<String>(),
+                });
+            }
+            let entries: Value = match self {
+                SeenMessages::Exact(messages) => json!(messages),
+                SeenMessages::Hash64(hashes) => json!(hashes),
+                SeenMessages::Hash128(hashes) => hashes.iter().map(|hash| format!("{:032x}", hash)).collect(),
+                SeenMessages::Bloom(_) | SeenMessages::Disk(_) => unreachable!(),
+            };
+            json!({ "mode": self.mode().to_string(), "entries": entries })
+        }
+
+        pub fn restore(value: &Value) -> Result<SeenMessages, String> {
+            let mode: DedupMode = value.get("mode").and_then(Value::as_str).ok_or("Missing dedup mode")?.parse()?;
+            let mut seen = match mode {
+                DedupMode::Exact => SeenMessages::Exact(HashSet::new()),
+                DedupMode::Hash64 => SeenMessages::Hash64(HashSet::new()),
+                DedupMode::Hash128 => SeenMessages::Hash128(HashSet::new()),
+                DedupMode::Bloom => { return BloomFilter::restore(value).map(SeenMessages::Bloom); }
+                DedupMode::Disk => { return DiskSet::restore(value).map(SeenMessages::Disk); }
+            };
+            let entries = value.get("entries").and_then(Value::as_array).ok_or("Missing dedup entries")?;
+            let invalid = |entry: &Value| format!("Invalid {} dedup entry {}", mode, entry);
+            for entry in entries {
+                match &mut seen {
+                    SeenMessages::Exact(messages) => { messages.insert(entry.as_str().ok_or_else(|| invalid(entry))?.to_string()); }
+                    SeenMessages::Hash64(hashes) => { hashes.insert(entry.as_u64().ok_or_else(|| invalid(entry))?); }
+                    SeenMessages::Hash128(hashes) => {
+                        let hash = entry.as_str().and_then(|hex| u128::from_str_radix(hex, 16).ok()).ok_or_else(|| invalid(entry))?;
+                        hashes.insert(hash);
+                    }
+                    SeenMessages::Bloom(_) | SeenMessages::Disk(_) => unreachable!(),
+                }
+            }
+            Ok(seen)
+        }
+    }
+
+    impl BloomFilter {
+        pub fn new(capacity: usize, false_positive_rate: f64) -> BloomFilter {
+            let expected = capacity.max(1) as f64;
+            let bits = (-expected * false_positive_rate.ln() / (LN_2 * LN_2)).ceil().max(64.0) as u64;
+            BloomFilter {
+                words: vec![0; bits.div_ceil(64) as usize],
+                bits,
+                hashes: (bits as f64 / expected * LN_2).round().max(1.0) as u32,
+                capacity,
+                inserted: 0,
+            }
+        }
+
+        // Returns false when every bit of the message was set already, i.e. it was probably seen
+        pub fn insert(&mut self, message: &str) -> bool {
+            let hash = xxh3_128(message.as_bytes());
+            let (first, second) = (hash as u64, (hash >> 64) as u64);
+            let mut new = false;
+            for i in 0..u64::from(self.hashes) {
+                let bit = first.wrapping_add(i.wrapping_mul(second)) % self.bits;
+                let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+                new |= self.words[word] & mask == 0;
+                self.words[word] |= mask;
+            }
+            if new {
+                self.inserted += 1;
+                if self.inserted == self.capacity + 1 {
+                    warn!("The Bloom filter exceeds its capacity of {} messages, its false positive rate rises", self.capacity);
+                }
+            }
+            new
+        }
+
+        fn restore(value: &Value) -> Result<BloomFilter, String> {
+            let number = |name: &str| value.get(name).and_then(Value::as_u64).ok_or(format!("Missing Bloom filter {}", name));
+            let words = value.get("words").and_then(Value::as_str).ok_or("Missing Bloom filter words")?;
+            let words = (0..words.len())
+                .step_by(16)
+                .map(|start| words.get(start..start + 16).and_then(|word| u64::from_str_radix(word, 16).ok()))
+                .collect::<Option<Vec<u64>>>()
+                .ok_or("Invalid Bloom filter words")?;
+            let bits = number("bits")?;
+            if words.len() as u64 != bits.div_ceil(64) {
+                return Err("Invalid Bloom filter size".to_string());
+            }
+            Ok(BloomFilter {
+                words,
+                bits,
+                hashes: number("hashes")? as u32,
+                capacity: number("capacity")? as usize,
+                inserted: number("inserted")? as usize,
+            })
+        }
+    }
+
+    impl DiskSet {
+        // Keeps the given runs of a checkpoint and removes any other run in the directory
+        fn open(directory: PathBuf, runs: Vec<String>) -> io::Result<DiskSet> {
+            std::fs::create_dir_all(&directory)?;
+            for entry in std::fs::read_dir(&directory)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if is_run_name(&name) && !runs.contains(&name) {
+                    std::fs::remove_file(entry.path())?;
+                }
+            }
+            let next_run = runs.iter().filter_map(|name| run_number(name)).max().map_or(0, |number| number + 1);
+            let runs = runs.iter().map(|name| Run::open(directory.join(name))).collect::<io::Result<Vec<Run>>>()?;
+            Ok(DiskSet {
+                directory,
+                buffer: HashSet::new(),
+                runs,
+                next_run,
+                merged: Vec::new(),
+                unreferenced: Vec::new(),
+            })
+        }
+
+        fn insert(&mut self, hash: u128) -> io::Result<bool> {
+            if self.buffer.contains(&hash) || self.runs.iter().any(|run| run.contains(hash)) {
+                return Ok(false);
+            }
+            self.buffer.insert(hash);
+            if self.buffer.len() >= DISK_BUFFER_ENTRIES {
+                self.spill()?;
+            }
+            Ok(true)
+        }
+
+        fn spill(&mut self) -> io::Result<()> {
+            if self.buffer.is_empty() {
+                return Ok(());
+            }
+            let hashes = std::mem::take(&mut self.buffer).into_iter().sorted();
+            let run = self.write_run(hashes)?;
+            self.runs.push(run);
+            while self.runs.len() >= 2 && self.runs[self.runs.len() - 1].len() >= self.runs[self.runs.len() - 2].len() {
+                let (newer, older) = (self.runs.pop().expect("Missing run"), self.runs.pop().expect("Missing run"));
+                debug!("Merging dedup runs of {} and {} hashes", older.len(), newer.len());
+                let merged = self.write_run(older.hashes().merge(newer.hashes()).dedup())?;
+                self.merged.push(older.path);
+                self.merged.push(newer.path);
+                self.runs.push(merged);
+            }
+            Ok(())
+        }
+
+        fn write_run(&mut self, hashes: impl Iterator<Item=u128>) -> io::Result<Run> {
+            let path = run_path(&self.directory, self.next_run);
+            self.next_run += 1;
+            let mut writer = BufWriter::new(File::create(&path)?);
+            for hash in hashes {
+                writer.write_all(&hash.to_be_bytes())?;
+            }
+            writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+            Run::open(path)
+        }
+
+        fn snapshot(&mut self) -> io::Result<Value> {
+            self.spill()?;
+            // The previous checkpoint is replaced by now, so the runs merged before it can go
+            for path in self.unreferenced.drain(..) {
+                std::fs::remove_file(path)?;
+            }
+            self.unreferenced = std::mem::take(&mut self.merged);
+            let runs: Vec<String> = self.runs.iter().map(|run| run.name()).collect();
+            Ok(json!({ "mode": DedupMode::Disk.to_string(), "directory": self.directory, "runs": runs }))
+        }
+
+        fn restore(value: &Value) -> Result<DiskSet, String> {
+            let directory = value.get("directory").and_then(Value::as_str).ok_or("Missing disk dedup directory")?;
+            let runs = value.get("runs")
+                .and_then(Value::as_array)
+                .and_then(|runs| runs.iter().map(|run| run.as_str().map(str::to_string)).collect::<Option<Vec<String>>>())
+                .ok_or("Missing disk dedup runs")?;
+            DiskSet::open(PathBuf::from(directory), runs).map_err(|err| format!("Unable to open the disk dedup store: {}", err))
+        }
+    }
+
+    impl Run {
+        fn open(path: PathBuf) -> io::Result<Run> {
+            let file = File::open(&path)?;
+            // Safety: runs are never modified once written
+            let mmap = unsafe { Mmap::map(&file)? };
+            Ok(Run { path, mmap })
+        }
+
+        fn len(&self) -> usize {
+            self.mmap.len() / DISK_ENTRY_SIZE
+        }
+
+        fn hash(&self, index: usize) -> u128 {
+            let start = index * DISK_ENTRY_SIZE;
+            u128::from_be_bytes(self.mmap[start..start + DISK_ENTRY_SIZE].try_into().expect("Invalid run entry"))
+        }
+
+        fn hashes(&self) -> impl Iterator<Item=u128> + '_ {
+            (0..self.len()).map(|index| self.hash(index))
+        }
+
+        fn contains(&self, hash: u128) -> bool {
+            let (mut low, mut high) = (0, self.len());
+            while low < high {
+                let middle = low + (high - low) / 2;
+                match self.hash(middle).cmp(&hash) {
+                    std::cmp::Ordering::Less => { low = middle + 1; }
+                    std::cmp::Ordering::Greater => { high = middle; }
+                    std::cmp::Ordering::Equal => { return true; }
+                }
+            }
+            false
+        }
+
+        fn name(&self) -> String {
+            self.path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default()
+        }
+    }
+
+    fn is_run_name(name: &str) -> bool {
+        run_number(name).is_some()
+    }
+
+    fn run_number(name: &str) -> Option<usize> {
+        name.strip_prefix("dedup-")?.strip_suffix(".run")?.parse().ok()
+    }
+
+    fn run_path(directory: &Path, number: usize) -> PathBuf {
+        directory.join(format!("dedup-{:06}.run", number))
+    }
+}
+pub mod mining {
+    use std::collections::HashMap;
+
+    const WILDCARD: &str = "<*>";
+    // Children of an inner node of the parse tree, further tokens go below its wildcard child
+    const MAX_CHILDREN: usize = 100;
+
+    // Online template miner after Drain (He et al., ICWS 2017), which clusters messages in a
+    // parse tree of fixed depth: by their number of tokens, then by their first tokens, and
+    // finally by the similarity to the templates of the clusters in the reached leaf
+    pub struct Drain {
+        depth: usize,
+        similarity: f64,
+        root: HashMap<usize, Node>,
+        clusters: Vec<Cluster>,
+    }
+
+    #[derive(Default)]
+    struct Node {
+        children: HashMap<String, Node>,
+        clusters: Vec<usize>,
+    }
+
+    // Tokens of a template, None for a parameter
+    #[derive(Clone, Debug)]
+    pub struct Cluster {
+        pub tokens: Vec<Option<String>>,
+        pub size: usize,
+    }
+
+    impl Drain {
+        // `depth` counts the root and the length level, so `depth - 2` tokens select the leaf;
+        // `similarity` is the fraction of equal tokens a message needs to join a cluster
+        pub fn new(depth: usize, similarity: f64) -> Drain {
+            Drain {
+                depth: depth.max(3),
+                similarity,
+                root: HashMap::new(),
+                clusters: Vec::new(),
+            }
+        }
+
+        // Returns the id of the cluster the message joined, which is its index in `clusters`
+        pub fn add(&mut self, msg: &str) -> usize {
+            let tokens: Vec<&str> = msg.split_whitespace().collect();
+            let mut node = self.root.entry(tokens.len()).or_default();
+            for token in tokens.iter().take(self.depth - 2) {
+                let key = if token.chars().any(|c| c.is_ascii_digit()) { WILDCARD } else { token };
+                let key = if node.children.contains_key(key) || node.children.len() < MAX_CHILDREN { key } else { WILDCARD };
+                node = node.children.entry(key.to_string()).or_default();
+            }
+            let best = node.clusters.iter()
+                .map(|&id| (id, similarity(&self.clusters[id], &tokens)))
+                .filter(|&(_, (similarity, _))| similarity >= self.similarity)
+                .max_by(|(_, a), (_, b)| a.partial_cmp(b).expect("Similarity is not a number"))
+                .map(|(id, _)| id);
+            match best {
+                Some(id) => {
+                    let cluster = &mut self.clusters[id];
+                    for (template, token) in cluster.tokens.iter_mut().zip(&tokens) {
+                        if template.as_deref().is_some_and(|template| template != *token) {
+                            *template = None;
+                        }
+                    }
+                    cluster.size += 1;
+                    id
+                }
+                None => {
+                    let id = self.clusters.len();
+                    self.clusters.push(Cluster {
+                        tokens: tokens.iter().map(|token| Some(token.to_string())).collect(),
+                        size: 1,
+                    });
+                    node.clusters.push(id);
+                    id
+                }
+            }
+        }
+
+        pub fn clusters(&self) -> &[Cluster] {
+            &self.clusters
+        }
+    }
+
+    impl Cluster {
+        // The template in LogHub form, e.g. `Took <*> ms`
+        pub fn template(&self) -> String {
+            self.tokens.iter().map(|token| token.as_deref().unwrap_or(WILDCARD)).collect::<Vec<_>>().join(" ")
+        }
+
+        // A regex for the regex file, capturing every parameter token
+        pub fn regex(&self) -> String {
+            self.tokens.iter()
+                .map(|token| token.as_deref().map_or(r"(\S+)".to_string(), regex::escape))
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+    }
+
+    // Fraction of equal tokens, with the number of parameters breaking ties
+    fn similarity(cluster: &Cluster, tokens: &[&str]) -> (f64, usize) {
+        if tokens.is_empty() {
+            return (1.0, 0);
+        }
+        let equal = cluster.tokens.iter().zip(tokens).filter(|(template, token)| template.as_deref() == Some(**token)).count();
+        let parameters = cluster.tokens.iter().filter(|token| token.is_none()).count();
+        (equal as f64 / tokens.len() as f64, parameters)
+    }
+}
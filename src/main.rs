@@ -1,11 +1,1184 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant, SystemTime};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use clap::{Parser, Subcommand};
 use csv::Writer;
-use log_pm_dataset_generator::matching::{start_thread_pool};
-use log_pm_dataset_generator::loading::{message_extractor, load_loglines, load_regex};
-use log::info;
+use glob::Pattern;
+use regex::Regex;
+use walkdir::WalkDir;
+use xxhash_rust::xxh3::Xxh3;
+use log_pm_dataset_generator::dedup::{DedupMode, DedupOptions, SeenMessages};
+use log_pm_dataset_generator::mining::Drain;
+use log_pm_dataset_generator::matching::{start_thread_pool, AnonymizeMode, Anonymizer, Column, DoubleMatchPolicy, MaskFormat, MaskUnit, MatchOptions, Matcher, NestedGroups, Normalization, OutputFormat, ParamType, TokenFallback, MatchStats, Metadata, Response, ThreadPoolInput, Unmatched, WorkerError};
+use log_pm_dataset_generator::loading::{check_extractors, configured_extractor, detect_format, follow_loglines, load_loglines, load_loglines_parallel, load_loghub_templates, load_regex, load_regex_dir, load_stdin_lines, load_journal_lines, listen_syslog, CrawlOptions, CsvColumn, FilePosition, CsvColumns, ExtractorOptions, JsonFields, Listener, LogcatFormat, LogLine, Multiline, Preprocess, RegexEngine, RegexLoadError, RegexOptions, TemplateRegex, Utf8Policy, LOGCAT_LONG_HEADER};
+#[cfg(feature = "http")]
+use log_pm_dataset_generator::loading::load_url_lines;
+#[cfg(feature = "s3")]
+use log_pm_dataset_generator::loading::load_s3_lines;
+#[cfg(feature = "kafka")]
+use log_pm_dataset_generator::loading::load_kafka_lines;
+#[cfg(feature = "arrow")]
+use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+#[cfg(feature = "arrow")]
+use arrow_ipc::writer::FileWriter;
+#[cfg(feature = "arrow")]
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use log::{error, info};
 
 
 const WORKER_COUNT: u8 = 4;
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Parser)]
+#[command(about = "Generates masked log parsing datasets from raw logs and template regexes")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Name of the dataset, selecting both the message extractor and the regex file
+    #[arg(required_unless_present = "dataset_flag")]
+    dataset: Option<String>,
+
+    /// Alternative to the positional dataset name
+    #[arg(long = "dataset", value_name = "DATASET", conflicts_with = "dataset")]
+    dataset_flag: Option<String>,
+
+    /// Match against the templates of a LogHub `*_templates.csv` file instead of the regex file
+    #[arg(long, value_name = "FILE")]
+    templates: Option<String>,
+
+    /// Let regexes match anywhere in the message instead of the whole message; regexes that
+    /// should still match it completely can use `^` and `$` themselves
+    #[arg(long)]
+    unanchored: bool,
+
+    /// Ignore case in every regex; single regexes can use the inline `(?i)` or `(?-i)` flags
+    #[arg(long)]
+    ignore_case: bool,
+
+    /// Approximate limit of the compiled size of a regex in bytes, for large generated regexes
+    /// exceeding the default of the regex crate
+    #[arg(long, value_name = "BYTES")]
+    regex_size_limit: Option<usize>,
+
+    /// Approximate limit of the lazy DFA cache of a regex in bytes, bounding the memory regexes
+    /// take in every worker
+    #[arg(long, value_name = "BYTES")]
+    regex_dfa_size_limit: Option<usize>,
+
+    /// Let classes like `\w`, `\d` and `\s` match only ASCII characters, which compiles regexes
+    /// with many of them much smaller; regexes with non-ASCII literals then fail to compile
+    #[arg(long)]
+    ascii_classes: bool,
+
+    /// Let `^` and `$` in regexes match at line breaks within the message as well
+    #[arg(long)]
+    multi_line: bool,
+
+    /// Comma separated normalizations of the messages the regexes are matched against:
+    /// whitespace (runs of whitespace become one space) and lowercase; the output keeps the
+    /// original message, and the mask marks the original characters of every parameter;
+    /// lowercase implies --ignore-case
+    #[arg(long, value_name = "STEPS", value_delimiter = ',')]
+    normalize: Vec<Normalization>,
+
+    /// Engine compiling the regexes: standard, fancy (fancy-regex, which supports lookaround and
+    /// backreferences, for every regex) or auto (fancy-regex for the regexes the standard one rejects)
+    #[cfg(feature = "fancy")]
+    #[arg(long, value_name = "ENGINE", default_value = "standard")]
+    regex_engine: RegexEngine,
+
+    /// Message extractor for the log format, defaults to the dataset name (or `journal` with --journal,
+    /// `gelf` with a GELF listener);
+    /// `auto` picks the extractor that succeeds on most of the first --detect-lines lines
+    #[arg(long, value_name = "FORMAT")]
+    format: Option<String>,
+
+    /// Use another extractor for the files whose path matches GLOB, e.g. `*/nova/*=openstack`
+    /// (repeatable, the first matching pattern wins; other files use --format)
+    #[arg(long, value_name = "GLOB=FORMAT", value_parser = parse_format_mapping)]
+    format_map: Vec<(Pattern, String)>,
+
+    /// Field path of the message for --format jsonl
+    #[arg(long, value_name = "PATH", default_value = ".message")]
+    json_message: String,
+
+    /// Field path of the RFC 3339 timestamp for --format jsonl
+    #[arg(long, value_name = "PATH")]
+    json_timestamp: Option<String>,
+
+    /// Field path of the level for --format jsonl
+    #[arg(long, value_name = "PATH")]
+    json_level: Option<String>,
+
+    /// Field path of the component (logger name) for --format jsonl
+    #[arg(long, value_name = "PATH")]
+    json_component: Option<String>,
+
+    /// Column of the message for --format csv or tsv, given by header name or 1-based position
+    #[arg(long, value_name = "COLUMN", default_value = "message")]
+    csv_message: CsvColumn,
+
+    /// Column of the timestamp for --format csv or tsv
+    #[arg(long, value_name = "COLUMN")]
+    csv_timestamp: Option<CsvColumn>,
+
+    /// Column of the level for --format csv or tsv
+    #[arg(long, value_name = "COLUMN")]
+    csv_level: Option<CsvColumn>,
+
+    /// Column of the component for --format csv or tsv
+    #[arg(long, value_name = "COLUMN")]
+    csv_component: Option<CsvColumn>,
+
+    /// Output format of `logcat -v` for --format android (threadtime, time, brief, tag, process or long),
+    /// detected per line by default
+    #[arg(long, value_name = "FORMAT")]
+    logcat_format: Option<LogcatFormat>,
+
+    /// Number of lines sampled by --format auto
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    detect_lines: usize,
+
+    /// Read log lines from stdin instead of crawling LOG_DATASETS
+    #[arg(long)]
+    stdin: bool,
+
+    /// Read the entries of the systemd journal through journalctl instead of crawling LOG_DATASETS
+    #[arg(long, conflicts_with = "stdin")]
+    journal: bool,
+
+    /// Only read journal entries matching FIELD=VALUE, e.g. `_SYSTEMD_UNIT=sshd.service`
+    #[arg(long, value_name = "FIELD=VALUE", requires = "journal")]
+    journal_match: Vec<String>,
+
+    /// Stream the log file at this HTTP(S) URL instead of crawling LOG_DATASETS
+    #[cfg(feature = "http")]
+    #[arg(long, value_name = "URL", conflicts_with = "stdin")]
+    url: Vec<String>,
+
+    /// Consume log messages continuously from a Kafka topic on these brokers (host:port)
+    #[cfg(feature = "kafka")]
+    #[arg(long, value_name = "HOST:PORT", value_delimiter = ',', requires = "kafka_topic", conflicts_with = "stdin")]
+    kafka_brokers: Vec<String>,
+
+    /// Kafka topic to consume
+    #[cfg(feature = "kafka")]
+    #[arg(long, value_name = "TOPIC", requires = "kafka_brokers")]
+    kafka_topic: Option<String>,
+
+    /// Kafka consumer group, whose committed offsets allow resuming the stream
+    #[cfg(feature = "kafka")]
+    #[arg(long, value_name = "GROUP", default_value = "log_pm_dataset_generator")]
+    kafka_group: String,
+
+    /// Receive syslog messages continuously on udp://host:port or tcp://host:port, or GELF messages
+    /// on gelf://host:port (UDP)
+    #[arg(long, value_name = "ADDRESS", conflicts_with = "stdin")]
+    listen: Option<Listener>,
+
+    /// Comma separated list of extensions of the crawled log files
+    #[arg(long, value_delimiter = ',', default_value = "log")]
+    extensions: Vec<String>,
+
+    /// Also crawl files matching this glob, e.g. `messages*` for rotated syslog files
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<Pattern>,
+
+    /// Skip files matching this glob
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<Pattern>,
+
+    /// Handling of lines that are not valid UTF-8: skip (with a warning), lossy or fail
+    #[arg(long, value_name = "POLICY", default_value = "skip")]
+    utf8_policy: Utf8Policy,
+
+    /// Comma separated preprocessing steps applied to every line before the message is extracted:
+    /// strip-ansi, collapse-whitespace, truncate:N (characters) and trim-bom
+    #[arg(long, value_name = "STEPS", value_delimiter = ',')]
+    preprocess: Vec<Preprocess>,
+
+    /// Join lines starting with whitespace (e.g. stack trace frames) to the preceding line
+    #[arg(long)]
+    multiline_indent: bool,
+
+    /// Join lines not matching this regex to the preceding line
+    #[arg(long, value_name = "REGEX", conflicts_with = "multiline_indent")]
+    multiline_header: Option<Regex>,
+
+    /// Crawl the files of each directory in file name order, so repeated runs produce the same output
+    #[arg(long)]
+    sorted: bool,
+
+    /// Only crawl files up to this depth below the dataset directory (1 for its direct children)
+    #[arg(long, value_name = "DEPTH")]
+    max_depth: Option<usize>,
+
+    /// Follow symbolic links while crawling
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Once the input is exhausted, keep running and re-match the unmatched messages whenever the
+    /// regex file (the fragment directory or --templates file) changes
+    #[arg(long, conflicts_with_all = ["follow", "listen"])]
+    watch: bool,
+
+    /// Keep following the log files of the dataset and label lines as they are appended,
+    /// including files that are rotated or created later
+    #[arg(long, conflicts_with_all = ["stdin", "journal", "listen"])]
+    follow: bool,
+
+    /// Number of threads reading log files concurrently; with more than one reader the
+    /// lines of different files are interleaved in the output
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    readers: usize,
+
+    /// Skip the first N crawled lines, e.g. to split the corpus between machines
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    skip: usize,
+
+    /// Only process M crawled lines (after --skip)
+    #[arg(long, value_name = "M")]
+    take: Option<usize>,
+
+    /// Remembering of the distributed messages: exact, hash64/hash128 to store XXH3 hashes
+    /// instead of the messages, which takes far less memory at a tiny risk of collisions, or
+    /// bloom for an approximate filter of bounded size, or disk for hashes spilled to --dedup-dir
+    #[arg(long, value_name = "MODE", default_value = "exact")]
+    dedup: DedupMode,
+
+    /// Expected number of unique messages for --dedup bloom, which sizes the filter
+    #[arg(long, value_name = "N", default_value_t = 10_000_000)]
+    bloom_capacity: usize,
+
+    /// Directory of --dedup disk, which keeps sorted runs of message hashes there
+    #[arg(long, value_name = "DIR", required_if_eq("dedup", "disk"))]
+    dedup_dir: Option<PathBuf>,
+
+    /// Fraction of new messages that --dedup bloom may drop as repeats while within its capacity
+    #[arg(long, value_name = "RATE", default_value_t = 0.001, value_parser = parse_false_positive_rate)]
+    bloom_fp_rate: f64,
+
+    /// Only keep messages matching this regex (any of them, if repeated)
+    #[arg(long, value_name = "REGEX")]
+    grep: Vec<Regex>,
+
+    /// Drop messages matching this regex, e.g. heartbeats (repeatable)
+    #[arg(long, value_name = "REGEX")]
+    grep_v: Vec<Regex>,
+
+    /// Fraction of the crawled lines to keep, e.g. 0.01 for a 1% sample
+    #[arg(long, value_name = "RATE", default_value_t = 1.0, value_parser = parse_rate)]
+    sample_rate: f64,
+
+    /// Seed making the sample deterministic, so every run keeps the same lines
+    #[arg(long, value_name = "SEED", requires = "sample_rate")]
+    seed: Option<u64>,
+
+    /// Stop after this many unique messages were distributed to the workers
+    #[arg(long, value_name = "N")]
+    max_lines: Option<u32>,
+
+    /// Read the input twice: first count the occurrences of every message and the messages of
+    /// every regex, matching without captures, then mask only the messages that are written.
+    /// Every unique message is kept in memory, as with --dedup exact
+    #[arg(long, conflicts_with_all = ["stdin", "journal", "watch", "checkpoint"])]
+    two_pass: bool,
+
+    /// Only write the first N unique messages of every regex (needs --two-pass)
+    #[arg(long, value_name = "N", requires = "two_pass")]
+    per_template: Option<usize>,
+
+    /// Write the unique messages, occurrences and written messages of every regex counted by
+    /// --two-pass to this CSV file, most frequent first
+    #[arg(long, value_name = "PATH", requires = "two_pass")]
+    template_counts: Option<PathBuf>,
+
+    /// Save the progress to this file periodically and resume from it if it exists; it is
+    /// removed once the run completes
+    #[arg(long, value_name = "FILE", conflicts_with = "readers")]
+    checkpoint: Option<PathBuf>,
+
+    /// Seconds between two checkpoints
+    #[arg(long, value_name = "SECONDS", default_value_t = 60, requires = "checkpoint")]
+    checkpoint_interval: u64,
+
+    /// Comma separated list of output columns: msg, mask, idx, source (file path), line,
+    /// timestamp (ISO 8601), epoch (milliseconds; local times are treated as UTC), level,
+    /// component (logger name), fields (further key-value pairs of structured formats like
+    /// logfmt), field:KEY (the value of one of them), count (occurrences of the message, which
+    /// holds back the output until the input is exhausted), token_mask (one 0/1 label per
+    /// whitespace separated token), template (the matching regex with `<*>` for its capture
+    /// groups), similarity (of --fuzzy matches), params (the captured values as a JSON list),
+    /// typed_params (the captured values with their inferred type as a JSON list of objects),
+    /// masked_msg (the message with `<*>` for every run of masked characters), typed_masked_msg
+    /// (the same with the inferred type of the run instead, e.g. `<ip>`),
+    /// named_params (the named capture groups as a JSON object), param:NAME (the value of one of
+    /// them), alternatives (index and mask of every matching regex of --double-match all),
+    /// overruled (the other matching regexes, which lost by their priority or --double-match),
+    /// confidence (1 for unique matches, 1/n for double matches of n regexes, the similarity of
+    /// --fuzzy matches), template_id (a hash of the matching regex as written, which does not
+    /// change when the regexes are reordered) and case_id (the value of the `case_id` capture
+    /// group, a correlation key grouping the messages into process mining traces)
+    #[arg(long, value_delimiter = ',', default_value = "msg,mask,idx")]
+    columns: Vec<Column>,
+
+    /// Format of the dataset file: csv, jsonl for one JSON object per message keyed by the
+    /// column names, written to DATASET.jsonl, or arrow for an Arrow IPC file (with the arrow
+    /// feature)
+    #[arg(long, value_name = "FORMAT", default_value = "csv")]
+    output_format: OutputFormat,
+
+    /// Unit of the mask digits: byte (of the UTF-8 encoded message) or char, which keeps the
+    /// mask as long as the message in characters when it contains non-ASCII text
+    #[arg(long, value_name = "UNIT", default_value = "byte")]
+    mask_unit: MaskUnit,
+
+    /// Encoding of the mask column: digits (one 0/1 per unit), rle (lengths of the alternating
+    /// runs of 0s and 1s, e.g. `5,2,3`), spans (`[start,length]` pairs of the parameters as JSON)
+    /// or span-objects (`{"start":5,"end":7}` objects of the parameters as JSON, end exclusive)
+    #[arg(long, value_name = "FORMAT", default_value = "digits")]
+    mask_format: MaskFormat,
+
+    /// Mark every parameter in the mask with the digit of its class instead of 1: 1 generic,
+    /// 2 number, 3 IP address, 4 path, 5 hex, 6 UUID (needs --mask-format digits)
+    #[arg(long)]
+    typed_mask: bool,
+
+    /// Masking of capture groups containing other groups: innermost (every group is masked, the
+    /// innermost one deciding the digit, so `const_*` groups stay unmasked within others) or
+    /// leaves (only the groups without groups inside are masked and parameters)
+    #[arg(long, value_name = "MODE", default_value = "innermost")]
+    nested_groups: NestedGroups,
+
+    /// Replace the parameters in the output by pseudonyms, the same one for equal values: hash (16
+    /// hex digits of a SipHash keyed by --anonymize-key-file or ANONYMIZE_KEY) or token (the type
+    /// and a counter, e.g. `ip_3`). A resumed run would assign other pseudonyms, so it cannot be
+    /// combined with --checkpoint.
+    #[arg(long, value_name = "MODE", conflicts_with = "checkpoint")]
+    anonymize: Option<AnonymizeMode>,
+
+    /// Comma separated types of the parameters to anonymize (datetime, ip, int, float, hex, path,
+    /// id or string), all of them by default
+    #[arg(long, value_name = "TYPES", value_delimiter = ',', requires = "anonymize")]
+    anonymize_types: Vec<ParamType>,
+
+    /// File holding the 128-bit key of the hashes of --anonymize hash as 32 hex digits, which keeps
+    /// the pseudonyms equal across runs; taken from the ANONYMIZE_KEY environment variable
+    /// without it, and random if neither is given. Keeping the key out of the command line keeps
+    /// it out of the process list and shell history.
+    #[arg(long, value_name = "PATH", requires = "anonymize")]
+    anonymize_key_file: Option<PathBuf>,
+
+    /// Resolution of messages matched by several regexes: fail (report them as unmatched), first
+    /// (in file order), longest (the longest pattern), priority:IDX,... (regex indices in order
+    /// of preference, unlisted regexes rank below them) or all (the first one, keeping every
+    /// matching regex in the alternatives column)
+    #[arg(long, value_name = "POLICY", default_value = "fail")]
+    double_match: DoubleMatchPolicy,
+
+    /// Only run the regexes whose literal prefix or suffix occurs in the message, which is much
+    /// faster than the regex set for thousands of regexes
+    #[arg(long)]
+    prefilter: bool,
+
+    /// Only run the regexes whose literal first token is the first token of the message
+    /// (ignoring case), and the regexes starting with a variable as --token-fallback says
+    #[arg(long)]
+    token_index: bool,
+
+    /// When the regexes without a literal first token are tried: always, or unmatched (only if
+    /// none of the indexed regexes matched, which prefers those)
+    #[arg(long, value_name = "WHEN", default_value = "always", requires = "token_index")]
+    token_fallback: TokenFallback,
+
+    /// Find the candidate regexes of a message with a Hyperscan database compiled from all of
+    /// them, then run only those for the mask
+    #[cfg(feature = "hyperscan")]
+    #[arg(long)]
+    hyperscan: bool,
+
+    /// Keep the match results of the N most recently matched messages, so a repeated message
+    /// skips the regexes (the cache is cleared when --watch reloads them)
+    #[arg(long, value_name = "N")]
+    match_cache: Option<usize>,
+
+    /// Label messages no regex matches with the template sharing most of their tokens, if their
+    /// Jaccard similarity is at least RATE; the similarity column tells them apart
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    fuzzy: Option<f64>,
+
+    /// Let every worker try the regexes in the order of their matches so far instead of using the
+    /// regex set, which saves time on skewed corpora with --double-match first; after a match only
+    /// the regexes before it in the file are tried, so the labels stay those of the file order
+    #[arg(long)]
+    adaptive_order: bool,
+
+    /// Number of messages sent to a worker at once, saving channel operations when the regexes
+    /// match quickly; live inputs always send every message right away
+    #[arg(long, value_name = "N", default_value_t = 64, value_parser = clap::value_parser!(u32).range(1..))]
+    batch_size: u32,
+
+    /// Write the messages no regex (or more than one) matched to this CSV file, with the
+    /// reason, source and line number, instead of logging them
+    #[arg(long, value_name = "PATH", conflicts_with = "watch")]
+    unmatched: Option<PathBuf>,
+
+    /// Write the failures of the workers to this CSV file, or JSON lines file if it ends with
+    /// `.jsonl`, instead of logging them: messages no regex (or more than one) matched, unless
+    /// --unmatched takes them, and responses the workers could not send
+    #[arg(long, value_name = "PATH")]
+    errors: Option<PathBuf>,
+
+    /// Cluster the unmatched messages into templates with Drain and write a suggested regex for
+    /// each of them to this file; with --unmatched, the messages get the provisional template id
+    /// their regex would have once appended to the regex file
+    #[arg(long, value_name = "PATH", conflicts_with = "watch")]
+    mine: Option<PathBuf>,
+
+    /// Write the template_id, index and pattern of every regex to this CSV file, relating the
+    /// indices of this run to the stable ids
+    #[arg(long, value_name = "PATH")]
+    id_map: Option<PathBuf>,
+
+    /// Write the number of messages each regex matched to this CSV file, most frequent first
+    #[arg(long, value_name = "PATH")]
+    stats: Option<PathBuf>,
+
+    /// Exit with status 1 if any regex labeled no message, logging each of them, as a stale or
+    /// broken regex file usually has some
+    #[arg(long, conflicts_with = "watch")]
+    fail_on_dead: bool,
+
+    /// Time every regex on its own (instead of matching with the regex set) and write the total
+    /// and mean time of each to this CSV file, slowest first
+    #[arg(long, value_name = "PATH")]
+    profile: Option<PathBuf>,
+
+    /// Disable a regex for the rest of the run once matching it against a single message took
+    /// longer than MS milliseconds, so a pathological pattern cannot stall the run
+    #[arg(long, value_name = "MS")]
+    time_budget: Option<u64>,
+
+    /// Instead of writing the dataset, match every regex against every message and write the
+    /// pairs of regexes matching the same message to this CSV file, with example messages; exits
+    /// with status 1 if there are any
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["checkpoint", "watch"])]
+    validate: Option<PathBuf>,
+
+    /// Depth of the Drain parse tree, i.e. two more than the leading tokens that split clusters
+    #[arg(long, value_name = "DEPTH", default_value_t = 4, requires = "mine")]
+    mine_depth: usize,
+
+    /// Fraction of equal tokens a message needs to join a mined template
+    #[arg(long, value_name = "RATE", default_value_t = 0.4, value_parser = parse_rate, requires = "mine")]
+    mine_similarity: f64,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the extractors on embedded sample lines of their formats and report every mismatch
+    CheckExtractor {
+        /// Formats to check, all of them by default
+        formats: Vec<String>,
+    },
+}
+
+// Progress of an interrupted run: the last line handled per source along with the byte offset
+// after it (unless the source cannot be seeked), the messages seen so far, the number of
+// distributed messages and the length of the output file once all of them were written
+struct Checkpoint {
+    positions: HashMap<String, (usize, Option<u64>)>,
+    messages: SeenMessages,
+    distributed_lines: u32,
+    csv_length: u64,
+}
+
+impl Checkpoint {
+    fn new(dedup: &DedupOptions) -> Checkpoint {
+        Checkpoint {
+            positions: HashMap::new(),
+            messages: SeenMessages::new(dedup),
+            distributed_lines: 0,
+            csv_length: 0,
+        }
+    }
+
+    fn load(path: &Path) -> Option<Checkpoint> {
+        let file = File::open(path).ok()?;
+        let (positions, messages, distributed_lines, csv_length): (_, serde_json::Value, _, _) = serde_json::from_reader(std::io::BufReader::new(file))
+            .unwrap_or_else(|err| panic!("Invalid checkpoint {}: {}", path.display(), err));
+        Some(Checkpoint {
+            positions,
+            messages: SeenMessages::restore(&messages).unwrap_or_else(|err| panic!("Invalid checkpoint {}: {}", path.display(), err)),
+            distributed_lines,
+            csv_length,
+        })
+    }
+
+    // Written to a temporary file first, so an interruption never leaves a partial checkpoint
+    fn save(&mut self, path: &Path) {
+        let temporary = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary).expect("Unable to create the checkpoint"));
+        serde_json::to_writer(&mut writer, &(&self.positions, self.messages.snapshot(), self.distributed_lines, self.csv_length))
+            .expect("Unable to write the checkpoint");
+        writer.into_inner().expect("Unable to write the checkpoint");
+        std::fs::rename(&temporary, path).expect("Unable to save the checkpoint");
+    }
+
+    fn advance(&mut self, source: &str, line_number: usize, offset: Option<u64>) {
+        match self.positions.get_mut(source) {
+            Some(position) => { *position = (line_number, offset); }
+            None => { self.positions.insert(source.to_string(), (line_number, offset)); }
+        }
+    }
+
+    // Sources that are read from the recorded offset rather than from the start
+    fn seekable_positions(&self) -> HashMap<String, FilePosition> {
+        self.positions.iter()
+            .filter_map(|(source, (line_number, offset))| Some((source.clone(), FilePosition { line_number: *line_number, offset: (*offset)? })))
+            .collect()
+    }
+}
+
+// Occurrences of a message, its regex and whether the second pass of --two-pass distributes it
+struct Planned {
+    occurrences: usize,
+    idx: Option<usize>,
+    emit: bool,
+}
+
+// Unique messages, their occurrences and the ones distributed of a regex, or of the unmatched messages
+#[derive(Clone, Default)]
+struct TemplateCount {
+    messages: usize,
+    occurrences: usize,
+    emitted: usize,
+}
+
+// Writer of the dataset in the --output-format
+enum OutputWriter {
+    Csv(Box<Writer<Box<dyn Write + Send>>>),
+    Jsonl(BufWriter<Box<dyn Write + Send>>),
+    #[cfg(feature = "arrow")]
+    Arrow(Box<ArrowWriter>),
+}
+
+impl OutputWriter {
+    #[cfg_attr(not(feature = "arrow"), allow(unused_variables))]
+    fn new(format: OutputFormat, output: Box<dyn Write + Send>, columns: &[Column]) -> OutputWriter {
+        match format {
+            OutputFormat::Csv => OutputWriter::Csv(Box::new(Writer::from_writer(output))),
+            OutputFormat::Jsonl => OutputWriter::Jsonl(BufWriter::new(output)),
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => OutputWriter::Arrow(Box::new(ArrowWriter::new(output, columns))),
+        }
+    }
+
+    fn write(&mut self, res: Response, columns: &[Column], mask_format: MaskFormat) {
+        match self {
+            OutputWriter::Csv(writer) => writer.write_record(res.into_csv_record(columns, mask_format)).expect("unable to write"),
+            OutputWriter::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, &res.into_json_record(columns, mask_format)).expect("unable to write");
+                writeln!(writer).expect("unable to write");
+            }
+            #[cfg(feature = "arrow")]
+            OutputWriter::Arrow(writer) => writer.write(res.into_csv_record(columns, mask_format)),
+        }
+    }
+
+    fn flush(&mut self) {
+        match self {
+            OutputWriter::Csv(writer) => writer.flush().expect("Failed to flush"),
+            OutputWriter::Jsonl(writer) => writer.flush().expect("Failed to flush"),
+            #[cfg(feature = "arrow")]
+            OutputWriter::Arrow(writer) => writer.write_batch(),
+        }
+    }
+
+    // Arrow files are only readable once their footer is written
+    fn finish(mut self) {
+        self.flush();
+        #[cfg(feature = "arrow")]
+        if let OutputWriter::Arrow(mut writer) = self {
+            writer.writer.finish().expect("Failed to finish the Arrow file");
+        }
+    }
+}
+
+// Rows are collected into record batches of this many rows
+#[cfg(feature = "arrow")]
+const ARROW_BATCH_ROWS: usize = 65536;
+
+// Arrow IPC file with the numeric columns as nullable integers and floats and the rest as strings
+#[cfg(feature = "arrow")]
+struct ArrowWriter {
+    writer: FileWriter<Box<dyn Write + Send>>,
+    schema: SchemaRef,
+    rows: Vec<Vec<String>>,
+}
+
+#[cfg(feature = "arrow")]
+impl ArrowWriter {
+    fn new(output: Box<dyn Write + Send>, columns: &[Column]) -> ArrowWriter {
+        let fields: Vec<Field> = columns.iter().map(|column| {
+            let data_type = match column {
+                Column::Similarity | Column::Confidence => DataType::Float64,
+                _ if column.is_numeric() => DataType::Int64,
+                _ => DataType::Utf8,
+            };
+            Field::new(column.name(), data_type, true)
+        }).collect();
+        let schema = Arc::new(Schema::new(fields));
+        let writer = FileWriter::try_new(output, &schema).expect("Unable to write the Arrow schema");
+        ArrowWriter { writer, schema, rows: Vec::new() }
+    }
+
+    fn write(&mut self, record: Vec<String>) {
+        self.rows.push(record);
+        if self.rows.len() >= ARROW_BATCH_ROWS {
+            self.write_batch();
+        }
+    }
+
+    fn write_batch(&mut self) {
+        if self.rows.is_empty() {
+            return;
+        }
+        let rows = std::mem::take(&mut self.rows);
+        let arrays: Vec<ArrayRef> = self.schema.fields().iter().enumerate().map(|(i, field)| {
+            let values = rows.iter().map(|row| Some(row[i].as_str()).filter(|value| !value.is_empty()));
+            let array: ArrayRef = match field.data_type() {
+                DataType::Int64 => Arc::new(values.map(|value| value.and_then(|value| value.parse::<i64>().ok())).collect::<Int64Array>()),
+                DataType::Float64 => Arc::new(values.map(|value| value.and_then(|value| value.parse::<f64>().ok())).collect::<Float64Array>()),
+                _ => Arc::new(rows.iter().map(|row| Some(row[i].as_str())).collect::<StringArray>()),
+            };
+            array
+        }).collect();
+        let batch = RecordBatch::try_new(self.schema.clone(), arrays).expect("Invalid record batch");
+        self.writer.write(&batch).expect("unable to write");
+    }
+}
+
+fn extractor_options(args: &Args) -> ExtractorOptions {
+    ExtractorOptions {
+        json: JsonFields {
+            message: args.json_message.clone(),
+            timestamp: args.json_timestamp.clone(),
+            level: args.json_level.clone(),
+            component: args.json_component.clone(),
+        },
+        csv: CsvColumns {
+            message: args.csv_message.clone(),
+            timestamp: args.csv_timestamp.clone(),
+            level: args.csv_level.clone(),
+            component: args.csv_component.clone(),
+        },
+        logcat: args.logcat_format,
+    }
+}
+
+// The template file, the regex file or else the directory of regex fragments of the dataset
+fn regex_source(args: &Args, env: &HashMap<String, String>, dataset_name: &str) -> PathBuf {
+    if let Some(templates) = &args.templates {
+        return PathBuf::from(templates);
+    }
+    let regex_directory = env.get("REGEX_DIRECTORY").expect("REGEX_DIRECTORY is not provided in the environment variables");
+    let regex_path = PathBuf::from(format!("{}/{}.regex", regex_directory, dataset_name));
+    let fragment_dir = PathBuf::from(format!("{}/{}", regex_directory, dataset_name));
+    // A directory of fragments is only used when there is no single regex file
+    if !regex_path.exists() && fragment_dir.is_dir() {
+        fragment_dir
+    } else {
+        regex_path
+    }
+}
+
+fn load_regexes(args: &Args, env: &HashMap<String, String>, dataset_name: &str) -> Result<Vec<TemplateRegex>, RegexLoadError> {
+    let source = regex_source(args, env, dataset_name);
+    let path = source.to_string_lossy();
+    if args.templates.is_some() {
+        info!("Loading templates from {}", path);
+        load_loghub_templates(&path, &regex_options(args))
+    } else if source.is_dir() {
+        info!("Loading regexes from the fragments in {}", path);
+        load_regex_dir(&path, &regex_options(args))
+    } else {
+        info!("Loading regexes from {}", path);
+        load_regex(&path, &regex_options(args))
+    }
+}
+
+fn regex_options(args: &Args) -> RegexOptions {
+    RegexOptions {
+        anchored: !args.unanchored,
+        // Lowercased messages are only matched by the uppercase literals of a regex ignoring case
+        case_insensitive: args.ignore_case || args.normalize.contains(&Normalization::Lowercase),
+        #[cfg(feature = "fancy")]
+        engine: args.regex_engine,
+        #[cfg(not(feature = "fancy"))]
+        engine: RegexEngine::Standard,
+        size_limit: args.regex_size_limit,
+        dfa_size_limit: args.regex_dfa_size_limit,
+        unicode: !args.ascii_classes,
+        multi_line: args.multi_line,
+    }
+}
+
+// Latest modification of the file, or of any file in the directory
+fn last_modified(path: &Path) -> Option<SystemTime> {
+    if path.is_dir() {
+        WalkDir::new(path).into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok()?.modified().ok())
+            .max()
+    } else {
+        std::fs::metadata(path).ok()?.modified().ok()
+    }
+}
+
+// Re-matches the unmatched messages whenever the regexes change, until the process is stopped
+fn watch_regexes(args: &Args, env: &HashMap<String, String>, dataset_name: &str, pool_input: &mut ThreadPoolInput) {
+    let source = regex_source(args, env, dataset_name);
+    let mut modified = last_modified(&source);
+    info!("Watching {} for changes", source.display());
+    loop {
+        std::thread::sleep(WATCH_POLL_INTERVAL);
+        let current = last_modified(&source);
+        if current == modified {
+            continue;
+        }
+        modified = current;
+        match load_regexes(args, env, dataset_name) {
+            Ok(regex_vec) => {
+                if let Some(path) = &args.id_map {
+                    write_id_map(path, &regex_vec);
+                }
+                // Messages still being matched against the previous regexes end up unmatched first
+                pool_input.wait_settled();
+                pool_input.reload(regex_vec);
+                let resubmitted = pool_input.resubmit_unmatched();
+                info!("Reloaded the regexes, re-matching {} unmatched messages", resubmitted);
+            }
+            Err(err) => { error!("Keeping the previous regexes: {}", err); }
+        }
+    }
+}
+
+// Writes the unmatched messages to `path` and clusters them with `drain`, whose provisional
+// template ids continue after the regexes
+// With a checkpoint every row is flushed, so the rows of the settled messages are on disk when it is saved
+fn collect_unmatched(path: Option<&Path>, report: ReportMode, mut drain: Option<Drain>, regex_count: usize, unmatched: impl Iterator<Item=Unmatched>) -> Option<Drain> {
+    let mut writer = path.map(|path| {
+        let (file, continued) = open_report(path, report.resuming);
+        let mut writer = Writer::from_writer(file);
+        if !continued {
+            writer.write_record(["msg", "error", "source", "line", "cluster"]).expect("unable to write");
+        }
+        writer
+    });
+    let mut count = 0;
+    for unmatched in unmatched {
+        let cluster = drain.as_mut().map(|drain| (regex_count + drain.add(&unmatched.msg)).to_string()).unwrap_or_default();
+        if let Some(writer) = &mut writer {
+            let line = unmatched.metadata.line_number.to_string();
+            writer.write_record([unmatched.msg.as_str(), &unmatched.error.to_string(), &unmatched.metadata.source, &line, &cluster]).expect("unable to write");
+            if report.checkpointed {
+                writer.flush().expect("Failed to flush");
+            }
+        }
+        count += 1;
+    }
+    if let (Some(writer), Some(path)) = (&mut writer, path) {
+        writer.flush().expect("Failed to flush");
+        info!("Total of {} unmatched messages were written to {}", count, path.display())
+    }
+    drain
+}
+
+// How the --unmatched and --errors files of a run with --checkpoint are written
+#[derive(Clone, Copy)]
+struct ReportMode {
+    checkpointed: bool,
+    resuming: bool,
+}
+
+// Reports of a resumed run are appended to, returning whether the file already had rows
+fn open_report(path: &Path, resuming: bool) -> (File, bool) {
+    let continued = resuming && std::fs::metadata(path).is_ok_and(|metadata| metadata.len() > 0);
+    let file = if resuming {
+        OpenOptions::new().append(true).create(true).open(path)
+    } else {
+        File::create(path)
+    };
+    (file.unwrap_or_else(|err| panic!("Unable to create {}: {}", path.display(), err)), continued)
+}
+
+// Writes the failures as they occur and logs their number by kind once the workers are done
+fn write_errors(path: &Path, report: ReportMode, errors: impl Iterator<Item=WorkerError>) {
+    let (file, continued) = open_report(path, report.resuming);
+    let file = BufWriter::new(file);
+    let (mut writer, mut jsonl) = if path.extension().is_some_and(|extension| extension == "jsonl") {
+        (None, Some(file))
+    } else {
+        (Some(Writer::from_writer(file)), None)
+    };
+    if let (Some(writer), false) = (&mut writer, continued) {
+        writer.write_record(["kind", "error", "msg", "source", "line", "worker"]).expect("unable to write");
+    }
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for error in errors {
+        *counts.entry(error.kind.name()).or_default() += 1;
+        let line = error.metadata.line_number.to_string();
+        let description = error.kind.to_string();
+        if let Some(writer) = &mut writer {
+            writer.write_record([error.kind.name(), &description, &error.msg, &error.metadata.source, &line, &error.worker]).expect("unable to write");
+        }
+        if let Some(file) = &mut jsonl {
+            let record = serde_json::json!({
+                "kind": error.kind.name(),
+                "error": description,
+                "msg": error.msg,
+                "source": &*error.metadata.source,
+                "line": error.metadata.line_number,
+                "worker": error.worker,
+            });
+            writeln!(file, "{}", record).expect("unable to write");
+        }
+        if report.checkpointed {
+            if let Some(writer) = &mut writer {
+                writer.flush().expect("Failed to flush");
+            }
+            if let Some(file) = &mut jsonl {
+                file.flush().expect("Failed to flush");
+            }
+        }
+    }
+    if let Some(writer) = &mut writer {
+        writer.flush().expect("Failed to flush");
+    }
+    if let Some(file) = &mut jsonl {
+        file.flush().expect("Failed to flush");
+    }
+    if !counts.is_empty() {
+        let summary: Vec<String> = counts.iter().map(|(kind, count)| format!("{} {}", count, kind)).collect();
+        error!("The workers failed on {} messages ({}), see {}", counts.values().sum::<usize>(), summary.join(", "), path.display());
+    }
+}
+
+// Suggested regexes in cluster order, so appending them to the regex file keeps the provisional ids
+fn write_mined_regexes(path: &Path, drain: &Drain, regex_count: usize) {
+    let mut file = BufWriter::new(File::create(path).expect("Unable to create the mined regex file"));
+    for (id, cluster) in drain.clusters().iter().enumerate() {
+        writeln!(file, "# {}: {} messages like `{}`", regex_count + id, cluster.size, cluster.template()).expect("unable to write");
+        writeln!(file, "{}", cluster.regex()).expect("unable to write");
+    }
+    file.flush().expect("Failed to flush");
+    info!("Suggested {} mined regexes in {}", drain.clusters().len(), path.display())
+}
+
+// The first pass of --two-pass, which decides the messages to distribute in the second one. The
+// last count is the one of the unmatched messages.
+fn count_messages(args: &Args, matcher: &Matcher, messages: impl Iterator<Item=(String, Metadata)>) -> (HashMap<String, Planned>, Vec<TemplateCount>) {
+    let mut plan: HashMap<String, Planned> = HashMap::new();
+    let mut counts = vec![TemplateCount::default(); matcher.regex_count() + 1];
+    let mut emitted: u32 = 0;
+    for (msg, _) in messages {
+        if let Some(planned) = plan.get_mut(&msg) {
+            planned.occurrences += 1;
+            counts[planned.idx.unwrap_or(matcher.regex_count())].occurrences += 1;
+            continue;
+        }
+        let idx = matcher.classify(&msg).ok();
+        let count = &mut counts[idx.unwrap_or(matcher.regex_count())];
+        let emit = args.max_lines.is_none_or(|max_lines| emitted < max_lines)
+            && (idx.is_none() || args.per_template.is_none_or(|limit| count.emitted < limit));
+        count.messages += 1;
+        count.occurrences += 1;
+        if emit {
+            count.emitted += 1;
+            emitted += 1;
+        }
+        plan.insert(msg, Planned { occurrences: 1, idx, emit });
+    }
+    (plan, counts)
+}
+
+fn write_template_counts(path: &Path, counts: &[TemplateCount], regex_vec: &[TemplateRegex]) {
+    let mut writer = Writer::from_path(path).expect("Unable to create the template count file");
+    writer.write_record(["idx", "messages", "occurrences", "emitted", "pattern"]).expect("unable to write");
+    let mut order: Vec<usize> = (0..counts.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(counts[i].occurrences));
+    for i in order {
+        let count = &counts[i];
+        // The unmatched messages have neither an index nor a pattern
+        let (idx, pattern) = regex_vec.get(i).map(|template| (i.to_string(), template.regex.as_str())).unwrap_or_default();
+        writer.write_record([idx.as_str(), &count.messages.to_string(), &count.occurrences.to_string(), &count.emitted.to_string(), pattern])
+            .expect("unable to write");
+    }
+    writer.flush().expect("Failed to flush");
+    info!("Wrote the message counts of {} regexes to {}", regex_vec.len(), path.display())
+}
+
+// Regexes by descending number of matches, so the dominating ones come first and the ones that
+// never matched last
+fn write_stats(path: &Path, stats: &MatchStats) {
+    let mut writer = Writer::from_path(path).expect("Unable to create the stats file");
+    writer.write_record(["idx", "matches", "share", "pattern"]).expect("unable to write");
+    let total = stats.matches.iter().sum::<usize>().max(1);
+    let mut order: Vec<usize> = (0..stats.matches.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(stats.matches[i]));
+    for i in order {
+        let pattern = stats.patterns.get(i).map(String::as_str).unwrap_or_default();
+        let share = format!("{:.4}", stats.matches[i] as f64 / total as f64);
+        writer.write_record([i.to_string().as_str(), &stats.matches[i].to_string(), &share, pattern]).expect("unable to write");
+    }
+    writer.flush().expect("Failed to flush");
+    info!("Wrote the match counts of {} regexes to {}, {} of them never matched", stats.matches.len(), path.display(), stats.dead_regexes().len())
+}
+
+fn write_profile(path: &Path, stats: &MatchStats) {
+    let mut writer = Writer::from_path(path).expect("Unable to create the profile");
+    writer.write_record(["idx", "total_ms", "runs", "mean_us", "disabled", "pattern"]).expect("unable to write");
+    let mut order: Vec<usize> = (0..stats.times.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(stats.times[i].total));
+    for i in order {
+        let time = &stats.times[i];
+        let pattern = stats.patterns.get(i).map(String::as_str).unwrap_or_default();
+        let total = format!("{:.3}", time.total.as_secs_f64() * 1e3);
+        let mean = format!("{:.3}", time.total.as_secs_f64() * 1e6 / time.runs.max(1) as f64);
+        writer.write_record([i.to_string().as_str(), &total, &time.runs.to_string(), &mean, &time.disabled.to_string(), pattern]).expect("unable to write");
+    }
+    writer.flush().expect("Failed to flush");
+    info!("Wrote the matching times of {} regexes to {}", stats.times.len(), path.display())
+}
+
+fn write_id_map(path: &Path, regex_vec: &[TemplateRegex]) {
+    let mut writer = Writer::from_path(path).expect("Unable to create the id map");
+    writer.write_record(["template_id", "idx", "pattern"]).expect("unable to write");
+    for (i, template) in regex_vec.iter().enumerate() {
+        writer.write_record([format!("{:016x}", template.id).as_str(), &i.to_string(), template.regex.as_str()]).expect("unable to write");
+    }
+    writer.flush().expect("Failed to flush");
+}
+
+fn write_overlaps(path: &Path, stats: &MatchStats) {
+    let mut writer = Writer::from_path(path).expect("Unable to create the overlap file");
+    writer.write_record(["first", "second", "count", "first_pattern", "second_pattern", "examples"]).expect("unable to write");
+    let mut pairs: Vec<_> = stats.overlaps.iter().collect();
+    pairs.sort_by_key(|(_, overlap)| std::cmp::Reverse(overlap.count));
+    for ((first, second), overlap) in &pairs {
+        let pattern = |i: &usize| stats.patterns.get(*i).map(String::as_str).unwrap_or_default();
+        let examples = serde_json::to_string(&overlap.examples).expect("Unable to serialize the examples");
+        writer.write_record([first.to_string().as_str(), &second.to_string(), &overlap.count.to_string(), pattern(first), pattern(second), &examples])
+            .expect("unable to write");
+    }
+    writer.flush().expect("Failed to flush");
+    if pairs.is_empty() {
+        info!("No two regexes matched the same message");
+    } else {
+        error!("{} pairs of regexes matched the same messages, see {}", pairs.len(), path.display());
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("'{}' is not a rate between 0 and 1", s)),
+    }
+}
+
+fn parse_format_mapping(s: &str) -> Result<(Pattern, String), String> {
+    let (glob, format) = s.rsplit_once('=').ok_or(format!("'{}' is not of the form GLOB=FORMAT", s))?;
+    let pattern = Pattern::new(glob).map_err(|err| format!("Invalid glob '{}': {}", glob, err))?;
+    Ok((pattern, format.to_string()))
+}
+
+fn parse_false_positive_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate < 1.0 => Ok(rate),
+        _ => Err(format!("'{}' is not a rate between 0 and 1 (exclusive)", s)),
+    }
+}
+
+fn dedup_options(args: &Args) -> DedupOptions {
+    DedupOptions {
+        mode: args.dedup,
+        capacity: args.bloom_capacity,
+        false_positive_rate: args.bloom_fp_rate,
+        directory: args.dedup_dir.clone(),
+    }
+}
+
+// Lines are kept based on a hash of their position, keyed randomly unless a seed is given
+// XXH3 keeps the selection of a seed stable across builds, unlike the std hashers
+fn sampler(rate: f64, seed: Option<u64>) -> impl Fn(&LogLine) -> bool {
+    let seed = seed.unwrap_or_else(|| RandomState::new().hash_one(Instant::now()));
+    let threshold = (rate * u64::MAX as f64) as u64;
+    move |line: &LogLine| {
+        let mut hasher = Xxh3::with_seed(seed);
+        hasher.update(line.source.as_bytes());
+        hasher.update(&[0xff]);
+        hasher.update(&(line.line_number as u64).to_le_bytes());
+        hasher.digest() <= threshold
+    }
+}
+
+fn preprocessed(args: &Args, loglines: Box<dyn Iterator<Item=LogLine>>) -> Box<dyn Iterator<Item=LogLine>> {
+    if args.preprocess.is_empty() {
+        return loglines;
+    }
+    let steps = args.preprocess.clone();
+    Box::new(loglines.map(move |mut line| {
+        line.text = steps.iter().fold(line.text, |text, step| step.apply(text));
+        line
+    }))
+}
+
+// Messages of the lines left by --skip, --take, the checkpoint, the sample and the filters, with
+// their metadata
+fn extract_messages<'a>(args: &'a Args, format: &str, loglines: Box<dyn Iterator<Item=LogLine>>, resumed_positions: HashMap<String, usize>, sample: &'a dyn Fn(&LogLine) -> bool) -> impl Iterator<Item=(String, Metadata)> + 'a {
+    let message_extractor = configured_extractor(&format.to_string(), &extractor_options(args));
+    let mapped_extractors: Vec<_> = args.format_map.iter()
+        .map(|(pattern, format)| (pattern.clone(), configured_extractor(format, &extractor_options(args))))
+        .collect();
+    let mut source_extractors: HashMap<Arc<str>, Option<usize>> = HashMap::new();
+    loglines
+        .skip(args.skip)
+        .take(args.take.unwrap_or(usize::MAX))
+        .filter(move |line| resumed_positions.get(&*line.source).is_none_or(|position| line.line_number > *position))
+        .filter(move |line| args.sample_rate >= 1.0 || sample(line))
+        .filter_map(move |line| {
+            let mapped = *source_extractors.entry(line.source.clone())
+                .or_insert_with(|| mapped_extractors.iter().position(|(pattern, _)| pattern.matches(&line.source)));
+            let extractor = mapped.map_or(&message_extractor, |index| &mapped_extractors[index].1);
+            let extracted = extractor(line.text)?;
+            if !args.grep.is_empty() && !args.grep.iter().any(|regex| regex.is_match(&extracted.message)) {
+                return None;
+            }
+            if args.grep_v.iter().any(|regex| regex.is_match(&extracted.message)) {
+                return None;
+            }
+            let metadata = Metadata {
+                source: line.source,
+                line_number: line.line_number,
+                offset: line.offset,
+                timestamp: extracted.timestamp,
+                level: extracted.level,
+                component: extracted.component,
+                fields: extracted.fields,
+            };
+            Some((extracted.message, metadata))
+        })
+}
+
+// Format named by --format or, without it, by the dataset
+fn explicit_format(args: &Args) -> Option<&str> {
+    args.format.as_ref().or(args.dataset.as_ref()).or(args.dataset_flag.as_ref()).map(String::as_str)
+}
+
+fn crawl_options(args: &Args, resume: HashMap<String, FilePosition>) -> CrawlOptions {
+    CrawlOptions {
+        extensions: args.extensions.iter().map(|ext| ext.trim_start_matches('.').to_string()).collect(),
+        include: args.include.clone(),
+        exclude: args.exclude.clone(),
+        utf8_policy: args.utf8_policy,
+        multiline: if args.multiline_indent || (args.multiline_header.is_none() && explicit_format(args) == Some("postgres")) {
+            Some(Multiline::Indentation)
+        } else if args.multiline_header.is_none() && args.logcat_format == Some(LogcatFormat::Long) {
+            Some(Multiline::Header(Regex::new(LOGCAT_LONG_HEADER).expect("Invalid logcat header regex")))
+        } else {
+            args.multiline_header.clone().map(Multiline::Header)
+        },
+        partial_records: explicit_format(args).into_iter()
+            .chain(args.format_map.iter().map(|(_, format)| format.as_str()))
+            .any(|format| matches!(format, "docker" | "cri" | "auto")),
+        sorted: args.sorted,
+        max_depth: args.max_depth,
+        follow_symlinks: args.follow_symlinks,
+        resume: Arc::new(resume),
+    }
+}
+
+// Key of --anonymize hash from --anonymize-key-file or ANONYMIZE_KEY, otherwise a random one
+fn anonymize_key(args: &Args, env: &HashMap<String, String>) -> [u8; 16] {
+    let (text, origin) = match &args.anonymize_key_file {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).unwrap_or_else(|err| panic!("Unable to read {}: {}", path.display(), err));
+            (text, path.display().to_string())
+        }
+        None => match env.get("ANONYMIZE_KEY") {
+            Some(text) => (text.clone(), "ANONYMIZE_KEY".to_string()),
+            None => {
+                let high = RandomState::new().hash_one(Instant::now()) as u128;
+                let low = RandomState::new().hash_one(SystemTime::now()) as u128;
+                return ((high << 64) | low).to_be_bytes();
+            }
+        },
+    };
+    let text = text.trim();
+    match u128::from_str_radix(text, 16) {
+        Ok(key) if text.len() == 32 && text.bytes().all(|b| b.is_ascii_hexdigit()) => key.to_be_bytes(),
+        _ => {
+            error!("The anonymization key in {} must be 32 hex digits", origin);
+            std::process::exit(1)
+        }
+    }
+}
+
+// Returns a description of the input along with its lines, starting at the resumed positions
+fn open_input(args: &Args, env: &HashMap<String, String>, dataset_name: &str, resume: HashMap<String, FilePosition>) -> (String, Box<dyn Iterator<Item=LogLine>>) {
+    let crawl_options = crawl_options(args, resume);
+    if args.stdin {
+        return ("stdin".to_string(), Box::new(load_stdin_lines(&crawl_options)));
+    }
+    if args.journal {
+        return ("journald".to_string(), Box::new(load_journal_lines(&args.journal_match, &crawl_options)));
+    }
+    if let Some(listener) = &args.listen {
+        let lines = listen_syslog(listener.clone(), &crawl_options);
+        return (format!("{:?}", listener), Box::new(lines));
+    }
+    #[cfg(feature = "kafka")]
+    if let Some(topic) = &args.kafka_topic {
+        let lines = load_kafka_lines(args.kafka_brokers.clone(), topic.clone(), args.kafka_group.clone(), &crawl_options);
+        return (format!("kafka topic {}", topic), Box::new(lines));
+    }
+    #[cfg(feature = "http")]
+    if !args.url.is_empty() {
+        return (args.url.join(", "), Box::new(load_url_lines(args.url.clone(), crawl_options)));
+    }
+    let dataset_path = format!("{}/{}",
+                               env.get("LOG_DATASETS")
+                                   .expect("LOG_DATASETS is not provided in the environment variables")
+                                   .as_str(),
+                               dataset_name);
+    #[cfg(feature = "s3")]
+    if dataset_path.starts_with("s3://") {
+        return (dataset_path.clone(), Box::new(load_s3_lines(dataset_path, crawl_options)));
+    }
+    if args.follow {
+        (dataset_path.clone(), follow_loglines(dataset_path, crawl_options))
+    } else if args.readers > 1 {
+        (dataset_path.clone(), Box::new(load_loglines_parallel(dataset_path, crawl_options, args.readers)))
+    } else {
+        (dataset_path.clone(), Box::new(load_loglines(dataset_path, crawl_options)))
+    }
+}
+
+// Live inputs never end, so their output is flushed whenever the workers catch up
+fn is_live(args: &Args) -> bool {
+    #[cfg(feature = "kafka")]
+    if args.kafka_topic.is_some() {
+        return true;
+    }
+    args.listen.is_some() || args.follow
+}
 
 fn main() {
 
@@ -14,62 +1187,268 @@ fn main() {
 
     // Reading environment variables and command line arguments
     let env: HashMap<String, String> = std::env::vars().collect();
-    let dataset_name = {
-        let args: Vec<String> = std::env::args().collect();
-        args.get(1).expect("Dataset not provided in the command line args").to_string()
+    let args = Args::parse();
+    if let Some(Command::CheckExtractor { formats }) = &args.command {
+        let failures = check_extractors(formats);
+        for failure in &failures {
+            println!("{}", failure);
+        }
+        if !failures.is_empty() {
+            std::process::exit(1)
+        }
+        println!("All extractor samples passed");
+        return;
+    }
+    let dataset_name = args.dataset.clone().or(args.dataset_flag.clone()).expect("Dataset not provided in the command line args");
+    if args.two_pass && is_live(&args) {
+        error!("--two-pass reads the input twice and cannot be used with streaming inputs");
+        std::process::exit(1)
+    }
+
+    let resumed = args.checkpoint.as_deref().and_then(Checkpoint::load);
+    if let Some(checkpoint) = &resumed {
+        if checkpoint.messages.mode() != args.dedup {
+            error!("The checkpoint was saved with --dedup {}", checkpoint.messages.mode());
+            std::process::exit(1)
+        }
+    }
+    // --skip and --take count the lines from the start, so they need every file read from there
+    let resume = match &resumed {
+        Some(checkpoint) if args.skip == 0 && args.take.is_none() => checkpoint.seekable_positions(),
+        _ => HashMap::new(),
     };
 
     // Init crawler
-    let dataset_path = format!("{}/{}",
-                               env.get("LOG_DATASETS")
-                                   .expect("LOG_DATASETS is not provided in the environment variables")
-                                   .as_str(),
-                               dataset_name);
-    let message_extractor = message_extractor(&dataset_name);
+    let (dataset_path, loglines) = open_input(&args, &env, &dataset_name, resume);
+    let mut loglines = preprocessed(&args, loglines);
+    let mut format = args.format.clone().unwrap_or_else(|| {
+        if args.journal {
+            "journal".to_string()
+        } else if matches!(args.listen, Some(Listener::Gelf(_))) {
+            "gelf".to_string()
+        } else {
+            dataset_name.clone()
+        }
+    });
+    if format == "auto" {
+        let sample: Vec<LogLine> = loglines.by_ref().take(args.detect_lines).collect();
+        let (detected, rate) = detect_format(&sample).expect("Unable to detect the log format");
+        info!("Detected the {} format, which extracts {:.1}% of the sampled lines", detected, rate * 100.0);
+        format = detected.to_string();
+        loglines = Box::new(sample.into_iter().chain(loglines));
+    }
 
     // Worker pool
+    if args.adaptive_order && args.double_match != DoubleMatchPolicy::First {
+        error!("The adaptive order only applies to --double-match first");
+        std::process::exit(1)
+    }
+    if args.typed_mask && args.mask_format != MaskFormat::Digits {
+        error!("Typed masks can only be written with --mask-format digits");
+        std::process::exit(1)
+    }
+    let regex_vec = load_regexes(&args, &env, &dataset_name).unwrap_or_else(|err| {
+        error!("{}", err);
+        std::process::exit(1)
+    });
+    let regex_count = regex_vec.len();
+    if let Some(path) = &args.id_map {
+        write_id_map(path, &regex_vec);
+    }
+    let options = MatchOptions {
+        double_match: args.double_match.clone(),
+        mask_unit: args.mask_unit,
+        prefilter: args.prefilter,
+        token_index: args.token_index,
+        token_fallback: args.token_fallback,
+        #[cfg(feature = "hyperscan")]
+        hyperscan: args.hyperscan,
+        cache_size: args.match_cache,
+        fuzzy: args.fuzzy,
+        overlaps: args.validate.is_some(),
+        confidence: args.columns.contains(&Column::Confidence),
+        typed_mask: args.typed_mask,
+        nested_groups: args.nested_groups,
+        profile: args.profile.is_some(),
+        time_budget: args.time_budget.map(Duration::from_millis),
+        normalize: args.normalize.clone(),
+        adaptive_order: args.adaptive_order,
+        report_errors: args.errors.is_some(),
+        size_limit: args.regex_size_limit,
+        dfa_size_limit: args.regex_dfa_size_limit,
+    };
+    let sample = sampler(args.sample_rate, args.seed);
+
+    // The counting pass reads the input before the workers start, and the second pass reads it again
+    let mut plan = if args.two_pass {
+        info!("Counting the messages of {}", dataset_path);
+        let matcher = Matcher::new(regex_vec.clone(), options.clone());
+        let (plan, counts) = count_messages(&args, &matcher, extract_messages(&args, &format, loglines, HashMap::new(), &sample));
+        info!("Counted {} unique messages, {} of them are distributed", plan.len(), counts.iter().map(|count| count.emitted).sum::<usize>());
+        if let Some(path) = &args.template_counts {
+            write_template_counts(path, &counts, &regex_vec);
+        }
+        loglines = preprocessed(&args, open_input(&args, &env, &dataset_name, HashMap::new()).1);
+        Some(plan)
+    } else {
+        None
+    };
+
+    let report = ReportMode { checkpointed: args.checkpoint.is_some(), resuming: resumed.is_some() };
     info!("Initiating worker pool");
-    let (mut pool_input, pool_output) = {
-        let regex_path = format!("{}/{}.regex",
-                                 env.get("REGEX_DIRECTORY").expect("REGEX_DIRECTORY is not provided in the environment variables").as_str(),
-                                 dataset_name);
-        info!("Loading regexes from {}", regex_path);
-        let regex_vec = load_regex(regex_path.as_str());
-        start_thread_pool(regex_vec, WORKER_COUNT)
+    let (mut pool_input, pool_output) = start_thread_pool(regex_vec, WORKER_COUNT, args.watch || args.unmatched.is_some() || args.mine.is_some(), options);
+    let unmatched_thread = if args.unmatched.is_some() || args.mine.is_some() {
+        let unmatched = pool_input.unmatched_messages();
+        let path = args.unmatched.clone();
+        let drain = args.mine.as_ref().map(|_| Drain::new(args.mine_depth, args.mine_similarity));
+        Some(std::thread::spawn(move || { collect_unmatched(path.as_deref(), report, drain, regex_count, unmatched) }))
+    } else {
+        None
     };
 
+    let errors_thread = args.errors.clone().map(|path| {
+        let errors = pool_input.errors();
+        std::thread::spawn(move || { write_errors(&path, report, errors) })
+    });
+
+    // Resuming drops the rows written after the checkpoint
+    let output_path = format!("{}.{}", dataset_name, args.output_format.extension());
+    let output: Box<dyn Write + Send> = match &resumed {
+        _ if args.validate.is_some() => Box::new(std::io::sink()),
+        Some(checkpoint) => {
+            info!("Resuming from checkpoint with {} distributed lines", checkpoint.distributed_lines);
+            let file = OpenOptions::new().write(true).open(&output_path).expect("Unable to open the output file");
+            file.set_len(checkpoint.csv_length).expect("Unable to truncate the output file");
+            Box::new(OpenOptions::new().append(true).open(&output_path).expect("Unable to open the output file"))
+        }
+        None => Box::new(OpenOptions::new().write(true).create(true).truncate(true).open(&output_path).unwrap()),
+    };
+    let mut output_writer = OutputWriter::new(args.output_format, output, &args.columns);
+
     // Writer thread
     info!("Starting the writer thread");
-    let mut csv_writer = Writer::from_path(format!("{}.csv", dataset_name).as_str()).unwrap();
+    let columns = args.columns.clone();
+    let mask_format = args.mask_format;
+    let flush_when_idle = is_live(&args) || args.checkpoint.is_some() || args.watch;
+    let count_occurrences = columns.contains(&Column::Count);
+    let two_pass = args.two_pass;
+    let output_file = output_path.clone();
+    let mut anonymizer = args.anonymize.map(|mode| Anonymizer::new(mode, args.anonymize_types.clone(), anonymize_key(&args, &env)));
+    if anonymizer.is_some() && columns.contains(&Column::Alternatives) {
+        error!("The masks of the alternatives column cannot be anonymized");
+        std::process::exit(1)
+    }
+    if count_occurrences && flush_when_idle {
+        error!("The count column needs the complete input and cannot be used with streaming inputs or --checkpoint");
+        std::process::exit(1)
+    }
+    // Counting keeps every unique message and its row in memory until the input is exhausted
+    if count_occurrences && args.dedup != DedupMode::Exact {
+        error!("The count column keeps every unique message in memory and cannot be used with --dedup {}", args.dedup);
+        std::process::exit(1)
+    }
+    #[cfg(feature = "arrow")]
+    if args.output_format == OutputFormat::Arrow && flush_when_idle {
+        error!("Arrow files are only readable once complete and cannot be appended to, so they cannot be used with streaming inputs, --watch or --checkpoint");
+        std::process::exit(1)
+    }
+    let (occurrences_tx, occurrences_rx) = mpsc::channel::<HashMap<String, usize>>();
     let writer_thread = std::thread::spawn(move || {
         info!("Writer thread started");
         let mut lines: u32 = 0;
-        for res in pool_output {
-            csv_writer.write_record(res.into_csv_record()).expect("unable to write");
+        let mut unflushed = 0;
+        let mut responses = pool_output.into_iter();
+        // --two-pass counts the occurrences before the messages are distributed, otherwise they
+        // are only known once the input is exhausted
+        let buffered: Vec<Response> = if count_occurrences && !two_pass { responses.by_ref().collect() } else { Vec::new() };
+        let occurrences = if count_occurrences { occurrences_rx.recv().expect("Missing occurrence counts") } else { HashMap::new() };
+        for mut res in buffered {
+            res.count = occurrences.get(&res.msg).copied().unwrap_or(1);
+            if let Some(anonymizer) = &mut anonymizer {
+                anonymizer.anonymize(&mut res);
+            }
+            output_writer.write(res, &columns, mask_format);
+            lines += 1;
+        }
+        while let Some(mut res) = responses.next() {
+            if count_occurrences {
+                res.count = occurrences.get(&res.msg).copied().unwrap_or(1);
+            }
+            if let Some(anonymizer) = &mut anonymizer {
+                anonymizer.anonymize(&mut res);
+            }
+            output_writer.write(res, &columns, mask_format);
             lines += 1;
+            unflushed += 1;
+            if flush_when_idle && responses.is_empty() {
+                output_writer.flush();
+                responses.acknowledge(unflushed);
+                unflushed = 0;
+            }
         }
-        csv_writer.flush().expect("Failed to flush");
-        info!("Total of {} lines were written to the csv files", lines)
+        output_writer.finish();
+        info!("Total of {} lines were written to {}", lines, output_file)
     });
 
     // Reading from crawler and writing to workers
     info!("Loading messages from {}", dataset_path);
     info!("Distributing messages among workers...");
-    let mut distributed_lines: u32 = 0;
     let mut crawled_lines: u32 = 0;
-    let mut message_set = HashSet::new();
-    for msg in load_loglines(dataset_path)
-        .filter_map(|line| { message_extractor(line) }) {
+    let mut progress = resumed.unwrap_or_else(|| Checkpoint::new(&dedup_options(&args)));
+    let resumed_positions = progress.positions.iter().map(|(source, (line_number, _))| (source.clone(), *line_number)).collect();
+    let mut last_checkpoint = Instant::now();
+    let checkpoint_interval = Duration::from_secs(args.checkpoint_interval);
+    let mut occurrences: HashMap<String, usize> = HashMap::new();
+    if let (Some(plan), true) = (&plan, count_occurrences) {
+        let planned = plan.iter().filter(|(_, planned)| planned.emit).map(|(msg, planned)| (msg.clone(), planned.occurrences)).collect();
+        occurrences_tx.send(planned).expect("Unable to send the occurrence counts");
+    }
+    let batch_size = if is_live(&args) { 1 } else { args.batch_size as usize };
+    let mut batch = Vec::with_capacity(batch_size);
+    for (msg, metadata) in extract_messages(&args, &format, loglines, resumed_positions, &sample) {
         crawled_lines += 1;
+        if let Some(checkpoint_path) = &args.checkpoint {
+            if last_checkpoint.elapsed() >= checkpoint_interval {
+                pool_input.submit_batch(std::mem::take(&mut batch));
+                pool_input.wait_settled();
+                progress.csv_length = std::fs::metadata(&output_path).expect("Unable to read the output file").len();
+                progress.save(checkpoint_path);
+                info!("Saved checkpoint after {} distributed lines", progress.distributed_lines);
+                last_checkpoint = Instant::now();
+            }
+            progress.advance(&metadata.source, metadata.line_number, metadata.offset);
+        }
         let message = msg.to_string();
-        if message_set.contains(&message) {
+        let distribute = match &mut plan {
+            Some(plan) => plan.get_mut(&message).is_some_and(|planned| std::mem::take(&mut planned.emit)),
+            None => {
+                if count_occurrences {
+                    *occurrences.entry(message.clone()).or_default() += 1;
+                }
+                progress.messages.insert(&message)
+            }
+        };
+        if !distribute {
             continue;
         }
-        message_set.insert(message.clone());
-        pool_input.submit(msg);
-        distributed_lines += 1;
+        batch.push((msg, metadata));
+        if batch.len() >= batch_size {
+            pool_input.submit_batch(std::mem::replace(&mut batch, Vec::with_capacity(batch_size)));
+        }
+        progress.distributed_lines += 1;
+        if args.max_lines.is_some_and(|max_lines| progress.distributed_lines >= max_lines) {
+            info!("Reached the maximum of {} lines", progress.distributed_lines);
+            break;
+        }
+    }
+    pool_input.submit_batch(batch);
+    info!("Total of {} lines were crawled and {} of them were distributed between workers", crawled_lines, progress.distributed_lines);
+    if count_occurrences && plan.is_none() {
+        occurrences_tx.send(occurrences).expect("Unable to send the occurrence counts");
+    }
+    if args.watch {
+        watch_regexes(&args, &env, &dataset_name, &mut pool_input);
     }
-    info!("Total of {} lines were crawled and {} of them were distributed between workers", crawled_lines, distributed_lines);
 
     // Shutting down worker pool
     info!("Sending halt message to all threads");
@@ -83,5 +1462,45 @@ fn main() {
 
     // Join worker threads
     info!("Joining worker threads");
-    pool_input.join();
+    let stats = pool_input.join();
+    info!("{} messages were matched by a regex, {} by --fuzzy, {} by no regex and {} ambiguously",
+          stats.matches.iter().sum::<usize>(), stats.fuzzy, stats.unmatched, stats.ambiguous);
+    if let Some(path) = &args.stats {
+        write_stats(path, &stats);
+    }
+    if let Some(path) = &args.validate {
+        write_overlaps(path, &stats);
+    }
+    if let Some(path) = &args.profile {
+        write_profile(path, &stats);
+    }
+    let disabled: Vec<String> = stats.times.iter().enumerate().filter(|(_, time)| time.disabled).map(|(i, _)| i.to_string()).collect();
+    if !disabled.is_empty() {
+        error!("The regexes {} exceeded the time budget and were disabled", disabled.join(", "));
+    }
+    if let Some(errors_thread) = errors_thread {
+        errors_thread.join().unwrap();
+    }
+    if let Some(unmatched_thread) = unmatched_thread {
+        let drain = unmatched_thread.join().unwrap();
+        if let (Some(path), Some(drain)) = (&args.mine, drain) {
+            write_mined_regexes(path, &drain, regex_count);
+        }
+    }
+
+    if let Some(checkpoint_path) = &args.checkpoint {
+        if checkpoint_path.exists() {
+            std::fs::remove_file(checkpoint_path).expect("Unable to remove the checkpoint");
+        }
+    }
+    let dead = if args.fail_on_dead { stats.dead_regexes() } else { Vec::new() };
+    if !dead.is_empty() {
+        error!("{} of the {} regexes labeled no message:", dead.len(), stats.patterns.len());
+        for i in &dead {
+            error!("{}: {}", i, stats.patterns[*i]);
+        }
+    }
+    if (args.validate.is_some() && !stats.overlaps.is_empty()) || !dead.is_empty() {
+        std::process::exit(1)
+    }
 }
\ No newline at end of file